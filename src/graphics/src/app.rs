@@ -4,7 +4,7 @@ use crate::widgets;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use graphics_base::system::System;
-use graphics_base::types::Event;
+use graphics_base::types::{Command, Event};
 use graphics_base::Result;
 use hecs::World;
 
@@ -15,16 +15,16 @@ pub struct App {
 }
 
 impl App {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self> {
         let mut systems: Vec<Box<dyn System>> = Vec::new();
-        systems.push(Box::new(ClientPortalSystemPre::new()));
+        systems.push(Box::new(ClientPortalSystemPre::new()?));
         widgets::register(&mut systems);
 
-        Self {
+        Ok(Self {
             world: World::new(),
             system: ClientPortalSystem::new(),
             systems,
-        }
+        })
     }
 
     pub fn world(&self) -> &World {
@@ -39,6 +39,14 @@ impl App {
         self.system.pipe.checkpoint()
     }
 
+    /// Asks the graphics server to start another app, which gets its own connection to the server. The server only
+    /// starts the apps it knows about, such as `terminal`.
+    pub fn spawn(&mut self, filename: &str) -> Result<()> {
+        self.system.pipe.send_command(&Command::Spawn {
+            filename: filename.to_owned(),
+        })
+    }
+
     pub fn wait_for_event(&mut self) -> Result<Event> {
         for system in self.systems.iter_mut() {
             system.run(&mut self.world)?;
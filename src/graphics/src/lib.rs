@@ -11,5 +11,5 @@ pub mod widgets;
 
 pub use app::App;
 pub use graphics_base::types::*;
-pub use graphics_base::Result;
+pub use graphics_base::{share, Result};
 pub use pipe::AppSync;
@@ -6,7 +6,7 @@ use core::slice;
 use graphics_base::frame_buffer::FrameBuffer;
 use graphics_base::system::System;
 use graphics_base::types::{Command, Event, EventInput};
-use graphics_base::Result;
+use graphics_base::{Error, Result};
 use graphics_server::{PortalRef, Screen, ServerPortal, ServerPortalSystem};
 use hashbrown::HashMap;
 use hecs::{Entity, World};
@@ -54,7 +54,7 @@ impl ClientPipe {
         let byte_len = stride * 600;
         let mut buffer = vec![0; byte_len / 4].into_boxed_slice();
         let aliased_buffer = unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, byte_len) };
-        let screen = Arc::new(Mutex::new(Screen::new((800, 600), aliased_buffer)));
+        let screen = Arc::new(Mutex::new(Screen::new((800, 600), aliased_buffer).unwrap()));
         let system = ServerPortalSystem::new(screen.clone(), input_state);
 
         Self {
@@ -69,6 +69,15 @@ impl ClientPipe {
     }
 
     pub fn send_command(&mut self, command: &Command) -> Result<()> {
+        self.handle_command(command, None)
+    }
+
+    /// Sends a command along with the frame buffer's memory.
+    pub fn send_command_with_frame_buffer(&mut self, command: &Command, frame_buffer: &FrameBuffer) -> Result<()> {
+        self.handle_command(command, Some(frame_buffer.as_raw()))
+    }
+
+    fn handle_command(&mut self, command: &Command, shared_mem: Option<usize>) -> Result<()> {
         match *command {
             Command::Checkpoint { id: _id } => (),
 
@@ -77,14 +86,13 @@ impl ClientPipe {
                 pos,
                 size,
                 frame_buffer_id,
-                shared_mem_handle,
             } => {
                 let portal_ref = PortalRef {
                     portal_id: id,
                     events: self.events.clone(),
                 };
 
-                let frame_buffer = FrameBuffer::from_raw(size, shared_mem_handle)?;
+                let frame_buffer = FrameBuffer::from_raw(size, shared_mem.ok_or(Error::NotSupported)?)?;
                 let portal = ServerPortal::new(&self.world, portal_ref, pos, frame_buffer_id, size, frame_buffer);
                 let entity = self.world.spawn((portal,));
                 self.portals_by_id.insert(id, entity);
@@ -100,10 +108,9 @@ impl ClientPipe {
                 id,
                 size,
                 frame_buffer_id,
-                shared_mem_handle,
             } => {
                 if let Some(entity) = self.portals_by_id.get(&id).copied() {
-                    let frame_buffer = FrameBuffer::from_raw(size, shared_mem_handle)?;
+                    let frame_buffer = FrameBuffer::from_raw(size, shared_mem.ok_or(Error::NotSupported)?)?;
 
                     let frame_buffer_id =
                         self.world
@@ -122,6 +129,11 @@ impl ClientPipe {
                     self.world.get_mut::<ServerPortal>(entity).unwrap().move_to(pos);
                 }
             }
+
+            Command::Spawn { ref filename } => {
+                // The new app opens its own window
+                let _ = process::Command::new(filename).spawn();
+            }
         }
 
         Ok(())
@@ -1,20 +1,20 @@
 use crate::pipe;
 use alloc::boxed::Box;
-use alloc::collections::vec_deque::VecDeque;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::mem;
+use graphics_base::frame_buffer::FrameBuffer;
 use graphics_base::ipc;
 use graphics_base::types::{Command, Event};
 use graphics_base::Result;
 use hecs::World;
-use os::{File, Mutex, OSHandle};
+use os::{Channel, Mutex, Port, Process};
 
 pub type Callback = Box<dyn FnOnce(&mut World) -> Result<()> + Send>;
 
 pub struct AppSync {
     callbacks: Arc<Mutex<Vec<Callback>>>,
-    server2client: File,
+    channel: Channel,
 }
 
 impl AppSync {
@@ -23,33 +23,41 @@ impl AppSync {
         F: FnOnce(&mut World) -> Result<()> + Send + 'static,
     {
         self.callbacks.lock().push(Box::new(f));
-        let _ = ipc::send_message(&mut self.server2client, &Command::Checkpoint { id: 0 });
+
+        // The server answers with a checkpoint event, which wakes up the app's event loop
+        let _ = ipc::send_message(&self.channel, &Command::Checkpoint { id: 0 }, Vec::new());
     }
 }
 
 pub struct ClientPipe {
-    buf: VecDeque<u8>,
-    client2server: File,
-    server2client: File,
+    channel: Channel,
     callbacks: Arc<Mutex<Vec<Box<dyn FnOnce(&mut World) -> Result<()> + Send>>>>,
 }
 
 impl ClientPipe {
     pub fn new() -> Self {
         Self {
-            buf: VecDeque::new(),
-            client2server: File::from_raw(OSHandle::from_raw(2)),
-            server2client: File::from_raw(OSHandle::from_raw(3)),
+            channel: Port::connect(ipc::SERVER_PORT).unwrap(),
             callbacks: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
     pub fn send_command(&mut self, command: &Command) -> Result<()> {
-        ipc::send_message(&mut self.client2server, command)
+        ipc::send_message(&self.channel, command, Vec::new())
+    }
+
+    /// Sends a command along with a handle to the frame buffer's shared memory.
+    pub fn send_command_with_frame_buffer(&mut self, command: &Command, frame_buffer: &FrameBuffer) -> Result<()> {
+        let handle = frame_buffer.duplicate_handle()?;
+        ipc::send_message(&self.channel, command, vec![handle])
     }
 
     pub fn wait_for_event(&mut self) -> Result<(Event, Vec<Callback>)> {
-        let event = ipc::read_message(&mut self.buf, &mut self.server2client)?;
+        let event = match ipc::receive_message(&self.channel, &mut Vec::new())? {
+            Some(event) => event,
+            None => Process::exit(0),
+        };
+
         let callbacks = mem::replace(&mut *self.callbacks.lock(), Vec::new());
         Ok((event, callbacks))
     }
@@ -63,7 +71,7 @@ impl ClientPipe {
     pub fn sync(&self) -> AppSync {
         AppSync {
             callbacks: self.callbacks.clone(),
-            server2client: self.server2client.duplicate().unwrap(),
+            channel: self.channel.duplicate().unwrap(),
         }
     }
 }
@@ -11,14 +11,13 @@ use cairo::bindings::CAIRO_FORMAT_RGB24;
 use cairo::Cairo;
 use freetype::FreeType;
 use graphics_base::frame_buffer::{AsSurfaceMut, FrameBuffer};
+use graphics_base::share;
 use graphics_base::system::{ChangedIndex, DeletedIndex, System};
 use graphics_base::types::{Command, Event, EventInput, MouseButton, MouseInputInfo, Rect};
 use graphics_base::Result;
 use hashbrown::{HashMap, HashSet};
 use hecs::{Entity, World};
 
-static FONT_BYTES: &[u8] = include_bytes!("Vera.ttf");
-
 struct Decoration;
 
 struct DragDropState {
@@ -243,15 +242,15 @@ pub struct ClientPortalSystemPre {
 }
 
 impl ClientPortalSystemPre {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self> {
         let mut ft = FreeType::new();
-        let mut ft_face = freetype::Face::from_slice(&mut ft, FONT_BYTES, 0);
+        let mut ft_face = freetype::Face::from_slice(&mut ft, share::load("fonts/Vera.ttf")?, 0);
         ft_face.set_char_size(0.0, 16.0, 72, 72);
 
-        Self {
+        Ok(Self {
             _ft: ft,
             font_face: Rc::new(cairo::FontFace::from_freetype(&ft_face)),
-        }
+        })
     }
 }
 
@@ -336,7 +335,7 @@ impl ClientPortalSystem {
         entity: Entity,
         pos: Rect,
         on_paint: Option<&OnPaint>,
-    ) -> Result<((u16, u16), usize, usize)> {
+    ) -> Result<((u16, u16), usize)> {
         let Rect { width, height, .. } = pos;
         let size = ((width + 0.5) as u16, (height + 0.5) as u16);
 
@@ -385,9 +384,8 @@ impl ClientPortalSystem {
         }
 
         let frame_buffer_id = pipe::alloc_id();
-        let shared_mem_handle = frame_buffer.as_raw();
         self.busy_frame_buffers.insert(frame_buffer_id, frame_buffer);
-        Ok((size, frame_buffer_id, shared_mem_handle))
+        Ok((size, frame_buffer_id))
     }
 
     pub fn dispatch_event(&mut self, world: &mut World, event: Event) -> Result<()> {
@@ -446,16 +444,16 @@ impl System for ClientPortalSystem {
 
         for (entity, pos, on_paint) in new_portals {
             let id = pipe::alloc_id();
-            let (size, frame_buffer_id, shared_mem_handle) =
-                self.render_portal(world, entity, pos, on_paint.as_ref())?;
-
-            self.pipe.send_command(&Command::CreatePortal {
+            let (size, frame_buffer_id) = self.render_portal(world, entity, pos, on_paint.as_ref())?;
+            let command = Command::CreatePortal {
                 id,
                 pos,
                 size,
                 frame_buffer_id,
-                shared_mem_handle,
-            })?;
+            };
+
+            self.pipe
+                .send_command_with_frame_buffer(&command, &self.busy_frame_buffers[&frame_buffer_id])?;
 
             world.insert(entity, (ClientPortalId(id), Focus(None))).unwrap();
         }
@@ -488,13 +486,15 @@ impl System for ClientPortalSystem {
             .with::<NeedsPaint>()
             .iter()
         {
-            let (size, frame_buffer_id, shared_mem_handle) = self.render_portal(world, entity, pos, on_paint)?;
-            self.pipe.send_command(&Command::DrawPortal {
+            let (size, frame_buffer_id) = self.render_portal(world, entity, pos, on_paint)?;
+            let command = Command::DrawPortal {
                 id,
                 size,
                 frame_buffer_id,
-                shared_mem_handle,
-            })?;
+            };
+
+            self.pipe
+                .send_command_with_frame_buffer(&command, &self.busy_frame_buffers[&frame_buffer_id])?;
             needs_paint_entities.insert(entity);
         }
 
@@ -25,8 +25,9 @@ impl FrameBuffer {
         Ok(())
     }
 
-    pub fn as_raw(&self) -> usize {
-        self.data.as_handle().get()
+    /// Returns a new handle to the shared memory, for handing to another process.
+    pub fn duplicate_handle(&self) -> Result<OSHandle> {
+        self.data.as_handle().duplicate()
     }
 }
 
@@ -1,36 +1,29 @@
-use alloc::collections::vec_deque::VecDeque;
 use corepack;
+use os::{Channel, OSHandle};
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
-use std::io::{Read, Write};
 use syscall::{ErrNum, Result};
 
-struct FromFrontIter<'a, T: 'a>(&'a mut VecDeque<T>);
+/// Clients connect to the graphics server through the port with this name.
+pub const SERVER_PORT: &str = "graphics_server";
 
-impl<'a, T: 'a> Iterator for FromFrontIter<'a, T> {
-    type Item = T;
-
-    fn next(&mut self) -> Option<T> {
-        self.0.pop_front()
-    }
+/// Sends `message` over a channel in one go, moving `handles` to the process at the other end.
+pub fn send_message<T: Serialize>(channel: &Channel, message: &T, handles: Vec<OSHandle>) -> Result<()> {
+    let buf = corepack::to_bytes(message).or(Err(ErrNum::NotSupported))?;
+    channel.send(&buf, handles)
 }
 
-pub fn read_message<T: DeserializeOwned>(buf: &mut VecDeque<u8>, file: &mut dyn Read) -> Result<T> {
-    let mut temp = vec![0; 4096];
-    loop {
-        match corepack::from_iter(FromFrontIter(buf)) {
-            Ok(message) => return Ok(message),
-            Err(corepack::error::Error::EndOfStream) => {}
-            Err(e) => panic!("Unexpected corepack error: {}", e),
-        }
-
-        let bytes_read = file.read(&mut temp)?;
-        buf.extend(&temp[..bytes_read]);
+/// Receives one message from a channel, and appends any handles that came with it to `handles`. Returns `None` once
+/// the other end has been closed.
+pub fn receive_message<T: DeserializeOwned>(channel: &Channel, handles: &mut Vec<OSHandle>) -> Result<Option<T>> {
+    let mut buf = vec![0; 4096];
+    let handle_count = handles.len();
+    let len = channel.receive(&mut buf, handles)?;
+    if len == 0 && handles.len() == handle_count {
+        return Ok(None);
     }
-}
 
-pub fn send_message<T: Serialize>(file: &mut dyn Write, message: &T) -> Result<()> {
-    let buf = corepack::to_bytes(message).or(Err(ErrNum::NotSupported))?;
-    file.write_all(&buf)?;
-    Ok(())
+    // Anybody can connect to the server, so don't trust what comes out of the channel
+    let message = corepack::from_iter(buf[..len].iter().cloned()).or(Err(ErrNum::InvalidArgument))?;
+    Ok(Some(message))
 }
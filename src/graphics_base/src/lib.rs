@@ -24,6 +24,7 @@ mod compat {
 pub mod ipc;
 
 pub mod frame_buffer;
+pub mod share;
 pub mod system;
 pub mod types;
 
@@ -0,0 +1,30 @@
+//! Fonts and images, which are installed under /share rather than built into each program.
+
+use crate::Result;
+
+/// Maps `path` from under /share into memory, so that programs using the same font or image share its pages. The
+/// mapping is kept for as long as the program runs, since fonts and images made from it go on referring to it.
+#[cfg(target_os = "rust_os")]
+pub fn load(path: &str) -> Result<&'static [u8]> {
+    use os::{File, OSMem};
+
+    let file = File::open(&format!("/share/{}", path))?;
+    let len = file.stat()?.size as usize;
+    if len == 0 {
+        return Ok(&[]);
+    }
+
+    let mem: &'static OSMem<u8> = Box::leak(Box::new(file.map(0, len, false)?));
+    Ok(&mem[..])
+}
+
+/// Reads `path` from the source tree's copy of /share.
+#[cfg(not(target_os = "rust_os"))]
+pub fn load(path: &str) -> Result<&'static [u8]> {
+    use crate::Error;
+    use std::fs;
+
+    let filename = format!("{}/../share/{}", env!("CARGO_MANIFEST_DIR"), path);
+    let data = fs::read(filename).map_err(|_| Error::NotSupported)?;
+    Ok(Box::leak(data.into_boxed_slice()))
+}
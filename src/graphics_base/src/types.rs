@@ -40,29 +40,33 @@ pub enum Command {
         id: usize,
     },
 
+    /// Sent along with a handle to the frame buffer's shared memory.
     CreatePortal {
         id: usize,
         pos: Rect,
         size: (u16, u16),
         frame_buffer_id: usize,
-        shared_mem_handle: usize,
     },
 
     DestroyPortal {
         id: usize,
     },
 
+    /// Sent along with a handle to the frame buffer's shared memory.
     DrawPortal {
         id: usize,
         size: (u16, u16),
         frame_buffer_id: usize,
-        shared_mem_handle: usize,
     },
 
     MovePortal {
         id: usize,
         pos: Rect,
     },
+
+    Spawn {
+        filename: String,
+    },
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -11,16 +11,14 @@ use core::fmt::Write;
 use freetype::FreeType;
 use graphics::components::{FontFace, NeedsPaint, OnClick, OnInput, Parent, Position, Text};
 use graphics::widgets::{Button, ClientPortal, Label, TextBox};
-use graphics::{App, Event, Result};
-
-static FONT_BYTES: &[u8] = include_bytes!("Vera.ttf");
+use graphics::{share, App, Event, Result};
 
 fn main() -> Result<()> {
     let mut ft = FreeType::new();
 
     {
-        let mut app = App::new();
-        let mut ft_face = freetype::Face::from_slice(&mut ft, FONT_BYTES, 0);
+        let mut app = App::new()?;
+        let mut ft_face = freetype::Face::from_slice(&mut ft, share::load("fonts/Vera.ttf")?, 0);
         ft_face.set_char_size(0.0, 16.0, 72, 72);
 
         let cr_face = Rc::new(cairo::FontFace::from_freetype(&ft_face));
@@ -4,13 +4,14 @@ use graphics_base::frame_buffer::FrameBuffer;
 use graphics_base::ipc;
 use graphics_base::system::System;
 use graphics_base::types::{Command, Event};
-use graphics_base::Result;
+use graphics_base::{Error, Result};
 use hashbrown::HashMap;
 use hecs::{Entity, World};
-use os::{File, Mutex, Process};
+use os::{Channel, OSHandle};
 
 pub struct ServerApp {
-    portals_by_id: HashMap<usize, Entity>,
+    /// Keyed by client id and then portal id, since each client picks its own portal ids.
+    portals_by_id: HashMap<(usize, usize), Entity>,
     world: World,
     systems: Vec<Box<dyn System>>,
 }
@@ -33,14 +34,14 @@ impl ServerApp {
 
     pub fn handle_command(
         &mut self,
-        client_process: &Process,
-        server2client: &Arc<Mutex<File>>,
+        client_id: usize,
+        channel: &Arc<Channel>,
         command: Command,
+        mut handles: Vec<OSHandle>,
     ) -> Result<()> {
         match command {
             Command::Checkpoint { id } => {
-                let mut server2client = server2client.lock();
-                ipc::send_message(&mut *server2client, &Event::Checkpoint { id })?;
+                ipc::send_message(channel, &Event::Checkpoint { id }, Vec::new())?;
             }
 
             Command::CreatePortal {
@@ -48,14 +49,13 @@ impl ServerApp {
                 pos,
                 size: frame_buffer_size,
                 frame_buffer_id,
-                shared_mem_handle,
             } => {
                 let portal_ref = PortalRef {
                     portal_id: id,
-                    server2client: server2client.clone(),
+                    channel: channel.clone(),
                 };
 
-                let shared_mem_handle = client_process.open_handle(shared_mem_handle)?;
+                let shared_mem_handle = handles.pop().ok_or(Error::InvalidArgument)?;
                 let frame_buffer = FrameBuffer::from_raw(frame_buffer_size, shared_mem_handle)?;
 
                 let portal = ServerPortal::new(
@@ -68,11 +68,11 @@ impl ServerApp {
                 );
 
                 let entity = self.world.spawn((portal,));
-                self.portals_by_id.insert(id, entity);
+                self.portals_by_id.insert((client_id, id), entity);
             }
 
             Command::DestroyPortal { id } => {
-                if let Some(entity) = self.portals_by_id.remove(&id) {
+                if let Some(entity) = self.portals_by_id.remove(&(client_id, id)) {
                     self.world.despawn(entity).unwrap();
                 }
             }
@@ -81,10 +81,9 @@ impl ServerApp {
                 id,
                 size: frame_buffer_size,
                 frame_buffer_id,
-                shared_mem_handle,
             } => {
-                if let Some(entity) = self.portals_by_id.get(&id).copied() {
-                    let shared_mem_handle = client_process.open_handle(shared_mem_handle)?;
+                if let Some(entity) = self.portals_by_id.get(&(client_id, id)).copied() {
+                    let shared_mem_handle = handles.pop().ok_or(Error::InvalidArgument)?;
                     let frame_buffer = FrameBuffer::from_raw(frame_buffer_size, shared_mem_handle)?;
 
                     let frame_buffer_id = self.world.get_mut::<ServerPortal>(entity).unwrap().draw(
@@ -93,18 +92,40 @@ impl ServerApp {
                         frame_buffer,
                     );
 
-                    let mut server2client = server2client.lock();
-                    ipc::send_message(&mut *server2client, &Event::ReuseFrameBuffer { frame_buffer_id })?;
+                    let event = Event::ReuseFrameBuffer { frame_buffer_id };
+                    ipc::send_message(channel, &event, Vec::new())?;
                 }
             }
 
             Command::MovePortal { id, pos } => {
-                if let Some(entity) = self.portals_by_id.get(&id).copied() {
+                if let Some(entity) = self.portals_by_id.get(&(client_id, id)).copied() {
                     self.world.get_mut::<ServerPortal>(entity).unwrap().move_to(pos);
                 }
             }
+
+            // ServerPipe starts new clients itself
+            Command::Spawn { .. } => {}
         }
 
+        self.run_systems()
+    }
+
+    /// Destroys the portals belonging to a client that has gone away.
+    pub fn remove_client(&mut self, client_id: usize) -> Result<()> {
+        let world = &mut self.world;
+        self.portals_by_id.retain(|&(id, _), &mut entity| {
+            if id == client_id {
+                world.despawn(entity).unwrap();
+                false
+            } else {
+                true
+            }
+        });
+
+        self.run_systems()
+    }
+
+    fn run_systems(&mut self) -> Result<()> {
         for system in self.systems.iter_mut() {
             system.run(&mut self.world)?;
         }
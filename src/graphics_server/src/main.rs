@@ -13,7 +13,11 @@ use os::libc_helpers;
 use os::{File, Mutex, OSHandle, OSMem, Result, Thread};
 use std::io::Read;
 
+/// Input threads run ahead of painting, so that the mouse stays responsive while the screen is busy.
+const INPUT_PRIORITY: u8 = syscall::DEFAULT_PRIORITY + 1;
+
 fn keyboard_thread(keyboard_focus: Arc<Mutex<Option<PortalRef>>>) -> Result<()> {
+    Thread::set_priority(INPUT_PRIORITY)?;
     let mut stdin = File::from_raw(OSHandle::from_raw(libc_helpers::stdin));
     let mut buf = [0; 4];
     loop {
@@ -32,6 +36,7 @@ fn mouse_thread<S>(screen: Arc<Mutex<Screen<S>>>) -> Result<()>
 where
     S: AsSurfaceMut,
 {
+    Thread::set_priority(INPUT_PRIORITY)?;
     let mut mouse = File::open("ps2_mouse")?;
     let mut buf = [0; 6];
     loop {
@@ -64,11 +69,18 @@ fn main() -> Result<()> {
     let lfb_ptr = syscall::init_video_mode(800, 600, 32)?;
     let stride = cairo::stride_for_width(CAIRO_FORMAT_ARGB32, 800);
     let lfb = unsafe { OSMem::from_raw(lfb_ptr, stride * 600) };
-    let screen = Arc::new(Mutex::new(Screen::new((800, 600), lfb)));
+    let screen = Arc::new(Mutex::new(Screen::new((800, 600), lfb)?));
     let keyboard_focus = Arc::new(Mutex::new(None));
     let mut app = ServerApp::new();
     app.add_system(ServerPortalSystem::new(screen.clone(), keyboard_focus.clone()));
     Thread::spawn(move || mouse_thread(screen).unwrap());
     Thread::spawn(move || keyboard_thread(keyboard_focus).unwrap());
-    ServerPipe::new(app, "terminal")?.run()
+
+    let mut pipe = ServerPipe::new(app)?;
+    pipe.spawn("terminal")?;
+    for filename in os::env::args().skip(1) {
+        pipe.spawn(filename)?;
+    }
+
+    pipe.run()
 }
@@ -1,43 +1,95 @@
 use crate::app::ServerApp;
-use alloc::collections::vec_deque::VecDeque;
 use alloc::sync::Arc;
-use core::str;
 use graphics_base::ipc;
+use graphics_base::types::Command;
 use graphics_base::Result;
+use hashbrown::HashMap;
 use os::libc_helpers;
-use os::{File, Mutex, Process};
+use os::{Channel, Port, Process};
+
+/// The apps that a client can ask the server to start. Anybody can connect to the server, so they can't start just any
+/// executable, which would run with the server's own stdin and stdout.
+const CLIENT_APPS: &[&str] = &["graphics_client", "terminal"];
 
 pub struct ServerPipe {
     server: ServerApp,
-    process: Process,
-    client2server: File,
-    server2client: Arc<Mutex<File>>,
+    port: Port,
+    connections: HashMap<usize, Arc<Channel>>,
+    next_client_id: usize,
 }
 
 impl ServerPipe {
-    pub fn new(server: ServerApp, filename: &str) -> Result<Self> {
-        let client2server = File::create_pipe();
-        let server2client = File::create_pipe();
-        let inherit = [
-            libc_helpers::stdin,
-            libc_helpers::stdout,
-            client2server.handle().get(),
-            server2client.handle().get(),
-        ];
-
+    pub fn new(server: ServerApp) -> Result<Self> {
         Ok(ServerPipe {
             server,
-            process: Process::spawn(filename, &inherit)?,
-            client2server,
-            server2client: Arc::new(Mutex::new(server2client)),
+            port: Port::register(ipc::SERVER_PORT)?,
+            connections: HashMap::new(),
+            next_client_id: 1,
         })
     }
 
+    /// Starts `filename`, which connects to the server like any other client.
+    pub fn spawn(&mut self, filename: &str) -> Result<()> {
+        Process::spawn(filename, &[libc_helpers::stdin, libc_helpers::stdout])?;
+        Ok(())
+    }
+
+    /// Accepts new clients, and handles commands from the existing ones as they arrive.
     pub fn run(mut self) -> Result<()> {
-        let mut buf = VecDeque::new();
         loop {
-            let c = ipc::read_message(&mut buf, &mut self.client2server)?;
-            self.server.handle_command(&self.process, &self.server2client, c)?;
+            let mut client_ids = Vec::with_capacity(self.connections.len());
+            let mut handles = Vec::with_capacity(self.connections.len() + 1);
+            handles.push(self.port.handle().get());
+            for (&client_id, channel) in self.connections.iter() {
+                client_ids.push(client_id);
+                handles.push(channel.handle().get());
+            }
+
+            match os::wait_for_any(&handles, None)? {
+                0 => {
+                    let channel = self.port.accept()?;
+                    let client_id = self.next_client_id;
+                    self.next_client_id += 1;
+                    self.connections.insert(client_id, Arc::new(channel));
+                }
+
+                index => self.handle_client(client_ids[index - 1])?,
+            }
+        }
+    }
+
+    /// Handles the next message from a client. A client that has gone away, or that sends a command that fails, is
+    /// disconnected rather than stopping the server.
+    fn handle_client(&mut self, client_id: usize) -> Result<()> {
+        let channel = self.connections[&client_id].clone();
+        let mut handles = Vec::new();
+        let command = match ipc::receive_message(&channel, &mut handles) {
+            Ok(Some(command)) => command,
+            Ok(None) | Err(_) => return self.disconnect(client_id),
+        };
+
+        let result = match command {
+            Command::Spawn { filename } => {
+                // There's nowhere to report a failure to, and the client that asked isn't to blame for it
+                if CLIENT_APPS.contains(&filename.as_str()) {
+                    let _ = self.spawn(&filename);
+                }
+
+                Ok(())
+            }
+
+            command => self.server.handle_command(client_id, &channel, command, handles),
+        };
+
+        if result.is_err() {
+            return self.disconnect(client_id);
         }
+
+        Ok(())
+    }
+
+    fn disconnect(&mut self, client_id: usize) -> Result<()> {
+        self.connections.remove(&client_id);
+        self.server.remove_client(client_id)
     }
 }
@@ -14,30 +14,28 @@ mod rust_os {
     use graphics_base::ipc;
     use graphics_base::types::{Event, EventInput};
     use graphics_base::Result;
-    use os::{File, Mutex};
+    use os::Channel;
 
     #[derive(Clone)]
     pub struct PortalRef {
-        pub server2client: Arc<Mutex<File>>,
+        pub channel: Arc<Channel>,
         pub portal_id: usize,
     }
 
     impl PartialEq for PortalRef {
         fn eq(&self, other: &Self) -> bool {
-            self.portal_id == other.portal_id && Arc::ptr_eq(&self.server2client, &other.server2client)
+            self.portal_id == other.portal_id && Arc::ptr_eq(&self.channel, &other.channel)
         }
     }
 
     impl PortalRef {
         pub fn send_input(&self, input: EventInput) -> Result<()> {
-            let mut server2client = self.server2client.lock();
-            ipc::send_message(
-                &mut *server2client,
-                &Event::Input {
-                    portal_id: self.portal_id,
-                    input,
-                },
-            )
+            let event = Event::Input {
+                portal_id: self.portal_id,
+                input,
+            };
+
+            ipc::send_message(&self.channel, &event, Vec::new())
         }
     }
 }
@@ -4,6 +4,7 @@ use cairo::bindings::{CAIRO_FORMAT_A8, CAIRO_FORMAT_ARGB32, CAIRO_FORMAT_RGB24};
 use cairo::{Cairo, Surface};
 use core::mem;
 use graphics_base::frame_buffer::{AsSurface, AsSurfaceMut, FrameBuffer};
+use graphics_base::share;
 use graphics_base::types::{EventInput, MouseButton, MouseInputInfo, Rect};
 use graphics_base::{Error, Result};
 use jpeg_decoder::{Decoder, ImageInfo, PixelFormat};
@@ -75,15 +76,12 @@ impl<S> Screen<S>
 where
     S: AsSurfaceMut,
 {
-    pub fn new(screen_size: (u16, u16), lfb: S) -> Self {
-        static CURSOR_BYTES: &'static [u8] = include_bytes!("icons8-cursor-32.png");
-        static WALLPAPER_BYTES: &'static [u8] = include_bytes!("wallpaper.jpg");
-
-        let cursor = Surface::from_png_slice(CURSOR_BYTES).unwrap();
+    pub fn new(screen_size: (u16, u16), lfb: S) -> Result<Self> {
+        let cursor = Surface::from_png_slice(share::load("images/icons8-cursor-32.png")?).unwrap();
         let cursor_hotspot = (screen_size.0 / 2, screen_size.1 / 2);
-        let wallpaper = surface_from_jpeg_slice(WALLPAPER_BYTES).unwrap();
+        let wallpaper = surface_from_jpeg_slice(share::load("images/wallpaper.jpg")?)?;
 
-        Self {
+        Ok(Self {
             cursor_hotspot,
             cursor_sprite: to_sprite(cursor_hotspot),
             buttons: [false; 3],
@@ -93,7 +91,7 @@ where
             wallpaper,
             buffers: Vec::new(),
             input_capture: None,
-        }
+        })
     }
 
     fn draw_buffers(cr: &Cairo, screen_size: (u16, u16), wallpaper: &Surface, buffers: &[ScreenBuffer]) {
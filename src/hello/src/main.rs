@@ -1,6 +1,10 @@
 extern crate alloc_system;
 extern crate rt;
 
+use std::env;
+
 fn main() {
-    println!("hello world");
+    // Greets whoever is named on the command line, which is how the kernel's tests see the arguments get through
+    let name = env::args().nth(1).unwrap_or_else(|| String::from("world"));
+    println!("hello {}", name);
 }
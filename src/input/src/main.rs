@@ -2,14 +2,15 @@ extern crate alloc_system;
 extern crate rt;
 
 use os::libc_helpers::{stdin, stdout};
-use os::Process;
+use os::{File, Process};
 use syscall::{ErrNum, Result};
 
-fn read_line(buf: &mut Vec<u8>) -> Result<String> {
+/// Returns the next line, or `None` once stdin has been closed.
+fn read_line(buf: &mut Vec<u8>) -> Result<Option<String>> {
     loop {
         if let Some(pos) = buf.iter().position(|&b| b == b'\n') {
             let line = buf.drain(..pos + 1).collect();
-            return String::from_utf8(line).map_err(|_| ErrNum::Utf8Error);
+            return String::from_utf8(line).map(Some).map_err(|_| ErrNum::Utf8Error);
         }
 
         let index = buf.len();
@@ -17,28 +18,46 @@ fn read_line(buf: &mut Vec<u8>) -> Result<String> {
 
         let len = syscall::read(stdin, &mut buf[index..])?;
         buf.truncate(index + len);
+        if len == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// Runs a command with its arguments, sending its output to a file if the line ends with `> filename`.
+fn run_line(line: &str) -> Result<()> {
+    let (command, output) = match line.find('>') {
+        Some(pos) => (line[..pos].trim(), Some(File::create(line[pos + 1..].trim())?)),
+        None => (line.trim(), None),
+    };
+
+    let args = command.split_whitespace().collect::<Vec<_>>();
+    if args.is_empty() {
+        return Ok(());
     }
+
+    let output_handle = output.as_ref().map_or(stdout, |file| file.handle().get());
+    Process::spawn_with_args(args[0], &args, &[stdin, output_handle])?.wait_for_exit()?;
+    Ok(())
 }
 
 fn main() -> Result<()> {
-    let inherit = [stdin, stdout];
     let mut buf = Vec::new();
     loop {
         print!("> ");
 
-        let mut line = read_line(&mut buf)?;
+        let mut line = match read_line(&mut buf)? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+
         assert_eq!(line.pop(), Some('\n'));
         if line == "exit" {
             return Ok(());
         }
 
         if line.len() > 0 {
-            let run_line = move || -> Result<()> {
-                Process::spawn(&line, &inherit)?.wait_for_exit()?;
-                Ok(())
-            };
-
-            if let Some(num) = run_line().err() {
+            if let Some(num) = run_line(&line).err() {
                 println!("{:?}", num);
             }
         }
@@ -0,0 +1,232 @@
+//! Finds the processors and interrupt controllers in the machine by reading the ACPI tables.
+
+use crate::arch::mmu;
+use crate::phys_mem;
+use crate::prelude::*;
+use core::convert::TryInto;
+
+const SDT_HEADER_LEN: usize = 36;
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+pub struct IoApic {
+    pub id: u8,
+    pub addr: usize,
+    pub gsi_base: u32,
+}
+
+/// Records that an ISA IRQ is wired to a different global system interrupt, or isn't edge triggered and active high.
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IrqRoute {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// The Multiple APIC Description Table, which lists the local APIC of each processor and the I/O APICs.
+pub struct Madt {
+    pub local_apic_addr: usize,
+    /// Local APIC ids of the processors that are enabled, in the order the firmware lists them.
+    pub apic_ids: Vec<u8>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    pub fn parse(table: &[u8]) -> Option<Self> {
+        if table.len() < SDT_HEADER_LEN + 8 || &table[..4] != b"APIC" || !checksum_ok(table) {
+            return None;
+        }
+
+        let mut madt = Madt {
+            local_apic_addr: u32_at(table, SDT_HEADER_LEN) as usize,
+            apic_ids: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        let mut offset = SDT_HEADER_LEN + 8;
+        while offset + 2 <= table.len() {
+            let len = table[offset + 1] as usize;
+            if len < 2 || offset + len > table.len() {
+                return None;
+            }
+
+            let entry = &table[offset..offset + len];
+            match entry[0] {
+                0 if len >= 8 => {
+                    if u32_at(entry, 4) & 1 != 0 {
+                        madt.apic_ids.push(entry[3]);
+                    }
+                }
+
+                1 if len >= 12 => madt.io_apics.push(IoApic {
+                    id: entry[2],
+                    addr: u32_at(entry, 4) as usize,
+                    gsi_base: u32_at(entry, 8),
+                }),
+
+                2 if len >= 10 => madt.overrides.push(InterruptOverride {
+                    source: entry[3],
+                    gsi: u32_at(entry, 4),
+                    flags: u16_at(entry, 8),
+                }),
+
+                5 if len >= 12 => madt.local_apic_addr = u64_at(entry, 4) as usize,
+                _ => (),
+            }
+
+            offset += len;
+        }
+
+        Some(madt)
+    }
+
+    /// Returns the global system interrupt that an ISA IRQ arrives on, or `None` if another IRQ has taken it over.
+    pub fn isa_irq(&self, irq: u8) -> Option<IrqRoute> {
+        if let Some(o) = self.overrides.iter().find(|o| o.source == irq) {
+            // Bits 0-1 are the polarity and bits 2-3 the trigger mode; 0 means the bus's default
+            return Some(IrqRoute {
+                gsi: o.gsi,
+                active_low: o.flags & 3 == 3,
+                level_triggered: (o.flags >> 2) & 3 == 3,
+            });
+        }
+
+        if self.overrides.iter().any(|o| o.gsi == irq as u32) {
+            return None;
+        }
+
+        Some(IrqRoute {
+            gsi: irq as u32,
+            active_low: false,
+            level_triggered: false,
+        })
+    }
+}
+
+fn find_rsdp() -> Option<&'static [u8]> {
+    let ebda = unsafe { *phys_mem::phys2virt::<u16>(0x40e) } as usize * 16;
+    let ranges = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+    ranges
+        .iter()
+        .filter(|&&(start, _)| start != 0)
+        .flat_map(|&(start, end)| (start..end).step_by(16))
+        .find_map(|addr| {
+            let rsdp: &[u8; 36] = unsafe { phys_mem::phys2virt(addr) };
+            if &rsdp[..8] == b"RSD PTR " && checksum_ok(&rsdp[..20]) {
+                Some(&rsdp[..])
+            } else {
+                None
+            }
+        })
+}
+
+/// ACPI tables are usually near the top of memory, outside the boot page tables, so they're mapped like devices.
+unsafe fn map_table(addr: usize) -> &'static [u8] {
+    let header = mmu::map_mmio(addr, SDT_HEADER_LEN);
+    mmu::map_mmio(addr, u32_at(header, 4) as usize)
+}
+
+/// Looks for the MADT through the RSDT, or through the XSDT on ACPI 2.0 and later.
+pub fn find_madt() -> Option<Madt> {
+    let rsdp = find_rsdp()?;
+    let xsdt_addr = if rsdp[15] >= 2 { u64_at(rsdp, 24) as usize } else { 0 };
+    let (root_addr, entry_len) = if xsdt_addr != 0 {
+        (xsdt_addr, 8)
+    } else {
+        (u32_at(rsdp, 16) as usize, 4)
+    };
+
+    let root = unsafe { map_table(root_addr) };
+    if !checksum_ok(root) {
+        return None;
+    }
+
+    for entry in root[SDT_HEADER_LEN..].chunks_exact(entry_len) {
+        let addr = if entry_len == 8 {
+            u64_at(entry, 0) as usize
+        } else {
+            u32_at(entry, 0) as usize
+        };
+
+        let header = unsafe { mmu::map_mmio(addr, SDT_HEADER_LEN) };
+        if &header[..4] == b"APIC" {
+            return Madt::parse(unsafe { map_table(addr) });
+        }
+    }
+
+    None
+}
+
+#[cfg(feature = "test")]
+pub mod test {
+    use super::*;
+
+    fn madt_bytes() -> Vec<u8> {
+        let mut table = b"APIC".to_vec();
+        table.resize(SDT_HEADER_LEN, 0);
+        table.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+        table.extend_from_slice(&1u32.to_le_bytes());
+        table.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]); // processor 0, enabled
+        table.extend_from_slice(&[0, 8, 1, 1, 1, 0, 0, 0]); // processor 1, enabled
+        table.extend_from_slice(&[0, 8, 2, 2, 0, 0, 0, 0]); // processor 2, disabled
+        table.extend_from_slice(&[1, 12, 3, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0]); // I/O APIC at 0xfec00000
+        table.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]); // IRQ 0 -> GSI 2
+        table.extend_from_slice(&[2, 10, 0, 9, 9, 0, 0, 0, 0x0f, 0]); // IRQ 9: level triggered, active low
+
+        let len = table.len() as u32;
+        table[4..8].copy_from_slice(&len.to_le_bytes());
+
+        let sum = table.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        table[9] = 0u8.wrapping_sub(sum);
+        table
+    }
+
+    test! {
+        fn can_parse_madt() {
+            let madt = Madt::parse(&madt_bytes()).unwrap();
+            assert_eq!(0xfee0_0000, madt.local_apic_addr);
+            assert_eq!(vec![0, 1], madt.apic_ids);
+            assert_eq!(1, madt.io_apics.len());
+            assert_eq!(3, madt.io_apics[0].id);
+            assert_eq!(0xfec0_0000, madt.io_apics[0].addr);
+            assert_eq!(2, madt.overrides.len());
+        }
+
+        fn can_route_isa_irqs() {
+            let madt = Madt::parse(&madt_bytes()).unwrap();
+            let edge = |gsi| Some(IrqRoute { gsi, active_low: false, level_triggered: false });
+            assert_eq!(edge(2), madt.isa_irq(0));
+            assert_eq!(edge(1), madt.isa_irq(1));
+            assert_eq!(None, madt.isa_irq(2));
+            assert_eq!(Some(IrqRoute { gsi: 9, active_low: true, level_triggered: true }), madt.isa_irq(9));
+        }
+
+        fn rejects_bad_checksum() {
+            let mut table = madt_bytes();
+            table[9] = table[9].wrapping_add(1);
+            assert!(Madt::parse(&table).is_none());
+        }
+    }
+}
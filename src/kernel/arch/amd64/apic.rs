@@ -0,0 +1,160 @@
+//! The local APIC that each processor has, and the I/O APICs that route device interrupts to them.
+
+use crate::arch::acpi::IrqRoute;
+use crate::arch::mmu;
+use core::intrinsics;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Vector for the local APIC timer, which preempts threads on processors other than the first.
+pub const TIMER_VECTOR: u8 = 0x31;
+/// Vector for the interrupt that tells a processor to flush its TLB.
+pub const FLUSH_TLB_VECTOR: u8 = 0x32;
+pub const SPURIOUS_VECTOR: u8 = 0x3f;
+
+const REG_ID: usize = 0x20;
+const REG_TPR: usize = 0x80;
+const REG_EOI: usize = 0xb0;
+const REG_SVR: usize = 0xf0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3e0;
+
+const SVR_ENABLE: u32 = 0x100;
+const ICR_INIT: u32 = 0x500;
+const ICR_STARTUP: u32 = 0x600;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 3 << 18;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_16: u32 = 3;
+
+static LOCAL_APIC: AtomicPtr<u32> = AtomicPtr::new(0 as *mut u32);
+
+unsafe fn read(reg: usize) -> u32 {
+    let base = LOCAL_APIC.load(Ordering::Relaxed);
+    intrinsics::volatile_load(base.add(reg / 4))
+}
+
+unsafe fn write(reg: usize, value: u32) {
+    let base = LOCAL_APIC.load(Ordering::Relaxed);
+    intrinsics::volatile_store(base.add(reg / 4), value)
+}
+
+/// Maps the local APIC's registers. Every processor sees its own local APIC at the same address.
+pub unsafe fn map_local(addr: usize) {
+    let regs = mmu::map_mmio(addr, 0x400);
+    LOCAL_APIC.store(regs.as_mut_ptr() as *mut u32, Ordering::Relaxed);
+}
+
+/// Turns on the current processor's local APIC, so that it accepts interrupts from the I/O APIC and other processors.
+pub unsafe fn enable_local() {
+    write(REG_TPR, 0);
+    write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+pub fn local_id() -> u8 {
+    unsafe { (read(REG_ID) >> 24) as u8 }
+}
+
+pub fn eoi() {
+    unsafe { write(REG_EOI, 0) }
+}
+
+unsafe fn send_ipi(dest: u32, command: u32) {
+    write(REG_ICR_HIGH, dest << 24);
+    write(REG_ICR_LOW, command);
+    while read(REG_ICR_LOW) & ICR_PENDING != 0 {}
+}
+
+pub unsafe fn send_init(apic_id: u8) {
+    send_ipi(apic_id as u32, ICR_INIT | ICR_ASSERT);
+}
+
+/// Tells a processor that has had an INIT to start running real mode code at `page * 4096`.
+pub unsafe fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id as u32, ICR_STARTUP | ICR_ASSERT | page as u32);
+}
+
+pub unsafe fn send_to_others(vector: u8) {
+    send_ipi(0, ICR_ALL_EXCLUDING_SELF | ICR_ASSERT | vector as u32);
+}
+
+/// Counts down the timer from its largest value, for calibrating it against another clock.
+pub unsafe fn start_timer_countdown() {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REG_LVT_TIMER, LVT_MASKED);
+    write(REG_TIMER_INITIAL, u32::max_value());
+}
+
+/// Stops the countdown started by `start_timer_countdown` and returns how far it got.
+pub unsafe fn stop_timer_countdown() -> u32 {
+    let elapsed = u32::max_value() - read(REG_TIMER_CURRENT);
+    write(REG_TIMER_INITIAL, 0);
+    elapsed
+}
+
+/// Interrupts the current processor on `TIMER_VECTOR` every `count` timer cycles.
+pub unsafe fn start_periodic_timer(count: u32) {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REG_LVT_TIMER, LVT_PERIODIC | TIMER_VECTOR as u32);
+    write(REG_TIMER_INITIAL, count);
+}
+
+pub struct IoApic {
+    regs: *mut u32,
+    gsi_base: u32,
+    count: u32,
+}
+
+unsafe impl Send for IoApic {}
+unsafe impl Sync for IoApic {}
+
+impl IoApic {
+    pub unsafe fn new(addr: usize, gsi_base: u32) -> Self {
+        let regs = mmu::map_mmio(addr, 0x20).as_mut_ptr() as *mut u32;
+        let mut io_apic = IoApic {
+            regs,
+            gsi_base,
+            count: 0,
+        };
+
+        io_apic.count = ((io_apic.read(1) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        intrinsics::volatile_store(self.regs, reg);
+        intrinsics::volatile_load(self.regs.add(4))
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        intrinsics::volatile_store(self.regs, reg);
+        intrinsics::volatile_store(self.regs.add(4), value);
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.count
+    }
+
+    /// Delivers an interrupt to one processor on the given vector.
+    pub unsafe fn route(&self, route: &IrqRoute, vector: u8, apic_id: u8) {
+        assert!(self.handles(route.gsi));
+
+        let mut low = vector as u32;
+        if route.active_low {
+            low |= 1 << 13;
+        }
+
+        if route.level_triggered {
+            low |= 1 << 15;
+        }
+
+        let reg = 0x10 + (route.gsi - self.gsi_base) * 2;
+        self.write(reg + 1, (apic_id as u32) << 24);
+        self.write(reg, low);
+    }
+}
@@ -80,6 +80,8 @@ pub const IA32_STAR: u32 = 0xC0000081;
 pub const IA32_LSTAR: u32 = 0xC0000082;
 pub const IA32_SFMASK: u32 = 0xC0000084;
 pub const IA32_FSBASE: u32 = 0xC0000100;
+pub const IA32_GSBASE: u32 = 0xC0000101;
+pub const IA32_KERNEL_GSBASE: u32 = 0xC0000102;
 
 extern "C" {
     pub fn lidt(ptr: &Dtr); // I don't know why I can't get lidt to work via inline asm
@@ -89,8 +91,8 @@ pub fn invlpg<T>(ptr: *const T) {
     unsafe { asm!("invlpg ($0)" :: "r"(ptr) : "memory" : "volatile") }
 }
 
-pub unsafe fn sysret<T, U>(rip: *const T, rsp: *const U, rdi: usize, rflags: u64) -> ! {
-    asm!("cli ; mov $0, %rsp ; sysretq" :: "r"(rsp), "{rcx}" (rip), "{rdi}"(rdi), "{r11}" (rflags) :: "volatile");
+pub unsafe fn sysret<T, U>(rip: *const T, rsp: *const U, rdi: usize, rsi: usize, rflags: u64) -> ! {
+    asm!("cli ; swapgs ; mov $0, %rsp ; sysretq" :: "r"(rsp), "{rcx}" (rip), "{rdi}"(rdi), "{rsi}"(rsi), "{r11}" (rflags) :: "volatile");
     unreachable!()
 }
 
@@ -101,6 +103,13 @@ pub fn rdmsr(reg: u32) -> u64 {
     (value_hi as u64) << 32 | (value_lo as u64)
 }
 
+pub fn rdtsc() -> u64 {
+    let value_hi: u32;
+    let value_lo: u32;
+    unsafe { asm!("rdtsc" : "={edx}" (value_hi), "={eax}" (value_lo) ::: "volatile") };
+    (value_hi as u64) << 32 | (value_lo as u64)
+}
+
 pub unsafe fn wrmsr(reg: u32, value: u64) {
     let value_hi = (value >> 32) as u32;
     let value_lo = value as u32;
@@ -47,3 +47,18 @@ irq(d)
 irq(e)
 irq(f)
 interrupt(30)
+interrupt(31)
+interrupt(32)
+interrupt(33)
+interrupt(34)
+interrupt(35)
+interrupt(36)
+interrupt(37)
+interrupt(38)
+interrupt(39)
+interrupt(3a)
+interrupt(3b)
+interrupt(3c)
+interrupt(3d)
+interrupt(3e)
+interrupt(3f)
@@ -1,6 +1,9 @@
+use crate::arch::apic;
 use crate::arch::cpu::{self, DescriptorExtra, Dtr, InterruptDescriptor, Regs, Tss};
 use crate::arch::debug;
 use crate::arch::mmu;
+use crate::arch::pit;
+use crate::arch::smp::{self, MAX_CPUS};
 use crate::once::{self, Once};
 use crate::prelude::*;
 use crate::process;
@@ -8,15 +11,17 @@ use crate::ptr::{self, Align};
 use crate::singleton::{DropSingleton, Singleton};
 use crate::thread;
 use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 extern "C" {
     static GDT: u8;
-    static mut GDT_TSS: DescriptorExtra;
-    static mut TSS: Tss;
+    static mut GDT_TSS: [DescriptorExtra; MAX_CPUS];
+    static GDTEnd: u8;
+    static mut TSS: [Tss; MAX_CPUS];
     static TSSEnd: u8;
-    static mut IDT: [InterruptDescriptor; 49];
+    static mut IDT: [InterruptDescriptor; 64];
     static IDTEnd: u8;
-    static interrupt_handlers: [u64; 49];
+    static interrupt_handlers: [u64; 64];
     static interrupt_handlers_end: u8;
 }
 
@@ -38,6 +43,12 @@ const PIC1_DATA: u16 = PIC1 + 1;
 const PIC2_COMMAND: u16 = PIC2;
 const PIC2_DATA: u16 = PIC2 + 1;
 
+/// Vector that IRQ 0 arrives on. The other IRQs follow it.
+pub const IRQ_BASE: u8 = 32;
+
+/// Set once device interrupts come through the I/O APIC instead of the PIC.
+static USE_APIC: AtomicBool = AtomicBool::new(false);
+
 pub type DropIrqHandler = DropSingleton<Box<dyn Fn()>>;
 
 pub fn register_irq_handler<T: Fn() + 'static>(irq: usize, handler: T) -> DropIrqHandler {
@@ -71,29 +82,13 @@ pub fn init_once() {
     static ONCE: Once = once::ONCE_INIT;
     ONCE.call_once(|| unsafe {
         assert_size!(&TSS, &TSSEnd);
+        assert_len!(&GDT_TSS, &GDTEnd);
         assert_len!(&interrupt_handlers, &interrupt_handlers_end);
         assert_len!(&IDT, &IDTEnd);
         assert_eq!(104, mem::size_of::<Tss>());
         assert_eq!(10, mem::size_of::<Dtr>());
         assert!(Align::is_aligned(mem::size_of::<Regs>(), 16));
-
-        let tss_selector = ptr::bytes_between(&GDT, &GDT_TSS as *const _ as *const u8) as u16;
-        assert_eq!(0x38, tss_selector);
-
-        let tss_ptr = &TSS as *const _ as usize;
-        GDT_TSS = Default::default();
-        GDT_TSS.limit_low = mem::size_of::<Tss>() as u16;
-        GDT_TSS.base_low = tss_ptr as u16;
-        GDT_TSS.base_mid = (tss_ptr >> 16) as u8;
-        GDT_TSS.base_high = (tss_ptr >> 24) as u8;
-        GDT_TSS.base_extra = (tss_ptr >> 32) as u32;
-        GDT_TSS.access = 0x89;
-        GDT_TSS.limit_high_and_flags = 0x10;
-
-        TSS = Default::default();
-        TSS.iopm_len = mem::size_of::<Tss>() as u16;
-
-        cpu::ltr(tss_selector);
+        assert_eq!(0x38, ptr::bytes_between(&GDT, GDT_TSS.as_ptr() as *const u8));
 
         for (handler_ptr, desc) in interrupt_handlers.iter().zip(IDT.iter_mut()) {
             let handler_ptr: u64 = *handler_ptr;
@@ -105,19 +100,14 @@ pub fn init_once() {
             desc.selector = 0x08;
         }
 
-        let idtr = Dtr {
-            limit: mem::size_of_val(&IDT) as u16,
-            base: IDT.as_ptr() as u64,
-        };
-
-        cpu::lidt(&idtr);
+        smp::init_cpu(0);
 
         const ICW1_ICW4: u8 = 0x01; /* ICW4 (not) needed */
         const ICW1_INIT: u8 = 0x10; /* Initialization - required! */
         const ICW4_8086: u8 = 0x01; /* 8086/88 (MCS-80/85) mode */
 
-        const OFFSET1: u8 = 32;
-        const OFFSET2: u8 = 40;
+        const OFFSET1: u8 = IRQ_BASE;
+        const OFFSET2: u8 = IRQ_BASE + 8;
 
         let a1 = cpu::inb(PIC1_DATA); // save masks
         let a2 = cpu::inb(PIC2_DATA);
@@ -135,41 +125,91 @@ pub fn init_once() {
         cpu::outb(PIC1_DATA, a1); // restore saved masks.
         cpu::outb(PIC2_DATA, a2);
 
+        pit::init();
         cpu::sti();
     });
 }
 
+/// Gives the current CPU its own TSS, and loads the IDT that all CPUs share.
+pub unsafe fn init_cpu(index: usize) {
+    let tss = &mut TSS[index];
+    *tss = Default::default();
+    tss.iopm_len = mem::size_of::<Tss>() as u16;
+
+    let tss_ptr = tss as *const _ as usize;
+    let desc = &mut GDT_TSS[index];
+    *desc = Default::default();
+    desc.limit_low = mem::size_of::<Tss>() as u16;
+    desc.base_low = tss_ptr as u16;
+    desc.base_mid = (tss_ptr >> 16) as u8;
+    desc.base_high = (tss_ptr >> 24) as u8;
+    desc.base_extra = (tss_ptr >> 32) as u32;
+    desc.access = 0x89;
+    desc.limit_high_and_flags = 0x10;
+
+    let tss_selector = ptr::bytes_between(&GDT, desc as *const _ as *const u8) as u16;
+    cpu::ltr(tss_selector);
+
+    let idtr = Dtr {
+        limit: mem::size_of_val(&IDT) as u16,
+        base: IDT.as_ptr() as u64,
+    };
+
+    cpu::lidt(&idtr);
+}
+
+/// Masks the PIC, once the I/O APIC has been set up to deliver the same IRQs to the same vectors.
+pub unsafe fn use_apic() {
+    cpu::outb(PIC1_DATA, 0xff);
+    cpu::outb(PIC2_DATA, 0xff);
+    USE_APIC.store(true, Ordering::SeqCst);
+}
+
 pub unsafe fn switch(kernel_rsp: *mut u8, fs_base: *mut u8) {
     assert!(Align::is_aligned(kernel_rsp, 16));
-    TSS.rsp0 = kernel_rsp as u64;
+    let this_cpu = smp::this_cpu();
+    this_cpu.kernel_rsp = kernel_rsp as u64;
+    TSS[this_cpu.index].rsp0 = kernel_rsp as u64;
     cpu::wrmsr(cpu::IA32_FSBASE, fs_base as u64);
 }
 
 #[no_mangle]
-pub extern "C" fn irq(num: usize, _: &Regs) {
+pub extern "C" fn irq(num: usize, regs: &Regs) {
     unsafe {
         const PIC_EOI: u8 = 0x20; // End-of-interrupt command code
 
-        if num != 0 {
-            if let Some(singleton) = IRQ_HANDLERS.get(num) {
-                if let Some(handler) = singleton.get() {
-                    handler();
-                }
+        if let Some(singleton) = IRQ_HANDLERS.get(num) {
+            if let Some(handler) = singleton.get() {
+                handler();
             }
         }
 
-        if num >= 8 {
-            cpu::outb(PIC2_COMMAND, PIC_EOI);
+        if USE_APIC.load(Ordering::Relaxed) {
+            apic::eoi();
+        } else {
+            if num >= 8 {
+                cpu::outb(PIC2_COMMAND, PIC_EOI);
+            }
+
+            cpu::outb(PIC1_COMMAND, PIC_EOI);
         }
+    }
+
+    // The interrupt controller has been acknowledged, so it's safe to switch away from this stack
+    thread::preempt();
 
-        cpu::outb(PIC1_COMMAND, PIC_EOI);
+    if regs.cs & 3 == 3 {
+        thread::exit_if_killed();
     }
 }
 
 #[no_mangle]
 pub extern "C" fn exception(num: u8, regs: &Regs) {
     let cr2: *mut u8 = cpu::read_cr2();
-    if num == 14 && (regs.error & 1) == 0 && process::resolve_page_fault(cr2) {
+    let (present, writing) = ((regs.error & 1) != 0, (regs.error & 2) != 0);
+
+    // Writing to a page that's present can mean it needs copying, if it's shared with a forked process
+    if num == 14 && (!present || writing) && process::resolve_page_fault(cr2, writing) {
         return;
     }
 
@@ -227,11 +267,21 @@ pub extern "C" fn exception(num: u8, regs: &Regs) {
             } else {
                 "page not present"
             },
-            if (regs.error & 2) != 0 { "writing" } else { "reading" },
+            if (regs.error & 0x10) != 0 {
+                "executing"
+            } else if (regs.error & 2) != 0 {
+                "writing"
+            } else {
+                "reading"
+            },
             cr2,
             if (regs.error & 4) != 0 { "user" } else { "kernel" }
         );
 
+        if process::is_guard_page(cr2) {
+            log!("stack overflow: {:p} is in the guard page below a stack", cr2);
+        }
+
         log!("cr3 = {:x}", cpu::read_cr3());
         mmu::print_mapping(cr2);
         log!("");
@@ -243,10 +293,26 @@ pub extern "C" fn exception(num: u8, regs: &Regs) {
 
 #[no_mangle]
 pub extern "C" fn interrupt(num: u8, regs: &Regs) {
-    if num < 32 {
-        exception(num, regs)
-    } else {
-        log!("interrupt {}", num)
+    match num {
+        0..=31 => exception(num, regs),
+
+        apic::TIMER_VECTOR => {
+            thread::local_tick();
+            apic::eoi();
+            thread::preempt();
+
+            if regs.cs & 3 == 3 {
+                thread::exit_if_killed();
+            }
+        }
+
+        apic::FLUSH_TLB_VECTOR => {
+            smp::handle_flush_tlb();
+            apic::eoi();
+        }
+
+        apic::SPURIOUS_VECTOR => (),
+        _ => log!("interrupt {}", num),
     }
 }
 
@@ -1,11 +1,15 @@
 use crate::arch::cpu;
+use crate::arch::smp;
 use crate::phys_mem::{self, PhysicalBitmap};
 use crate::ptr::Align;
 use crate::spin::Mutex;
 use alloc::sync::Arc;
 use core::fmt::{Debug, Error, Formatter};
+use core::intrinsics;
 use core::marker::PhantomData;
 use core::result;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use syscall::Result;
 
 bitflags! {
@@ -19,6 +23,7 @@ bitflags! {
         const PAGE_DIRTY = 0x040; // PTE only
         const PAGE_BIG = 0x080; // PDE only
         const PAGE_GLOBAL = 0x100; // PTE only
+        const PAGE_NO_EXECUTE = 1 << 63;
     }
 }
 
@@ -70,7 +75,7 @@ impl<T> PageEntry<T> {
         Ok(())
     }
 
-    pub fn map(&mut self, addr: Option<usize>, user: bool, writable: bool) {
+    pub fn map(&mut self, addr: Option<usize>, user: bool, writable: bool, executable: bool) {
         let mut flags = PageFlags::empty();
         if addr.is_some() {
             flags.insert(PageFlags::PAGE_PRESENT);
@@ -84,6 +89,10 @@ impl<T> PageEntry<T> {
             flags.insert(PageFlags::PAGE_WRITABLE);
         }
 
+        if !executable {
+            flags.insert(PageFlags::PAGE_NO_EXECUTE);
+        }
+
         self.entry = if let Some(addr) = addr {
             assert!(Align::is_aligned(addr, phys_mem::PAGE_SIZE));
             join(addr, flags | PageFlags::PAGE_PRESENT)
@@ -107,6 +116,7 @@ impl<T> Debug for PageEntry<T> {
         write!(fmt, "{{ addr = {:-16x}, flags = ", addr)?;
 
         static ALL_FLAGS: &'static [(&'static str, PageFlags)] = &[
+            ("N", PageFlags::PAGE_NO_EXECUTE),
             ("G", PageFlags::PAGE_GLOBAL),
             ("B", PageFlags::PAGE_BIG),
             ("D", PageFlags::PAGE_DIRTY),
@@ -132,7 +142,9 @@ impl<T> Debug for PageEntry<T> {
 ///
 /// +-- kernel
 /// |
-/// V  0xFFFFF800_00000000 (8,192GB) blank
+/// V  0xFFFFF800_00000000 (7,168GB) blank
+///    0xFFFFFE00_00000000   (512GB) kernel heap, from map_heap_page
+///    0xFFFFFE80_00000000   (512GB) device memory, from map_mmio
 ///    0xFFFFFF00_00000000   (512GB) recursive page tables
 ///    0xFFFFFF80_00000000   (512GB) init_pdpt
 ///    0xFFFFFFFF_80000000     (2GB)   -> identity mapped
@@ -141,8 +153,14 @@ unsafe fn usize2ref<T>(ptr: usize) -> &'static mut T {
     &mut *(ptr as *mut T)
 }
 
+const MMU_HEAP_SLOT: usize = 508;
+const MMU_MMIO_SLOT: usize = 509;
 const MMU_RECURSIVE_SLOT: usize = 510;
 
+pub const KADDR_HEAP: usize = 0xFFFF000000000000 + (MMU_HEAP_SLOT << 39);
+pub const HEAP_MAX_LEN: usize = 1 << 39;
+const KADDR_MMIO: usize = 0xFFFF000000000000 + (MMU_MMIO_SLOT << 39);
+
 const KADDR_MMU_PT: usize = 0xFFFF000000000000 + (MMU_RECURSIVE_SLOT << 39);
 const KADDR_MMU_PD: usize = KADDR_MMU_PT + (MMU_RECURSIVE_SLOT << 30);
 const KADDR_MMU_PDPT: usize = KADDR_MMU_PD + (MMU_RECURSIVE_SLOT << 21);
@@ -212,6 +230,103 @@ pub fn print_mapping<T>(ptr: *const T) {
 extern "C" {
     static KERNEL_BASE: u8;
     static init_pml4: u8;
+    static low_pdpt: u8;
+    static mut heap_pdpt: PDPT;
+    static mmio_pdpt: u8;
+    static mut mmio_pt: PT;
+}
+
+/// Maps device memory, such as the local APIC's registers, at an address that's the same in every address space.
+/// The mapping is never removed.
+pub unsafe fn map_mmio(addr: usize, len: usize) -> &'static mut [u8] {
+    static NEXT_PAGE: AtomicUsize = AtomicUsize::new(0);
+
+    let offset = addr % phys_mem::PAGE_SIZE;
+    let page_count = (offset + len + phys_mem::PAGE_SIZE - 1) / phys_mem::PAGE_SIZE;
+    let first_page = NEXT_PAGE.fetch_add(page_count, Ordering::SeqCst);
+    assert!(
+        first_page + page_count <= mmio_pt.len(),
+        "out of space for device memory"
+    );
+
+    for (i, pt_entry) in mmio_pt[first_page..first_page + page_count].iter_mut().enumerate() {
+        let flags = PageFlags::PAGE_PRESENT
+            | PageFlags::PAGE_WRITABLE
+            | PageFlags::PAGE_WRITETHROUGH
+            | PageFlags::PAGE_NOCACHE
+            | PageFlags::PAGE_NO_EXECUTE;
+
+        pt_entry.entry = join(addr - offset + i * phys_mem::PAGE_SIZE, flags);
+    }
+
+    let ptr = (KADDR_MMIO + first_page * phys_mem::PAGE_SIZE + offset) as *mut u8;
+    slice::from_raw_parts_mut(ptr, len)
+}
+
+unsafe fn ensure_heap_table<T>(entry: &mut PageEntry<T>, alloc_table: &mut dyn FnMut() -> Option<usize>) -> bool {
+    if !entry.present() {
+        let addr = match alloc_table() {
+            Some(addr) => addr,
+            None => return false,
+        };
+
+        intrinsics::write_bytes(phys_mem::phys2virt::<u8>(addr), 0, phys_mem::PAGE_SIZE);
+        entry.entry = join(addr, PageFlags::PAGE_PRESENT | PageFlags::PAGE_WRITABLE);
+    }
+
+    true
+}
+
+/// Maps page number `index` of the kernel heap, which is the same in every address space. `alloc_table` provides
+/// pages for any page tables that are needed along the way; they stay mapped after the heap shrinks.
+pub unsafe fn map_heap_page(index: usize, addr: usize, alloc_table: &mut dyn FnMut() -> Option<usize>) -> bool {
+    let ptr = (KADDR_HEAP + index * phys_mem::PAGE_SIZE) as *const u8;
+    let pdpt_entry = &mut heap_pdpt[pdpt_index(ptr)];
+    if !ensure_heap_table(pdpt_entry, alloc_table) {
+        return false;
+    }
+
+    let pd_entry = &mut pdpt_entry.as_mut_ref()[pd_index(ptr)];
+    if !ensure_heap_table(pd_entry, alloc_table) {
+        return false;
+    }
+
+    let pt_entry = &mut pd_entry.as_mut_ref()[pt_index(ptr)];
+    pt_entry.map(Some(addr), false, true, false);
+    true
+}
+
+/// Removes page number `index` from the kernel heap and returns the physical address it was mapped to.
+pub unsafe fn unmap_heap_page(index: usize) -> usize {
+    let ptr = (KADDR_HEAP + index * phys_mem::PAGE_SIZE) as *const u8;
+    let pd_entry = &heap_pdpt[pdpt_index(ptr)].as_mut_ref()[pd_index(ptr)];
+    let pt_entry = &mut pd_entry.as_mut_ref()[pt_index(ptr)];
+    let addr = pt_entry.addr();
+    pt_entry.map(None, false, false, false);
+    cpu::invlpg(ptr);
+    smp::flush_tlb_others();
+    addr
+}
+
+/// Adds or removes an identity mapping of the bottom of physical memory in the page tables the kernel booted with.
+/// Application processors need this mapping while they turn on paging.
+pub unsafe fn set_low_mapping(enabled: bool) {
+    let pml4 = &mut *(&init_pml4 as *const u8 as *mut PML4);
+    pml4[0].entry = if enabled {
+        join(
+            phys_mem::virt2phys(&low_pdpt),
+            PageFlags::PAGE_PRESENT | PageFlags::PAGE_WRITABLE,
+        )
+    } else {
+        0
+    };
+
+    cpu::write_cr3(cpu::read_cr3());
+}
+
+/// Switches to the page tables the kernel booted with, which don't belong to any process.
+pub unsafe fn switch_to_init() {
+    cpu::write_cr3(phys_mem::virt2phys(&init_pml4));
 }
 
 pub struct AddressSpace {
@@ -227,7 +342,7 @@ impl AddressSpace {
 
         unsafe {
             let pml4: &mut PML4 = &mut phys_mem::phys2virt(pml4_addr);
-            pml4[MMU_RECURSIVE_SLOT].map(Some(pml4_addr), false, true);
+            pml4[MMU_RECURSIVE_SLOT].map(Some(pml4_addr), false, true, false);
 
             let kernel_base_ptr = Align::down(&KERNEL_BASE as *const u8, phys_mem::PAGE_SIZE);
             for addr in (0..bitmap.total_bytes()).step_by(two_meg) {
@@ -248,6 +363,16 @@ impl AddressSpace {
                     PageFlags::PAGE_PRESENT | PageFlags::PAGE_WRITABLE | PageFlags::PAGE_BIG,
                 );
             }
+
+            pml4[MMU_HEAP_SLOT].entry = join(
+                phys_mem::virt2phys(&heap_pdpt),
+                PageFlags::PAGE_PRESENT | PageFlags::PAGE_WRITABLE,
+            );
+
+            pml4[MMU_MMIO_SLOT].entry = join(
+                phys_mem::virt2phys(&mmio_pdpt),
+                PageFlags::PAGE_PRESENT | PageFlags::PAGE_WRITABLE,
+            );
         }
 
         Ok(Self {
@@ -264,7 +389,14 @@ impl AddressSpace {
         }
     }
 
-    pub unsafe fn map<T>(&self, ptr: *const T, addr: Option<usize>, user: bool, writable: bool) -> Result<()> {
+    pub unsafe fn map<T>(
+        &self,
+        ptr: *const T,
+        addr: Option<usize>,
+        user: bool,
+        writable: bool,
+        executable: bool,
+    ) -> Result<()> {
         let _x = lock!(self.mutex);
         let pml4_entry = pml4_entry(ptr);
         let pdpt_entry = pdpt_entry(ptr);
@@ -274,20 +406,132 @@ impl AddressSpace {
         pdpt_entry.ensure_present(&self.bitmap)?;
         assert!(!pd_entry.big());
         pd_entry.ensure_present(&self.bitmap)?;
-        pt_entry.map(addr, user, writable);
+        pt_entry.map(addr, user, writable, executable);
         cpu::invlpg(ptr);
         Ok(())
     }
+
+    /// Changes the permissions of a page, if it's mapped, bringing back a page that `hide` took away. Pages that
+    /// aren't mapped yet are left alone.
+    pub unsafe fn protect<T>(&self, ptr: *const T, user: bool, writable: bool, executable: bool) {
+        let _x = lock!(self.mutex);
+        if !pml4_entry(ptr).present() || !pdpt_entry(ptr).present() || !pd_entry(ptr).present() {
+            return;
+        }
+
+        assert!(!pd_entry(ptr).big());
+
+        let pt_entry = pt_entry(ptr);
+        if pt_entry.present() || pt_entry.addr() != 0 {
+            let addr = pt_entry.addr();
+            pt_entry.map(Some(addr), user, writable, executable);
+            cpu::invlpg(ptr);
+            smp::flush_tlb_others();
+        }
+    }
+
+    /// Makes a mapped page fault when anything touches it, the kernel included, while keeping hold of the physical
+    /// page so that `protect` can bring it back. Unlike `protect`, this works whether or not the address space is the
+    /// current one.
+    pub unsafe fn hide<T>(&self, ptr: *const T) {
+        let _x = lock!(self.mutex);
+        if let Some(pt_entry) = self.find_pt_entry(ptr) {
+            let (addr, flags) = pt_entry.entry();
+            pt_entry.entry = join(addr, flags - PageFlags::PAGE_PRESENT);
+            if cpu::read_cr3() == self.cr3 {
+                cpu::invlpg(ptr);
+            }
+
+            smp::flush_tlb_others();
+        }
+    }
+
+    /// Finds the page table entry for a page, including one that's been hidden, whether or not the address space is
+    /// the current one.
+    unsafe fn find_pt_entry<T>(&self, ptr: *const T) -> Option<&'static mut PageEntry<*mut u8>> {
+        let pml4: &mut PML4 = phys_mem::phys2virt(self.cr3);
+        let pml4_entry = &pml4[pml4_index(ptr)];
+        if !pml4_entry.present() {
+            return None;
+        }
+
+        let pdpt_entry = &pml4_entry.as_mut_ref()[pdpt_index(ptr)];
+        if !pdpt_entry.present() {
+            return None;
+        }
+
+        let pd_entry = &pdpt_entry.as_mut_ref()[pd_index(ptr)];
+        if !pd_entry.present() || pd_entry.big() {
+            return None;
+        }
+
+        let pt_entry = &mut pd_entry.as_mut_ref()[pt_index(ptr)];
+        if !pt_entry.present() && pt_entry.addr() == 0 {
+            return None;
+        }
+
+        Some(pt_entry)
+    }
+
+    /// Returns the physical address that a page is mapped to, if it's mapped.
+    pub unsafe fn translate<T>(&self, ptr: *const T) -> Option<usize> {
+        let _x = lock!(self.mutex);
+        self.find_pt_entry(ptr).map(|pt_entry| pt_entry.addr())
+    }
+
+    /// Removes the mapping for a page and returns the physical address it was mapped to, if any. Unlike `map`, this
+    /// works whether or not the address space is the current one.
+    pub unsafe fn unmap<T>(&self, ptr: *const T) -> Option<usize> {
+        let _x = lock!(self.mutex);
+        let pt_entry = self.find_pt_entry(ptr)?;
+        let addr = pt_entry.addr();
+        pt_entry.map(None, false, false, false);
+        if cpu::read_cr3() == self.cr3 {
+            cpu::invlpg(ptr);
+        }
+
+        // Other CPUs could be running threads in this address space
+        smp::flush_tlb_others();
+
+        Some(addr)
+    }
 }
 
 impl Drop for AddressSpace {
     fn drop(&mut self) {
         if cpu::read_cr3() == self.cr3 {
-            let cr3 = phys_mem::virt2phys(unsafe { &init_pml4 });
-            unsafe { cpu::write_cr3(cr3) };
+            unsafe { switch_to_init() };
+        }
+
+        // Free the page tables. The pages they point to belong to whoever mapped them.
+        let pml4: &mut PML4 = unsafe { phys_mem::phys2virt(self.cr3) };
+        for (pml4_index, pml4_entry) in pml4.iter().enumerate() {
+            if pml4_index == MMU_HEAP_SLOT
+                || pml4_index == MMU_MMIO_SLOT
+                || pml4_index == MMU_RECURSIVE_SLOT
+                || !pml4_entry.present()
+            {
+                continue;
+            }
+
+            for pdpt_entry in unsafe { pml4_entry.as_mut_ref() }.iter() {
+                if !pdpt_entry.present() || pdpt_entry.big() {
+                    continue;
+                }
+
+                for pd_entry in unsafe { pdpt_entry.as_mut_ref() }.iter() {
+                    if pd_entry.present() && !pd_entry.big() {
+                        self.bitmap.free_page(pd_entry.addr());
+                    }
+                }
+
+                self.bitmap.free_page(pdpt_entry.addr());
+            }
+
+            self.bitmap.free_page(pml4_entry.addr());
         }
 
-        // TODO free memory
+        self.bitmap.free_page(self.cr3);
     }
 }
 
@@ -296,7 +540,6 @@ pub mod test {
     use super::*;
     use crate::phys_mem::{self, PhysicalBitmap};
     use crate::ptr::Align;
-    use alloc::sync::Arc;
     use core::intrinsics;
     use core::mem;
 
@@ -320,19 +563,19 @@ pub mod test {
         }
 
         fn can_switch() {
-            let bitmap = Arc::new(PhysicalBitmap::machine());
+            let bitmap = PhysicalBitmap::machine();
             let address_space = AddressSpace::new(bitmap).unwrap();
             unsafe { address_space.switch() };
         }
 
         fn can_map_user() {
-            let bitmap = Arc::new(PhysicalBitmap::machine());
+            let bitmap = PhysicalBitmap::machine();
             let ptr1 = 0x1000 as *mut u16;
             let addr = bitmap.alloc_page().unwrap();
             let address_space = AddressSpace::new(bitmap).unwrap();
             unsafe {
                 address_space.switch();
-                address_space.map(ptr1, Some(addr), false, true).unwrap();
+                address_space.map(ptr1, Some(addr), false, true, false).unwrap();
 
                 let ptr2 = phys_mem::phys2virt(addr);
                 let sentinel = 0x55aa;
@@ -341,8 +584,112 @@ pub mod test {
             }
         }
 
+        fn can_protect_page() {
+            let bitmap = PhysicalBitmap::machine();
+            let ptr = 0x1000 as *mut u8;
+            let addr = bitmap.alloc_page().unwrap();
+            let address_space = AddressSpace::new(bitmap).unwrap();
+            unsafe {
+                address_space.switch();
+                address_space.map(ptr, Some(addr), true, true, false).unwrap();
+                assert_eq!(
+                    PageFlags::PAGE_PRESENT | PageFlags::PAGE_USER | PageFlags::PAGE_WRITABLE | PageFlags::PAGE_NO_EXECUTE,
+                    pt_entry(ptr).flags()
+                );
+
+                address_space.protect(ptr, true, false, true);
+                assert_eq!(addr, pt_entry(ptr).addr());
+                assert_eq!(PageFlags::PAGE_PRESENT | PageFlags::PAGE_USER, pt_entry(ptr).flags());
+            }
+        }
+
+        fn can_hide_page() {
+            let bitmap = PhysicalBitmap::machine();
+            let ptr = 0x1000 as *mut u8;
+            let addr = bitmap.alloc_page().unwrap();
+            let address_space = AddressSpace::new(bitmap.clone()).unwrap();
+            unsafe {
+                address_space.switch();
+                address_space.map(ptr, Some(addr), true, true, false).unwrap();
+                intrinsics::volatile_store(ptr, 0x55);
+
+                address_space.hide(ptr);
+                assert!(!pt_entry(ptr).present());
+                assert_eq!(Some(addr), address_space.translate(ptr));
+
+                address_space.protect(ptr, true, false, false);
+                assert!(pt_entry(ptr).present());
+                assert_eq!(0x55, intrinsics::volatile_load(ptr));
+
+                address_space.hide(ptr);
+                assert_eq!(Some(addr), address_space.unmap(ptr));
+                assert_eq!(None, address_space.translate(ptr));
+            }
+
+            bitmap.free_page(addr);
+        }
+
+        fn can_unmap_and_free_page_tables() {
+            let bitmap = PhysicalBitmap::machine();
+            let free_bytes = bitmap.free_bytes() + phys_mem::heap_bytes();
+            let ptr = 0x1000 as *mut u8;
+            let addr = bitmap.alloc_page().unwrap();
+            {
+                let address_space = AddressSpace::new(bitmap.clone()).unwrap();
+                unsafe {
+                    address_space.switch();
+                    address_space.map(ptr, Some(addr), true, true, false).unwrap();
+                    assert_eq!(Some(addr), address_space.translate(ptr));
+                    assert_eq!(Some(addr), address_space.unmap(ptr));
+                    assert_eq!(None, address_space.translate(ptr));
+                    assert_eq!(None, address_space.unmap(ptr));
+                }
+            }
+
+            bitmap.free_page(addr);
+            assert_eq!(free_bytes, bitmap.free_bytes() + phys_mem::heap_bytes());
+        }
+
+        fn can_map_mmio() {
+            let bitmap = PhysicalBitmap::machine();
+            let addr = bitmap.alloc_page().unwrap();
+            let mmio = unsafe { map_mmio(addr + 4, 4) }.as_mut_ptr() as *mut u32;
+            let sentinel = 0x55aa_1234;
+            {
+                let address_space = AddressSpace::new(bitmap.clone()).unwrap();
+                unsafe {
+                    address_space.switch();
+
+                    let ptr: *mut u32 = phys_mem::phys2virt(addr + 4);
+                    intrinsics::volatile_store(ptr, sentinel);
+                    assert_eq!(sentinel, intrinsics::volatile_load(mmio));
+
+                    // Device memory is mapped the same way in every address space, including the boot one
+                    switch_to_init();
+                    assert_eq!(sentinel, intrinsics::volatile_load(mmio));
+                }
+            }
+
+            bitmap.free_page(addr);
+        }
+
+        fn kernel_heap_grows_past_boot_region_and_shrinks() {
+            // Bigger than the region the linker sets aside for the heap
+            let len = 4 * 1024 * 1024;
+            let before = phys_mem::heap_bytes();
+            let during = {
+                let v = vec![1u8; len];
+                assert_eq!(KADDR_HEAP, Align::down(v.as_ptr() as usize, HEAP_MAX_LEN));
+                assert_eq!(1, unsafe { intrinsics::volatile_load(&v[len - 1]) });
+                phys_mem::heap_bytes()
+            };
+
+            assert!(during > before);
+            assert!(phys_mem::heap_bytes() < during);
+        }
+
         fn can_map_kernel() {
-            let bitmap = Arc::new(PhysicalBitmap::machine());
+            let bitmap = PhysicalBitmap::machine();
             let two_meg = 2 * 1024 * 1024;
 
             let ptr1: *mut u16 =
@@ -356,7 +703,7 @@ pub mod test {
             let address_space = AddressSpace::new(bitmap).unwrap();
             unsafe {
                 address_space.switch();
-                address_space.map(ptr1, Some(addr), false, true).unwrap();
+                address_space.map(ptr1, Some(addr), false, true, false).unwrap();
 
                 let ptr2 = phys_mem::phys2virt(addr);
                 let sentinel = 0x55aa;
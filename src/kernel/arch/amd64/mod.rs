@@ -12,10 +12,13 @@
 
 pub use crate::arch::x86_common::*;
 
+pub mod acpi;
+pub mod apic;
 pub mod cpu;
 pub mod isr;
 pub mod mmu;
 pub mod process;
+pub mod smp;
 pub mod thread;
 
 #[inline]
@@ -32,6 +35,21 @@ pub fn restore_interrupts(token: usize) {
     }
 }
 
+/// Called in a loop while waiting for a lock. Whoever holds the lock could be waiting for this CPU to flush its TLB.
+#[inline]
+pub fn spin_wait() {
+    smp::handle_flush_tlb();
+    core::sync::atomic::spin_loop_hint();
+}
+
+/// Returns the index of the CPU that's running this code, from 0 (the boot processor) to `smp::cpu_count() - 1`.
+#[inline]
+pub fn cpu_index() -> usize {
+    let index: usize;
+    unsafe { asm!("mov %gs:8, $0" : "=r"(index)) }; // PerCpu::index
+    index
+}
+
 #[allow(unused_attributes)]
 #[link_args = "-T arch/amd64/link.ld"]
 #[link_args = "-L arch/amd64"]
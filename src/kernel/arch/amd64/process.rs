@@ -3,6 +3,9 @@ use crate::phys_mem::PhysicalBitmap;
 use alloc::sync::Arc;
 use syscall::Result;
 
+/// User-mode addresses are all below this, in the lower half of the address space.
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
 pub struct ArchProcess {
     address_space: AddressSpace,
 }
@@ -18,7 +21,30 @@ impl ArchProcess {
         self.address_space.switch()
     }
 
-    pub unsafe fn map<T>(&self, ptr: *const T, addr: Option<usize>, user: bool, writable: bool) -> Result<()> {
-        self.address_space.map(ptr, addr, user, writable)
+    pub unsafe fn map<T>(
+        &self,
+        ptr: *const T,
+        addr: Option<usize>,
+        user: bool,
+        writable: bool,
+        executable: bool,
+    ) -> Result<()> {
+        self.address_space.map(ptr, addr, user, writable, executable)
+    }
+
+    pub unsafe fn protect<T>(&self, ptr: *const T, user: bool, writable: bool, executable: bool) {
+        self.address_space.protect(ptr, user, writable, executable)
+    }
+
+    pub unsafe fn hide<T>(&self, ptr: *const T) {
+        self.address_space.hide(ptr)
+    }
+
+    pub unsafe fn translate<T>(&self, ptr: *const T) -> Option<usize> {
+        self.address_space.translate(ptr)
+    }
+
+    pub unsafe fn unmap<T>(&self, ptr: *const T) -> Option<usize> {
+        self.address_space.unmap(ptr)
     }
 }
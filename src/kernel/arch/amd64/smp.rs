@@ -0,0 +1,232 @@
+//! Starts the other processors in the machine, and keeps the state that each processor has its own copy of.
+
+use crate::arch;
+use crate::arch::acpi::{self, Madt};
+use crate::arch::apic::{self, IoApic};
+use crate::arch::cpu;
+use crate::arch::isr;
+use crate::arch::mmu;
+use crate::arch::pit;
+use crate::arch::thread as arch_thread;
+use crate::phys_mem;
+use crate::prelude::*;
+use crate::ptr;
+use crate::thread;
+use alloc::alloc::{self as alloc_mod, Layout};
+use core::intrinsics;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Must match MAX_CPUS in start.S.
+pub const MAX_CPUS: usize = 16;
+
+/// Physical address that application processors start running at. Must match AP_TRAMPOLINE in start.S.
+const TRAMPOLINE_ADDR: usize = 0x8000;
+
+const AP_STACK_SIZE: usize = 4096 * 8;
+
+/// Number of ticks to wait for a processor to start before giving up on it.
+const AP_START_TIMEOUT: usize = 100;
+
+extern "C" {
+    static ap_trampoline: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Each CPU's GS base points at its own `PerCpu`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PerCpu {
+    /// Top of the kernel stack of the thread running on this CPU. `syscall_entry_asm` reads it through `%gs:0`.
+    pub kernel_rsp: u64,
+    /// Read by `arch::cpu_index` through `%gs:8`.
+    pub index: usize,
+    pub apic_id: u8,
+}
+
+static mut CPUS: [PerCpu; MAX_CPUS] = [PerCpu {
+    kernel_rsp: 0,
+    index: 0,
+    apic_id: 0,
+}; MAX_CPUS];
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Local APIC timer count that makes one tick on the application processors.
+static TIMER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// One bit for each CPU that has been asked to flush its TLB and hasn't done it yet.
+static FLUSH_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+pub fn this_cpu() -> &'static mut PerCpu {
+    unsafe { &mut CPUS[arch::cpu_index()] }
+}
+
+/// Sets up the state that each CPU has its own copy of. Nothing can allocate memory on this CPU until it's done,
+/// since the heap lock needs to know which CPU it's on.
+pub unsafe fn init_cpu(index: usize) {
+    CPUS[index].index = index;
+    cpu::wrmsr(cpu::IA32_GSBASE, &CPUS[index] as *const PerCpu as u64);
+    cpu::wrmsr(cpu::IA32_KERNEL_GSBASE, 0);
+    isr::init_cpu(index);
+    arch_thread::init_cpu();
+}
+
+/// Finds the other processors through the ACPI tables and starts them, and moves device interrupts over from the PIC
+/// to the I/O APIC. Without an MADT, the kernel carries on with one processor and the PIC.
+pub fn init() {
+    let madt = match acpi::find_madt() {
+        Some(madt) => madt,
+        None => {
+            log!("no MADT; only using one CPU");
+            return;
+        }
+    };
+
+    unsafe {
+        apic::map_local(madt.local_apic_addr);
+        apic::enable_local();
+
+        let bsp_id = apic::local_id();
+        CPUS[0].apic_id = bsp_id;
+
+        apic::start_timer_countdown();
+        pit::busy_wait_tick();
+        TIMER_COUNT.store(apic::stop_timer_countdown() as usize, Ordering::SeqCst);
+
+        route_isa_irqs(&madt, bsp_id);
+        start_aps(&madt, bsp_id);
+    }
+
+    log!("using {} CPUs", cpu_count());
+}
+
+/// Sends the IRQs that used to go through the PIC to the boot processor instead, on the same vectors as before.
+unsafe fn route_isa_irqs(madt: &Madt, apic_id: u8) {
+    let io_apics = madt
+        .io_apics
+        .iter()
+        .map(|io_apic| IoApic::new(io_apic.addr, io_apic.gsi_base))
+        .collect::<Vec<_>>();
+
+    if io_apics.is_empty() {
+        return;
+    }
+
+    let token = arch::disable_interrupts();
+    for irq in 0..16 {
+        if let Some(route) = madt.isa_irq(irq) {
+            if let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(route.gsi)) {
+                io_apic.route(&route, isr::IRQ_BASE + irq, apic_id);
+            }
+        }
+    }
+
+    isr::use_apic();
+    arch::restore_interrupts(token);
+}
+
+/// Starts each application processor in turn with INIT and STARTUP interrupts, following the MultiProcessor
+/// Specification.
+unsafe fn start_aps(madt: &Madt, bsp_id: u8) {
+    let trampoline_len = ptr::bytes_between(&ap_trampoline, &ap_trampoline_end);
+    let trampoline_dest: &mut u8 = phys_mem::phys2virt(TRAMPOLINE_ADDR);
+    intrinsics::copy_nonoverlapping(&ap_trampoline, trampoline_dest, trampoline_len);
+
+    let stack_offset = ptr::bytes_between(&ap_trampoline, &ap_trampoline_stack);
+    let stack_slot: &mut u64 = phys_mem::phys2virt(TRAMPOLINE_ADDR + stack_offset);
+    let stack_layout = Layout::from_size_align_unchecked(AP_STACK_SIZE, 16);
+
+    mmu::set_low_mapping(true);
+
+    for &apic_id in madt.apic_ids.iter().filter(|&&apic_id| apic_id != bsp_id) {
+        let index = cpu_count();
+        if index >= MAX_CPUS {
+            log!("only using the first {} CPUs", MAX_CPUS);
+            break;
+        }
+
+        let stack = alloc_mod::alloc_zeroed(stack_layout);
+        *stack_slot = stack.add(AP_STACK_SIZE) as u64;
+        CPUS[index].apic_id = apic_id;
+        AP_STARTED.store(false, Ordering::SeqCst);
+
+        apic::send_init(apic_id);
+        pit::busy_wait_tick();
+
+        // Send STARTUP twice, since the first one can get lost
+        for _ in 0..2 {
+            if AP_STARTED.load(Ordering::SeqCst) {
+                break;
+            }
+
+            apic::send_startup(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+            pit::busy_wait_tick();
+        }
+
+        for _ in 0..AP_START_TIMEOUT {
+            if AP_STARTED.load(Ordering::SeqCst) {
+                break;
+            }
+
+            pit::busy_wait_tick();
+        }
+
+        if AP_STARTED.load(Ordering::SeqCst) {
+            CPU_COUNT.store(index + 1, Ordering::SeqCst);
+        } else {
+            log!("CPU with APIC id {} didn't start", apic_id);
+            alloc_mod::dealloc(stack, stack_layout);
+        }
+    }
+
+    mmu::set_low_mapping(false);
+}
+
+/// Where application processors go after `ap_start64_high` in start.S.
+#[no_mangle]
+pub extern "C" fn ap_main() -> ! {
+    // The boot processor doesn't count this CPU until it's started
+    let index = cpu_count();
+
+    unsafe {
+        init_cpu(index);
+        apic::enable_local();
+        AP_STARTED.store(true, Ordering::SeqCst);
+        apic::start_periodic_timer(TIMER_COUNT.load(Ordering::SeqCst) as u32);
+        cpu::sti();
+    }
+
+    thread::run_ap(index)
+}
+
+/// Makes the other CPUs forget any page table entries they have cached, and waits until they've done so.
+pub fn flush_tlb_others() {
+    let count = cpu_count();
+    if count == 1 {
+        return;
+    }
+
+    let others = ((1 << count) - 1) & !(1 << arch::cpu_index());
+    FLUSH_PENDING.fetch_or(others, Ordering::SeqCst);
+    unsafe { apic::send_to_others(apic::FLUSH_TLB_VECTOR) };
+
+    // Another CPU could be waiting for this one to flush, with interrupts disabled, just as this one might be
+    while FLUSH_PENDING.load(Ordering::SeqCst) & others != 0 {
+        arch::spin_wait();
+    }
+}
+
+/// Flushes the current CPU's TLB if another CPU has asked it to.
+pub fn handle_flush_tlb() {
+    let bit = 1 << arch::cpu_index();
+    if FLUSH_PENDING.load(Ordering::SeqCst) & bit != 0 {
+        unsafe { cpu::write_cr3(cpu::read_cr3()) };
+        FLUSH_PENDING.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
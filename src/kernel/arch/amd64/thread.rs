@@ -1,8 +1,8 @@
 use crate::arch::cpu::{self, Regs};
 use crate::ksyscall;
-use crate::once::{self, Once};
 use crate::prelude::*;
 use crate::ptr::Align;
+use crate::thread;
 use core::mem;
 use libc::jmp_buf;
 use syscall::PackedArgs;
@@ -22,7 +22,9 @@ pub unsafe fn syscall_entry(regs: &Regs) -> isize {
         regs.r9 as usize,
         regs.r10 as usize,
     );
-    ksyscall::dispatch(regs.rax as usize, args)
+    let result = ksyscall::dispatch(regs.rax as usize, args);
+    thread::exit_if_killed();
+    result
 }
 
 #[no_mangle]
@@ -57,20 +59,19 @@ pub fn new_jmp_buf<'a>(p: Box<dyn FnOnce() + 'a>, stack_ptr: *mut u8) -> jmp_buf
     }
 }
 
-pub unsafe fn jmp_user_mode(rip: *const u8, rsp: *mut u8, rdi: usize) -> ! {
+pub unsafe fn jmp_user_mode(rip: *const u8, rsp: *mut u8, rdi: usize, rsi: usize) -> ! {
     assert!(Align::is_aligned(rsp, 16));
     let rsp = (rsp as *mut usize).offset(-1); // fake return address
     *rsp = 0;
+    cpu::sysret(rip, rsp, rdi, rsi, 1 << 9)
+}
 
-    static INIT: Once = once::ONCE_INIT;
-    INIT.call_once(|| {
-        const KERNEL_CS: u16 = 0x08; // KERNEL_SS = 0x10 (+8)
-        const USER_CS_32: u16 = 0x23; // USER_SS = 0x2B (+8); USER_CS_64 = 0x33 (+16)
-        const RFLAGS_IF: u64 = 1 << 9;
-        cpu::wrmsr(cpu::IA32_STAR, (USER_CS_32 as u64) << 48 | (KERNEL_CS as u64) << 32);
-        cpu::wrmsr(cpu::IA32_LSTAR, &syscall_entry_asm as *const u8 as u64);
-        cpu::wrmsr(cpu::IA32_SFMASK, RFLAGS_IF);
-    });
-
-    cpu::sysret(rip, rsp, rdi, 1 << 9)
+/// Points the current CPU's `syscall` instruction at `syscall_entry_asm`.
+pub unsafe fn init_cpu() {
+    const KERNEL_CS: u16 = 0x08; // KERNEL_SS = 0x10 (+8)
+    const USER_CS_32: u16 = 0x23; // USER_SS = 0x2B (+8); USER_CS_64 = 0x33 (+16)
+    const RFLAGS_IF: u64 = 1 << 9;
+    cpu::wrmsr(cpu::IA32_STAR, (USER_CS_32 as u64) << 48 | (KERNEL_CS as u64) << 32);
+    cpu::wrmsr(cpu::IA32_LSTAR, &syscall_entry_asm as *const u8 as u64);
+    cpu::wrmsr(cpu::IA32_SFMASK, RFLAGS_IF);
 }
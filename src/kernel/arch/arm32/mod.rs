@@ -10,6 +10,14 @@ pub fn disable_interrupts() -> usize {
 #[inline]
 pub fn restore_interrupts(_token: usize) {}
 
+#[inline]
+pub fn spin_wait() {}
+
+#[inline]
+pub fn cpu_index() -> usize {
+    0
+}
+
 #[allow(unused_attributes)]
 #[link_args = "-T arch/arm32/link.ld"]
 #[link_args = "-L arch/arm32"]
@@ -1,10 +1,12 @@
 use crate::arch::cpu;
 use crate::arch::isr::{self, DropIrqHandler};
+use crate::deferred::Deferred;
 use crate::io::{AsyncRead, Pipe, Read, Write};
 use crate::kobj::KObj;
 use crate::spin::Mutex;
 use alloc::sync::Arc;
 use core::char;
+use syscall::POLL_READ;
 
 unsafe fn read_keyboard() -> u8 {
     loop {
@@ -310,4 +312,13 @@ impl KObj for Keyboard {
     fn read(&self) -> Option<&dyn Read> {
         Some(&*self.device)
     }
+
+    fn ready(&self) -> Option<Deferred<()>> {
+        self.device.ready()
+    }
+
+    fn poll(&self) -> Option<(u32, Deferred<()>)> {
+        let (state, changed) = self.device.poll_state();
+        Some((state & POLL_READ, changed))
+    }
 }
@@ -3,6 +3,7 @@ pub mod keyboard;
 pub mod multiboot;
 pub mod pci;
 pub mod phys_mem;
+pub mod pit;
 pub mod ps2_mouse;
 pub mod serial;
 pub mod vga;
@@ -52,6 +52,23 @@ pub fn multiboot_info() -> &'static multiboot_info_t {
     unsafe { phys_mem::phys2virt(mboot_ptr as usize) }
 }
 
+/// Returns the contents of the initial RAM disk loaded by the boot loader.
+pub fn initrd() -> &'static [u8] {
+    let info = multiboot_info();
+
+    unsafe {
+        let mods: &[multiboot_module_t] =
+            slice::from_raw_parts(phys_mem::phys2virt(info.mods_addr as usize), info.mods_count as usize);
+
+        assert_eq!(1, mods.len());
+
+        slice::from_raw_parts(
+            phys_mem::phys2virt(mods[0].mod_start as usize),
+            (mods[0].mod_end - mods[0].mod_start) as usize,
+        )
+    }
+}
+
 pub fn machine() -> PhysicalBitmap {
     parse_multiboot()
 }
@@ -72,10 +89,11 @@ pub mod test {
         }
 
         fn can_alloc_zeroed_memory() {
-            let bitmap = parse_multiboot();
+            let bitmap = PhysicalBitmap::machine();
             let addr = bitmap.alloc_zeroed_page().unwrap();
             let ptr: &[u8; PAGE_SIZE] = unsafe { phys_mem::phys2virt(addr) };
             assert!(ptr.iter().all(|&b| b == 0));
+            bitmap.free_page(addr);
         }
     }
 }
@@ -0,0 +1,40 @@
+use crate::arch::cpu;
+
+const PIT_FREQUENCY: u32 = 1193182;
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+
+/// Frequency of IRQ 0, in Hz.
+pub const TICK_HZ: u32 = 100;
+
+pub fn init() {
+    let divisor = PIT_FREQUENCY / TICK_HZ;
+    assert!(divisor > 0 && divisor <= 0xffff);
+
+    unsafe {
+        cpu::outb(PIT_COMMAND, 0x36); // channel 0, lobyte/hibyte, mode 3 (square wave), binary
+        cpu::outb(PIT_CHANNEL0, divisor as u8);
+        cpu::outb(PIT_CHANNEL0, (divisor >> 8) as u8);
+    }
+}
+
+/// Waits for one tick's worth of time by polling channel 2, so it works before interrupts are set up.
+pub fn busy_wait_tick() {
+    const PIT_CHANNEL2: u16 = 0x42;
+    const PORT_B: u16 = 0x61;
+    const GATE2: u8 = 0x01;
+    const SPEAKER: u8 = 0x02;
+    const OUT2: u8 = 0x20;
+
+    let count = PIT_FREQUENCY / TICK_HZ;
+    unsafe {
+        let port_b = cpu::inb(PORT_B) & !(GATE2 | SPEAKER);
+        cpu::outb(PORT_B, port_b);
+        cpu::outb(PIT_COMMAND, 0xb0); // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+        cpu::outb(PIT_CHANNEL2, count as u8);
+        cpu::outb(PIT_CHANNEL2, (count >> 8) as u8);
+        cpu::outb(PORT_B, port_b | GATE2);
+        while cpu::inb(PORT_B) & OUT2 == 0 {}
+        cpu::outb(PORT_B, port_b);
+    }
+}
@@ -1,10 +1,12 @@
 use crate::arch::cpu;
 use crate::arch::isr::{self, DropIrqHandler};
+use crate::deferred::Deferred;
 use crate::io::{AsyncRead, Pipe, Read, Write};
 use crate::kobj::KObj;
 use crate::spin::Mutex;
 use alloc::sync::Arc;
 use core::mem;
+use syscall::POLL_READ;
 
 unsafe fn write_keyboard(port: u16, data: u8) {
     loop {
@@ -170,4 +172,13 @@ impl KObj for Ps2Mouse {
     fn read(&self) -> Option<&dyn Read> {
         Some(&*self.device)
     }
+
+    fn ready(&self) -> Option<Deferred<()>> {
+        self.device.ready()
+    }
+
+    fn poll(&self) -> Option<(u32, Deferred<()>)> {
+        let (state, changed) = self.device.poll_state();
+        Some((state & POLL_READ, changed))
+    }
 }
@@ -1,13 +1,19 @@
 use crate::kobj::KObj;
 use crate::spin::Mutex;
 use crate::thread::{self, BlockedThread};
+use crate::time;
 use alloc::collections::vec_deque::VecDeque;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::mem;
+use syscall::ErrNum;
 
 struct DeferredState<A> {
     result: Option<A>,
+    /// Stays set once there's been a result, even after it's been taken.
+    resolved: bool,
     waiters: VecDeque<BlockedThread>,
+    listeners: Vec<(Deferred<usize>, usize)>,
 }
 
 pub struct Deferred<A> {
@@ -18,24 +24,64 @@ impl<A> Deferred<A> {
     pub fn new() -> Self {
         let dstate = Arc::new(Mutex::new(DeferredState {
             result: None,
+            resolved: false,
             waiters: VecDeque::new(),
+            listeners: Vec::new(),
         }));
 
         Deferred { state: dstate }
     }
 
+    pub fn resolved(result: A) -> Self {
+        let d = Self::new();
+        d.resolve(result);
+        d
+    }
+
     pub fn resolve(&self, result: A) {
-        let mut dstate = lock!(self.state);
-        match dstate.result {
-            Some(_) => panic!("promise is already resolved"),
-            None => {}
+        if !self.try_resolve(result) {
+            panic!("promise is already resolved");
         }
+    }
+
+    /// Like `resolve`, but returns `false` instead of panicking if the deferred has had a result already, even if
+    /// somebody has taken it since.
+    pub fn try_resolve(&self, result: A) -> bool {
+        let listeners = {
+            let mut dstate = lock!(self.state);
+            if dstate.resolved {
+                return false;
+            }
 
-        dstate.result = Some(result);
+            dstate.result = Some(result);
+            dstate.resolved = true;
 
-        let mut waiters = mem::replace(&mut dstate.waiters, VecDeque::new());
-        while let Some(thread) = waiters.pop_front() {
-            thread.resume();
+            let mut waiters = mem::replace(&mut dstate.waiters, VecDeque::new());
+            while let Some(thread) = waiters.pop_front() {
+                thread.resume();
+            }
+
+            mem::replace(&mut dstate.listeners, Vec::new())
+        };
+
+        for (any, index) in listeners {
+            any.try_resolve(index);
+        }
+
+        true
+    }
+
+    /// Resolves `any` with `index` once this deferred has a result, unless something else resolved `any` first. This
+    /// lets one thread wait for any of several deferreds.
+    pub fn notify(&self, any: &Deferred<usize>, index: usize) {
+        let mut dstate = lock!(self.state);
+        if dstate.resolved {
+            mem::drop(dstate);
+            any.try_resolve(index);
+        } else {
+            // Forget about earlier waits that have finished
+            dstate.listeners.retain(|(any, _)| !any.is_orphaned());
+            dstate.listeners.push((any.clone(), index));
         }
     }
 
@@ -57,6 +103,22 @@ impl<A> Deferred<A> {
         }
     }
 
+    /// Returns `true` if nothing apart from this `Deferred` refers to the same value, which means that nobody is
+    /// waiting for the result any more.
+    pub fn is_orphaned(&self) -> bool {
+        Arc::strong_count(&self.state) == 1
+    }
+
+    /// Returns `true` if the deferred has a result that nobody has taken yet.
+    pub fn is_resolved(&self) -> bool {
+        lock!(self.state).result.is_some()
+    }
+
+    /// Returns `true` if the deferred has had a result, whether or not somebody has taken it since.
+    pub fn has_resolved(&self) -> bool {
+        lock!(self.state).resolved
+    }
+
     pub fn try_get(self) -> Result<A, Self> {
         let opt = {
             let mut state = lock!(self.state);
@@ -67,6 +129,40 @@ impl<A> Deferred<A> {
     }
 }
 
+impl<A: 'static> Deferred<A> {
+    /// Blocks until the deferred is resolved or the tick count reaches `deadline`. Returns the deferred itself if the
+    /// deadline passed first, or if the current process was killed.
+    pub fn get_timeout(self, deadline: usize) -> Result<A, Self> {
+        loop {
+            let mut dstate = lock!(self.state);
+            match mem::replace(&mut dstate.result, None) {
+                Some(result) => {
+                    return Ok(result);
+                }
+                None => (),
+            }
+
+            if time::ticks() >= deadline {
+                mem::drop(dstate);
+                return Err(self);
+            }
+
+            let state = self.state.clone();
+            let result = thread::block_until(
+                deadline,
+                move |thread| {
+                    dstate.waiters.push_back(thread);
+                },
+                move |id| thread::remove_waiter(&mut lock!(state).waiters, id),
+            );
+
+            if result == Err(ErrNum::Interrupted) {
+                return Err(self);
+            }
+        }
+    }
+}
+
 impl<A> Clone for Deferred<A> {
     fn clone(&self) -> Self {
         Deferred {
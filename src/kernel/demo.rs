@@ -3,6 +3,8 @@ use crate::arch::keyboard::Keyboard;
 use crate::arch::ps2_mouse::Ps2Mouse;
 use crate::arch::vga::Vga;
 use crate::deferred::Deferred;
+use crate::io::{Pipe, Read};
+use crate::kobj::KObj;
 use crate::ksyscall::{self, SyscallHandler};
 use crate::process;
 use crate::thread;
@@ -26,6 +28,24 @@ impl<A> Deferred<A> {
 }
 
 test! {
+    fn processes_see_their_arguments() {
+        thread::with_scheduler(|| {
+            let handler = SyscallHandler::new(Arc::new(Ps2Mouse::new()));
+            let _x = ksyscall::register_handler(handler);
+
+            let stdin: Arc<dyn KObj> = Arc::new(Pipe::new());
+            let stdout = Arc::new(Pipe::new());
+            let args = process::Args::parse(b"hello\0arguments\0\0").unwrap();
+            let handles = vec![Some(stdin), Some(stdout.clone() as Arc<dyn KObj>)];
+            let process = process::spawn("hello".into(), args, handles).unwrap();
+            assert_eq!(0, process.exit_code().poll());
+
+            let mut buf = [0; 64];
+            let len = Read::read(&*stdout, &mut buf).unwrap();
+            assert_eq!(b"hello arguments\n", &buf[..len]);
+        });
+    }
+
     fn can_run_hello_world() {
         thread::with_scheduler(|| {
             let handler = SyscallHandler::new(Arc::new(Ps2Mouse::new()));
@@ -33,7 +53,8 @@ test! {
 
             let stdin = Arc::new(Keyboard::new());
             let stdout = Arc::new(Vga::new());
-            let process = process::spawn("graphics_server".into(), vec![Some(stdin), Some(stdout)]).unwrap();
+            let args = process::Args::parse(b"graphics_server\0").unwrap();
+            let process = process::spawn("graphics_server".into(), args, vec![Some(stdin), Some(stdout)]).unwrap();
             assert_eq!(0, process.exit_code().poll());
         });
     }
@@ -244,6 +244,9 @@ pub const PT_HISUNW: ::libc::c_uint = 1879048191;
 pub const PT_HIOS: ::libc::c_uint = 1879048191;
 pub const PT_LOPROC: ::libc::c_uint = 1879048192;
 pub const PT_HIPROC: ::libc::c_uint = 2147483647;
+pub const PF_X: ::libc::c_uint = 1;
+pub const PF_W: ::libc::c_uint = 2;
+pub const PF_R: ::libc::c_uint = 4;
 pub const PF_MASKOS: ::libc::c_uint = 267386880;
 pub const PF_MASKPROC: ::libc::c_uint = 4026531840;
 pub const NT_PRSTATUS: ::libc::c_uint = 1;
@@ -0,0 +1,143 @@
+//! Read-only file system over the tar archive loaded by the boot loader.
+
+use crate::fs::{Directory, FileSystem};
+use crate::io::{MapFile, Read, Seek, SeekFrom};
+use crate::kobj::KObj;
+use crate::phys_mem;
+use crate::prelude::*;
+use crate::ptr::PointerInSlice;
+use crate::spin::Mutex;
+use crate::tar::{self, EntryKind};
+use alloc::sync::Arc;
+use core::cmp;
+use core::str;
+use syscall::{ErrNum, FileKind, FileStat, Result};
+
+pub struct Initrd {
+    data: &'static [u8],
+}
+
+impl Initrd {
+    pub fn new(data: &'static [u8]) -> Self {
+        Initrd { data }
+    }
+}
+
+impl FileSystem for Initrd {
+    fn open(&self, path: &[&str]) -> Result<Arc<dyn KObj>> {
+        // Directories don't need their own entry in the archive: it's enough for a file to be inside one
+        let mut is_dir = path.is_empty();
+        let mut dir_mode = 0o755;
+        let mut names = Vec::<String>::new();
+
+        for entry in tar::entries(self.data) {
+            let mut components = entry.components();
+            if !path.iter().all(|c| components.next() == Some(c.as_bytes())) {
+                continue;
+            }
+
+            match (components.next(), entry.kind) {
+                (None, EntryKind::File) => return Ok(Arc::new(InitrdFile::new(entry.data, entry.mode))),
+                (None, EntryKind::Directory) => {
+                    is_dir = true;
+                    dir_mode = entry.mode;
+                }
+                (Some(name), _) => {
+                    is_dir = true;
+                    if let Ok(name) = str::from_utf8(name) {
+                        if !names.iter().any(|n| n == name) {
+                            names.push(String::from(name));
+                        }
+                    }
+                }
+                (None, EntryKind::Other) => {}
+            }
+        }
+
+        if is_dir {
+            Ok(Arc::new(Directory::new(names, dir_mode)))
+        } else {
+            Err(ErrNum::FileNotFound)
+        }
+    }
+}
+
+pub struct InitrdFile {
+    data: &'static [u8],
+    mode: u32,
+    pos: Mutex<u64>,
+}
+
+impl InitrdFile {
+    pub fn new(data: &'static [u8], mode: u32) -> Self {
+        InitrdFile {
+            data,
+            mode,
+            pos: Mutex::new(0),
+        }
+    }
+}
+
+impl Read for InitrdFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut pos = lock!(self.pos);
+        let len = self.read_at(*pos, buf)?;
+        *pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for InitrdFile {
+    fn seek(&self, pos: SeekFrom) -> Result<u64> {
+        let mut current = lock!(self.pos);
+        *current = pos.resolve(*current, self.data.len() as u64)?;
+        Ok(*current)
+    }
+}
+
+impl MapFile for InitrdFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let start = cmp::min(offset, self.data.len() as u64) as usize;
+        let len = cmp::min(buf.len(), self.data.len() - start);
+        buf[..len].copy_from_slice(&self.data[start..start + len]);
+        Ok(len)
+    }
+
+    fn phys_page(&self, offset: u64) -> Option<usize> {
+        // Files in the archive are only aligned to 512 bytes, and the last page of a file has to read as zeros past
+        // the end, so only whole pages that happen to be aligned can be mapped as they are
+        let end = offset.checked_add(phys_mem::PAGE_SIZE as u64)?;
+        if end > self.data.len() as u64 {
+            return None;
+        }
+
+        let ptr = unsafe { self.data.as_ptr().offset(offset as isize) };
+        if ptr as usize % phys_mem::PAGE_SIZE != 0 || !phys_mem::identity_range().contains_ptr(ptr) {
+            return None;
+        }
+
+        Some(phys_mem::virt2phys(ptr))
+    }
+}
+
+impl KObj for InitrdFile {
+    fn read(&self) -> Option<&dyn Read> {
+        Some(self)
+    }
+
+    fn seek(&self) -> Option<&dyn Seek> {
+        Some(self)
+    }
+
+    fn map_file(&self) -> Option<&dyn MapFile> {
+        Some(self)
+    }
+
+    fn stat(&self) -> Option<FileStat> {
+        Some(FileStat {
+            size: self.data.len() as u64,
+            kind: FileKind::File,
+            mode: self.mode,
+        })
+    }
+}
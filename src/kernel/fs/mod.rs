@@ -0,0 +1,458 @@
+//! Virtual file system: file systems mounted at points in a single tree of paths.
+
+pub mod initrd;
+pub mod tmpfs;
+
+use crate::arch::phys_mem;
+use crate::io::ReadDir;
+use crate::kobj::KObj;
+use crate::prelude::*;
+use crate::spin::Mutex;
+use alloc::sync::Arc;
+use syscall::{ErrNum, FileKind, FileStat, Result};
+
+pub use self::initrd::Initrd;
+pub use self::tmpfs::Tmpfs;
+
+/// A tree of files and directories that can be mounted into the VFS.
+pub trait FileSystem {
+    /// Opens the file at `path`, given as components relative to the mount point.
+    fn open(&self, path: &[&str]) -> Result<Arc<dyn KObj>>;
+
+    /// Creates an empty file at `path`, or truncates the file that's already there, and opens it.
+    fn create(&self, _path: &[&str]) -> Result<Arc<dyn KObj>> {
+        Err(ErrNum::NotSupported)
+    }
+
+    /// Removes a file or an empty directory.
+    fn unlink(&self, _path: &[&str]) -> Result<()> {
+        Err(ErrNum::NotSupported)
+    }
+
+    fn mkdir(&self, _path: &[&str]) -> Result<()> {
+        Err(ErrNum::NotSupported)
+    }
+}
+
+struct Mount {
+    path: Vec<String>,
+    fs: Arc<dyn FileSystem>,
+}
+
+pub struct Vfs {
+    mounts: Mutex<Vec<Mount>>,
+}
+
+unsafe impl Send for Vfs {}
+unsafe impl Sync for Vfs {}
+
+impl Vfs {
+    pub fn new() -> Self {
+        Vfs {
+            mounts: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Makes the contents of `fs` appear under `path`. A later mount at the same path hides an earlier one.
+    pub fn mount(&self, path: &str, fs: Arc<dyn FileSystem>) {
+        let path = parse_path(path).into_iter().map(String::from).collect();
+        lock!(self.mounts).push(Mount { path, fs });
+    }
+
+    pub fn open(&self, path: &str) -> Result<Arc<dyn KObj>> {
+        let path = parse_path(path);
+        let (fs, mount_len) = self.resolve(&path)?;
+        fs.open(&path[mount_len..])
+    }
+
+    pub fn create(&self, path: &str) -> Result<Arc<dyn KObj>> {
+        let path = parse_path(path);
+        let (fs, mount_len) = self.resolve(&path)?;
+        fs.create(&path[mount_len..])
+    }
+
+    pub fn unlink(&self, path: &str) -> Result<()> {
+        let path = parse_path(path);
+        let (fs, mount_len) = self.resolve(&path)?;
+        fs.unlink(&path[mount_len..])
+    }
+
+    pub fn mkdir(&self, path: &str) -> Result<()> {
+        let path = parse_path(path);
+        let (fs, mount_len) = self.resolve(&path)?;
+        fs.mkdir(&path[mount_len..])
+    }
+
+    fn resolve(&self, path: &[&str]) -> Result<(Arc<dyn FileSystem>, usize)> {
+        let mounts = lock!(self.mounts);
+        mounts
+            .iter()
+            .filter(|mount| mount.path.len() <= path.len() && mount.path.iter().zip(path).all(|(a, b)| a == b))
+            .max_by_key(|mount| mount.path.len())
+            .map(|mount| (mount.fs.clone(), mount.path.len()))
+            .ok_or(ErrNum::FileNotFound)
+    }
+}
+
+/// A handle to an open directory, which lists the entries that were in the directory when it was opened.
+pub struct Directory {
+    names: Vec<String>,
+    mode: u32,
+    pos: Mutex<usize>,
+}
+
+impl Directory {
+    pub fn new(names: Vec<String>, mode: u32) -> Self {
+        Directory {
+            names,
+            mode,
+            pos: Mutex::new(0),
+        }
+    }
+}
+
+impl ReadDir for Directory {
+    fn read_dir(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut pos = lock!(self.pos);
+        let name = match self.names.get(*pos) {
+            Some(name) => name.as_bytes(),
+            None => return Ok(0),
+        };
+
+        if name.len() > buf.len() {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        buf[..name.len()].copy_from_slice(name);
+        *pos += 1;
+        Ok(name.len())
+    }
+}
+
+impl KObj for Directory {
+    fn stat(&self) -> Option<FileStat> {
+        Some(FileStat {
+            size: self.names.len() as u64,
+            kind: FileKind::Directory,
+            mode: self.mode,
+        })
+    }
+
+    fn read_dir(&self) -> Option<&dyn ReadDir> {
+        Some(self)
+    }
+}
+
+/// Splits a path into its components, resolving `.` and `..`. All paths are relative to the root.
+pub fn parse_path(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+
+    components
+}
+
+lazy_static! {
+    static ref ROOT: Vfs = {
+        let vfs = Vfs::new();
+        vfs.mount("/", Arc::new(Initrd::new(phys_mem::initrd())));
+        vfs.mount("/tmp", Arc::new(Tmpfs::new()));
+        vfs
+    };
+}
+
+/// Opens a file in the kernel's root file system.
+pub fn open(path: &str) -> Result<Arc<dyn KObj>> {
+    ROOT.open(path)
+}
+
+pub fn create(path: &str) -> Result<Arc<dyn KObj>> {
+    ROOT.create(path)
+}
+
+pub fn unlink(path: &str) -> Result<()> {
+    ROOT.unlink(path)
+}
+
+pub fn mkdir(path: &str) -> Result<()> {
+    ROOT.mkdir(path)
+}
+
+#[cfg(feature = "test")]
+pub mod test {
+    use super::*;
+    use crate::fs::initrd::InitrdFile;
+    use crate::io::{MapFile, Read, SeekFrom, Write};
+    use crate::phys_mem::{PhysicalBitmap, PAGE_SIZE};
+    use core::mem;
+
+    const DIRECTORY: u8 = b'5';
+    const FILE: u8 = b'0';
+
+    fn write_octal(field: &mut [u8], value: usize) {
+        let digits = field.len() - 1;
+        for i in 0..digits {
+            field[digits - 1 - i] = b'0' + ((value >> (3 * i)) & 7) as u8;
+        }
+    }
+
+    fn make_tar(entries: &[(&str, &str, u8, &[u8])]) -> &'static [u8] {
+        let mut tar = Vec::new();
+        for &(prefix, filename, typeflag, data) in entries {
+            let mut header = [0u8; 512];
+            header[..filename.len()].copy_from_slice(filename.as_bytes());
+            write_octal(&mut header[100..108], 0o644);
+            write_octal(&mut header[124..136], data.len());
+            header[156] = typeflag;
+            header[257..263].copy_from_slice(b"ustar\0");
+            header[263..265].copy_from_slice(b"00");
+            header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+            tar.extend_from_slice(&header[..]);
+            tar.extend_from_slice(data);
+            tar.resize((tar.len() + 511) & !511, 0);
+        }
+
+        tar.resize(tar.len() + 1024, 0);
+        Box::leak(tar.into_boxed_slice())
+    }
+
+    fn make_vfs() -> Vfs {
+        let tar = make_tar(&[
+            ("", "bin/", DIRECTORY, b""),
+            ("", "bin/hello", FILE, b"hello world"),
+            ("share/fonts", "Vera.ttf", FILE, b"font"),
+        ]);
+
+        let vfs = Vfs::new();
+        vfs.mount("/", Arc::new(Initrd::new(tar)));
+        vfs.mount("/tmp", Arc::new(Tmpfs::new()));
+        vfs
+    }
+
+    fn write_all(kobj: &Arc<dyn KObj>, buf: &[u8]) {
+        let file = KObj::write(&**kobj).expect("file isn't writable");
+        assert_eq!(Ok(buf.len()), file.write(buf));
+    }
+
+    fn read_to_end(kobj: &Arc<dyn KObj>) -> Vec<u8> {
+        let file = KObj::read(&**kobj).expect("file isn't readable");
+        let mut v = Vec::new();
+        let mut buf = [0; 4];
+        loop {
+            let len = file.read(&mut buf).unwrap();
+            if len == 0 {
+                return v;
+            }
+
+            v.extend_from_slice(&buf[..len]);
+        }
+    }
+
+    fn list_dir(kobj: &Arc<dyn KObj>) -> Vec<String> {
+        let read_dir = kobj.read_dir().expect("not a directory");
+        let mut names = Vec::new();
+        let mut buf = [0; 100];
+        loop {
+            let len = read_dir.read_dir(&mut buf).unwrap();
+            if len == 0 {
+                return names;
+            }
+
+            names.push(String::from_utf8(buf[..len].to_vec()).unwrap());
+        }
+    }
+
+    test! {
+        fn can_parse_path() {
+            assert_eq!(vec!["bin", "hello"], parse_path("/bin/hello"));
+            assert_eq!(vec!["share", "Vera.ttf"], parse_path("share/./fonts/../Vera.ttf"));
+            assert_eq!(Vec::<&str>::new(), parse_path("/../.."));
+        }
+
+        fn can_open_file_in_directory() {
+            let vfs = make_vfs();
+            let file = vfs.open("/bin/hello").unwrap();
+            assert_eq!(b"hello world", &read_to_end(&file)[..]);
+        }
+
+        fn can_open_file_with_ustar_prefix() {
+            let vfs = make_vfs();
+            let file = vfs.open("/share/fonts/Vera.ttf").unwrap();
+            assert_eq!(b"font", &read_to_end(&file)[..]);
+        }
+
+        fn cant_open_missing_file() {
+            let vfs = make_vfs();
+            assert_eq!(Some(ErrNum::FileNotFound), vfs.open("/bin/goodbye").err());
+            assert_eq!(Some(ErrNum::FileNotFound), vfs.open("/hello").err());
+        }
+
+        fn can_list_directory() {
+            let vfs = make_vfs();
+            assert_eq!(vec!["bin", "share"], list_dir(&vfs.open("/").unwrap()));
+            assert_eq!(vec!["hello"], list_dir(&vfs.open("/bin").unwrap()));
+            assert_eq!(vec!["fonts"], list_dir(&vfs.open("/share").unwrap()));
+            assert_eq!(vec!["Vera.ttf"], list_dir(&vfs.open("/share/fonts").unwrap()));
+        }
+
+        fn read_dir_needs_big_enough_buffer() {
+            let vfs = make_vfs();
+            let dir = vfs.open("/bin").unwrap();
+            let read_dir = dir.read_dir().unwrap();
+            let mut buf = [0; 2];
+            assert_eq!(Err(ErrNum::InvalidArgument), read_dir.read_dir(&mut buf));
+
+            let mut buf = [0; 5];
+            assert_eq!(Ok(5), read_dir.read_dir(&mut buf));
+            assert_eq!(b"hello", &buf);
+            assert_eq!(Ok(0), read_dir.read_dir(&mut buf));
+        }
+
+        fn can_stat() {
+            let vfs = make_vfs();
+            let file = vfs.open("/bin/hello").unwrap().stat().unwrap();
+            assert_eq!(FileStat { size: 11, kind: FileKind::File, mode: 0o644 }, file);
+
+            let dir = vfs.open("/share/fonts").unwrap().stat().unwrap();
+            assert_eq!(FileKind::Directory, dir.kind);
+        }
+
+        fn can_seek() {
+            let vfs = make_vfs();
+            let file = vfs.open("/bin/hello").unwrap();
+            let seek = file.seek().expect("file isn't seekable");
+            assert_eq!(6, seek.seek(SeekFrom::Start(6)).unwrap());
+            assert_eq!(b"world", &read_to_end(&file)[..]);
+            assert_eq!(9, seek.seek(SeekFrom::End(-2)).unwrap());
+            assert_eq!(b"ld", &read_to_end(&file)[..]);
+            assert_eq!(Some(ErrNum::InvalidArgument), seek.seek(SeekFrom::Current(-100)).err());
+        }
+
+        fn later_mount_hides_earlier_one() {
+            let vfs = make_vfs();
+            vfs.mount("/bin", Arc::new(Initrd::new(make_tar(&[("", "hello", FILE, b"other")]))));
+            let file = vfs.open("/bin/hello").unwrap();
+            assert_eq!(b"other", &read_to_end(&file)[..]);
+        }
+
+        fn can_create_and_reopen_file() {
+            let vfs = make_vfs();
+            let file = vfs.create("/tmp/hello").unwrap();
+            write_all(&file, b"hello ");
+            write_all(&file, b"world");
+            assert_eq!(b"hello world", &read_to_end(&vfs.open("/tmp/hello").unwrap())[..]);
+            assert_eq!(11, file.stat().unwrap().size);
+        }
+
+        fn create_truncates_existing_file() {
+            let vfs = make_vfs();
+            write_all(&vfs.create("/tmp/hello").unwrap(), b"hello world");
+            write_all(&vfs.create("/tmp/hello").unwrap(), b"bye");
+            assert_eq!(b"bye", &read_to_end(&vfs.open("/tmp/hello").unwrap())[..]);
+        }
+
+        fn can_truncate_and_overwrite() {
+            let vfs = make_vfs();
+            let file = vfs.create("/tmp/hello").unwrap();
+            write_all(&file, b"hello world");
+            file.truncate().unwrap().truncate(5).unwrap();
+            file.seek().unwrap().seek(SeekFrom::Start(1)).unwrap();
+            write_all(&file, b"ipp");
+            file.seek().unwrap().seek(SeekFrom::Start(0)).unwrap();
+            assert_eq!(b"hippo", &read_to_end(&file)[..]);
+        }
+
+        fn tmpfs_files_have_a_size_limit() {
+            let vfs = make_vfs();
+            let file = vfs.create("/tmp/hello").unwrap();
+            let truncate = file.truncate().unwrap();
+            assert_eq!(Err(ErrNum::NoSpace), truncate.truncate(u64::max_value()));
+            assert_eq!(Err(ErrNum::NoSpace), truncate.truncate(tmpfs::MAX_FILE_SIZE as u64 + 1));
+
+            file.seek().unwrap().seek(SeekFrom::Start(tmpfs::MAX_FILE_SIZE as u64)).unwrap();
+            assert_eq!(Err(ErrNum::NoSpace), KObj::write(&*file).unwrap().write(b"x"));
+            assert_eq!(0, file.stat().unwrap().size);
+        }
+
+        fn tmpfs_has_a_total_size_and_node_limit() {
+            let vfs = Vfs::new();
+            vfs.mount("/", Arc::new(Tmpfs::with_limits(0x1000, 3)));
+
+            let a = vfs.create("/a").unwrap();
+            a.truncate().unwrap().truncate(0x1000).unwrap();
+            let b = vfs.create("/b").unwrap();
+            assert_eq!(Err(ErrNum::NoSpace), b.truncate().unwrap().truncate(1));
+            assert_eq!(Err(ErrNum::NoSpace), KObj::write(&*b).unwrap().write(b"x"));
+
+            vfs.mkdir("/c").unwrap();
+            assert_eq!(Some(ErrNum::NoSpace), vfs.create("/d").err());
+            assert_eq!(Some(ErrNum::NoSpace), vfs.mkdir("/d").err());
+
+            // An unlinked file still counts while it's open
+            vfs.unlink("/a").unwrap();
+            assert_eq!(Err(ErrNum::NoSpace), KObj::write(&*b).unwrap().write(b"x"));
+
+            mem::drop(a);
+            assert_eq!(Ok(1), KObj::write(&*b).unwrap().write(b"x"));
+            vfs.create("/d").unwrap();
+        }
+
+        fn can_make_directories() {
+            let vfs = make_vfs();
+            vfs.mkdir("/tmp/a").unwrap();
+            vfs.mkdir("/tmp/a/b").unwrap();
+            vfs.create("/tmp/a/file").unwrap();
+            assert_eq!(Some(ErrNum::AlreadyExists), vfs.mkdir("/tmp/a").err());
+            assert_eq!(Some(ErrNum::FileNotFound), vfs.mkdir("/tmp/missing/c").err());
+            assert_eq!(vec!["a"], list_dir(&vfs.open("/tmp").unwrap()));
+            assert_eq!(vec!["b", "file"], list_dir(&vfs.open("/tmp/a").unwrap()));
+        }
+
+        fn can_unlink() {
+            let vfs = make_vfs();
+            vfs.mkdir("/tmp/a").unwrap();
+            let file = vfs.create("/tmp/a/file").unwrap();
+            assert_eq!(Some(ErrNum::DirectoryNotEmpty), vfs.unlink("/tmp/a").err());
+            vfs.unlink("/tmp/a/file").unwrap();
+            vfs.unlink("/tmp/a").unwrap();
+            assert_eq!(Some(ErrNum::FileNotFound), vfs.open("/tmp/a").err());
+            assert_eq!(Some(ErrNum::FileNotFound), vfs.unlink("/tmp/a").err());
+
+            // The unlinked file stays usable through its open handle
+            write_all(&file, b"still here");
+            file.seek().unwrap().seek(SeekFrom::Start(0)).unwrap();
+            assert_eq!(b"still here", &read_to_end(&file)[..]);
+        }
+
+        fn initrd_is_read_only() {
+            let vfs = make_vfs();
+            assert_eq!(Some(ErrNum::NotSupported), vfs.create("/bin/new").err());
+            assert_eq!(Some(ErrNum::NotSupported), vfs.unlink("/bin/hello").err());
+            assert!(vfs.open("/bin/hello").unwrap().write().is_none());
+        }
+
+        fn initrd_maps_only_whole_aligned_pages() {
+            let phys = PhysicalBitmap::machine();
+            let addr = phys.alloc_zeroed_page().unwrap();
+            let page: &'static [u8; PAGE_SIZE] = unsafe { crate::phys_mem::phys2virt(addr) };
+
+            let file = InitrdFile::new(&page[..], 0o644);
+            assert_eq!(Some(addr), file.phys_page(0));
+            assert_eq!(None, file.phys_page(PAGE_SIZE as u64));
+            assert_eq!(None, InitrdFile::new(&page[..100], 0o644).phys_page(0));
+            assert_eq!(None, InitrdFile::new(&page[512..], 0o644).phys_page(0));
+
+            let mut buf = [1; 4];
+            assert_eq!(Ok(2), file.read_at(PAGE_SIZE as u64 - 2, &mut buf));
+            assert_eq!([0, 0, 1, 1], buf);
+            phys.free_page(addr);
+        }
+    }
+}
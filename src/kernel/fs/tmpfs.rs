@@ -0,0 +1,305 @@
+//! Writable file system that keeps its files in memory.
+
+use crate::fs::{Directory, FileSystem};
+use crate::io::{Read, Seek, SeekFrom, Truncate, Write};
+use crate::kobj::KObj;
+use crate::prelude::*;
+use crate::spin::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::cmp;
+use core::convert::TryFrom;
+use syscall::{ErrNum, FileKind, FileStat, Result};
+
+const FILE_MODE: u32 = 0o644;
+const DIR_MODE: u32 = 0o755;
+
+/// Files can't grow past this.
+pub const MAX_FILE_SIZE: usize = 16 * 1024 * 1024;
+
+/// The files in a tmpfs can't hold more than this between them, so that filling it can't use up all of the kernel's
+/// memory.
+pub const MAX_TOTAL_SIZE: usize = 64 * 1024 * 1024;
+
+/// A tmpfs can't hold more files and directories than this.
+pub const MAX_NODES: usize = 4096;
+
+/// How much a tmpfs holds, and how much it's allowed to.
+struct Usage {
+    bytes: usize,
+    nodes: usize,
+    max_bytes: usize,
+    max_nodes: usize,
+}
+
+impl Usage {
+    /// Counts `bytes` more bytes and `nodes` more files or directories, failing with `ErrNum::NoSpace` if that goes
+    /// over either limit.
+    fn charge(&mut self, bytes: usize, nodes: usize) -> Result<()> {
+        if bytes > self.max_bytes - self.bytes || nodes > self.max_nodes - self.nodes {
+            return Err(ErrNum::NoSpace);
+        }
+
+        self.bytes += bytes;
+        self.nodes += nodes;
+        Ok(())
+    }
+
+    fn release(&mut self, bytes: usize, nodes: usize) {
+        self.bytes -= bytes;
+        self.nodes -= nodes;
+    }
+}
+
+/// The contents of a file, which count towards the tmpfs's usage until the file has been unlinked and closed.
+struct Contents {
+    bytes: Vec<u8>,
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl Contents {
+    fn new(usage: Arc<Mutex<Usage>>) -> Result<Self> {
+        lock!(usage).charge(0, 1)?;
+        Ok(Contents {
+            bytes: Vec::new(),
+            usage,
+        })
+    }
+
+    /// Makes the file `len` bytes long, failing with `ErrNum::NoSpace` if that's more than `MAX_FILE_SIZE` or more
+    /// than the tmpfs has room for.
+    fn resize(&mut self, len: usize) -> Result<()> {
+        if len > MAX_FILE_SIZE {
+            return Err(ErrNum::NoSpace);
+        }
+
+        if len > self.bytes.len() {
+            let extra = len - self.bytes.len();
+            lock!(self.usage).charge(extra, 0)?;
+            if self.bytes.try_reserve(extra).is_err() {
+                lock!(self.usage).release(extra, 0);
+                return Err(ErrNum::OutOfMemory);
+            }
+
+            self.bytes.resize(len, 0);
+        } else {
+            lock!(self.usage).release(self.bytes.len() - len, 0);
+            self.bytes.truncate(len);
+            self.bytes.shrink_to_fit();
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Contents {
+    fn drop(&mut self) {
+        lock!(self.usage).release(self.bytes.len(), 1);
+    }
+}
+
+type FileData = Arc<Mutex<Contents>>;
+type DirEntries = Arc<Mutex<BTreeMap<String, Node>>>;
+
+#[derive(Clone)]
+enum Node {
+    File(FileData),
+    Directory(DirEntries),
+}
+
+pub struct Tmpfs {
+    root: DirEntries,
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl Tmpfs {
+    pub fn new() -> Self {
+        Self::with_limits(MAX_TOTAL_SIZE, MAX_NODES)
+    }
+
+    /// Makes a tmpfs whose files can hold `max_bytes` between them, and which can hold `max_nodes` files and
+    /// directories.
+    pub fn with_limits(max_bytes: usize, max_nodes: usize) -> Self {
+        Tmpfs {
+            root: Arc::new(Mutex::new(BTreeMap::new())),
+            usage: Arc::new(Mutex::new(Usage {
+                bytes: 0,
+                nodes: 0,
+                max_bytes,
+                max_nodes,
+            })),
+        }
+    }
+
+    fn lookup(&self, path: &[&str]) -> Result<Node> {
+        let mut node = Node::Directory(self.root.clone());
+        for &name in path {
+            node = match node {
+                Node::Directory(entries) => lock!(entries).get(name).cloned().ok_or(ErrNum::FileNotFound)?,
+                Node::File(_) => return Err(ErrNum::FileNotFound),
+            };
+        }
+
+        Ok(node)
+    }
+
+    /// Finds the directory that contains `path`, and returns it along with the last component of the path.
+    fn lookup_parent<'a>(&self, path: &[&'a str]) -> Result<(DirEntries, &'a str)> {
+        let (&name, parent) = path.split_last().ok_or(ErrNum::InvalidArgument)?;
+        match self.lookup(parent)? {
+            Node::Directory(entries) => Ok((entries, name)),
+            Node::File(_) => Err(ErrNum::FileNotFound),
+        }
+    }
+}
+
+impl FileSystem for Tmpfs {
+    fn open(&self, path: &[&str]) -> Result<Arc<dyn KObj>> {
+        match self.lookup(path)? {
+            Node::File(data) => Ok(Arc::new(TmpFile::new(data))),
+            Node::Directory(entries) => {
+                let names = lock!(entries).keys().cloned().collect();
+                Ok(Arc::new(Directory::new(names, DIR_MODE)))
+            }
+        }
+    }
+
+    fn create(&self, path: &[&str]) -> Result<Arc<dyn KObj>> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let data = {
+            let mut entries = lock!(parent);
+            match entries.get(name) {
+                Some(Node::File(data)) => {
+                    lock!(data).resize(0)?;
+                    data.clone()
+                }
+                Some(Node::Directory(_)) => return Err(ErrNum::AlreadyExists),
+                None => {
+                    let data = Arc::new(Mutex::new(Contents::new(self.usage.clone())?));
+                    entries.insert(String::from(name), Node::File(data.clone()));
+                    data
+                }
+            }
+        };
+
+        Ok(Arc::new(TmpFile::new(data)))
+    }
+
+    fn unlink(&self, path: &[&str]) -> Result<()> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let mut entries = lock!(parent);
+        let is_dir = match entries.get(name) {
+            Some(Node::Directory(children)) if !lock!(children).is_empty() => return Err(ErrNum::DirectoryNotEmpty),
+            Some(Node::Directory(_)) => true,
+            Some(Node::File(_)) => false,
+            None => return Err(ErrNum::FileNotFound),
+        };
+
+        // A file's contents stop counting once nothing has it open
+        entries.remove(name);
+        if is_dir {
+            lock!(self.usage).release(0, 1);
+        }
+
+        Ok(())
+    }
+
+    fn mkdir(&self, path: &[&str]) -> Result<()> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let mut entries = lock!(parent);
+        if entries.contains_key(name) {
+            return Err(ErrNum::AlreadyExists);
+        }
+
+        lock!(self.usage).charge(0, 1)?;
+        let dir = Arc::new(Mutex::new(BTreeMap::new()));
+        entries.insert(String::from(name), Node::Directory(dir));
+        Ok(())
+    }
+}
+
+/// An open file in a `Tmpfs`. The file's contents outlive its directory entry, so a file that's unlinked while
+/// open can still be read and written through its handle.
+pub struct TmpFile {
+    data: FileData,
+    pos: Mutex<u64>,
+}
+
+impl TmpFile {
+    fn new(data: FileData) -> Self {
+        TmpFile {
+            data,
+            pos: Mutex::new(0),
+        }
+    }
+}
+
+impl Read for TmpFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut pos = lock!(self.pos);
+        let data = lock!(self.data);
+        let start = cmp::min(*pos, data.bytes.len() as u64) as usize;
+        let len = cmp::min(buf.len(), data.bytes.len() - start);
+        buf[..len].copy_from_slice(&data.bytes[start..start + len]);
+        *pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for TmpFile {
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        let mut pos = lock!(self.pos);
+        let start = usize::try_from(*pos).map_err(|_| ErrNum::InvalidArgument)?;
+        let end = start.checked_add(buf.len()).ok_or(ErrNum::InvalidArgument)?;
+        let mut data = lock!(self.data);
+        if data.bytes.len() < end {
+            data.resize(end)?;
+        }
+
+        data.bytes[start..end].copy_from_slice(buf);
+        *pos = end as u64;
+        Ok(buf.len())
+    }
+}
+
+impl Seek for TmpFile {
+    fn seek(&self, pos: SeekFrom) -> Result<u64> {
+        let mut current = lock!(self.pos);
+        let len = lock!(self.data).bytes.len() as u64;
+        *current = pos.resolve(*current, len)?;
+        Ok(*current)
+    }
+}
+
+impl Truncate for TmpFile {
+    fn truncate(&self, len: u64) -> Result<()> {
+        let len = usize::try_from(len).map_err(|_| ErrNum::NoSpace)?;
+        lock!(self.data).resize(len)
+    }
+}
+
+impl KObj for TmpFile {
+    fn read(&self) -> Option<&dyn Read> {
+        Some(self)
+    }
+
+    fn write(&self) -> Option<&dyn Write> {
+        Some(self)
+    }
+
+    fn seek(&self) -> Option<&dyn Seek> {
+        Some(self)
+    }
+
+    fn truncate(&self) -> Option<&dyn Truncate> {
+        Some(self)
+    }
+
+    fn stat(&self) -> Option<FileStat> {
+        Some(FileStat {
+            size: lock!(self.data).bytes.len() as u64,
+            kind: FileKind::File,
+            mode: FILE_MODE,
+        })
+    }
+}
@@ -0,0 +1,389 @@
+use crate::deferred::Deferred;
+use crate::io::{Read, Write};
+use crate::kobj::KObj;
+use crate::prelude::*;
+use crate::spin::Mutex;
+use crate::thread::{self, BlockedThread};
+use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
+use core::cmp;
+use core::mem;
+use syscall::{ErrNum, Result, POLL_HUP, POLL_READ, POLL_WRITE};
+
+/// How many messages can wait in each direction before `send` fails.
+const MAX_QUEUED_MESSAGES: usize = 64;
+
+/// How many bytes of message data can wait in each direction before `send` fails.
+const MAX_QUEUED_BYTES: usize = 64 * 1024;
+
+/// Bytes sent over a channel in one go, along with any objects that go with them.
+pub struct Message {
+    pub data: Vec<u8>,
+    pub handles: Vec<Arc<dyn KObj>>,
+}
+
+struct Queue {
+    messages: VecDeque<Message>,
+    /// Bytes of data in `messages`.
+    bytes: usize,
+    waiters: VecDeque<BlockedThread>,
+    ready: Deferred<()>,
+    /// Resolved when anything changes, including a full queue getting room again.
+    pollers: Vec<Deferred<()>>,
+    /// Set once the end that sends to this queue has gone.
+    closed: bool,
+}
+
+impl Queue {
+    fn new() -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Queue {
+            messages: VecDeque::new(),
+            bytes: 0,
+            waiters: VecDeque::new(),
+            ready: Deferred::new(),
+            pollers: Vec::new(),
+            closed: false,
+        }))
+    }
+
+    fn is_full(&self) -> bool {
+        self.messages.len() >= MAX_QUEUED_MESSAGES || self.bytes >= MAX_QUEUED_BYTES
+    }
+
+    /// Calls `f` with the queue, then wakes up anyone waiting for it, unless `f` fails.
+    fn wake(queue: &Mutex<Self>, f: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        let mut queue = lock!(queue);
+        f(&mut queue)?;
+
+        while let Some(thread) = queue.waiters.pop_front() {
+            thread.resume();
+        }
+
+        let ready = mem::replace(&mut queue.ready, Deferred::new());
+        let pollers = mem::replace(&mut queue.pollers, Vec::new());
+        mem::drop(queue);
+        ready.resolve(());
+        for poller in pollers {
+            poller.try_resolve(());
+        }
+
+        Ok(())
+    }
+
+    /// Accounts for `len` bytes of data having been read, and takes the first message off the queue if `pop` is
+    /// set. Lets pollers know if the queue had been full.
+    fn drain(&mut self, len: usize, pop: bool) -> Option<Message> {
+        let was_full = self.is_full();
+        self.bytes -= len;
+
+        let message = if pop { self.messages.pop_front() } else { None };
+        if was_full {
+            for poller in mem::replace(&mut self.pollers, Vec::new()) {
+                poller.try_resolve(());
+            }
+        }
+
+        message
+    }
+
+    fn poller(&mut self, changed: &Deferred<()>) {
+        // Forget about earlier polls that have finished
+        self.pollers.retain(|poller| !poller.is_orphaned());
+        self.pollers.push(changed.clone());
+    }
+}
+
+/// One end of a connected pair. Messages sent from one end are received at the other, in order and with their
+/// boundaries kept.
+pub struct Channel {
+    inbox: Arc<Mutex<Queue>>,
+    outbox: Arc<Mutex<Queue>>,
+}
+
+unsafe impl Send for Channel {}
+unsafe impl Sync for Channel {}
+
+impl Channel {
+    pub fn pair() -> (Self, Self) {
+        let a = Queue::new();
+        let b = Queue::new();
+        (Self::new(a.clone(), b.clone()), Self::new(b, a))
+    }
+
+    fn new(inbox: Arc<Mutex<Queue>>, outbox: Arc<Mutex<Queue>>) -> Self {
+        Channel { inbox, outbox }
+    }
+
+    /// Queues a message for the other end. Fails with `ErrNum::WouldBlock` if too many messages or bytes are waiting
+    /// already, and with `ErrNum::InvalidArgument` if the message could never fit, if it carries either end of this
+    /// channel, or if it carries a channel end that has channel ends waiting to be received itself. Channels sent
+    /// that way could end up queued inside each other, with nothing left outside to close them.
+    pub fn send(&self, message: Message) -> Result<()> {
+        if message.data.len() > MAX_QUEUED_BYTES {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        for kobj in message.handles.iter() {
+            if let Some(channel) = kobj.channel() {
+                if Arc::ptr_eq(&channel.inbox, &self.outbox)
+                    || Arc::ptr_eq(&channel.outbox, &self.outbox)
+                    || channel.has_channels_queued()
+                {
+                    return Err(ErrNum::InvalidArgument);
+                }
+            }
+        }
+
+        Queue::wake(&self.outbox, |queue| {
+            if queue.is_full() || queue.bytes + message.data.len() > MAX_QUEUED_BYTES {
+                return Err(ErrNum::WouldBlock);
+            }
+
+            queue.bytes += message.data.len();
+            queue.messages.push_back(message);
+            Ok(())
+        })
+    }
+
+    fn has_channels_queued(&self) -> bool {
+        let inbox = lock!(self.inbox);
+        inbox
+            .messages
+            .iter()
+            .any(|message| message.handles.iter().any(|kobj| kobj.channel().is_some()))
+    }
+
+    /// Blocks until there's a message, then calls `f` with the queue, which is only empty if the other end has
+    /// gone. Fails with `ErrNum::Interrupted` if the current process is killed first.
+    fn with_messages<T, F: FnOnce(&mut Queue) -> T>(&self, f: F) -> Result<T> {
+        loop {
+            let mut inbox = lock!(self.inbox);
+            if !inbox.messages.is_empty() || inbox.closed {
+                return Ok(f(&mut inbox));
+            }
+
+            let queue = self.inbox.clone();
+            thread::block_until(
+                usize::MAX,
+                move |thread| {
+                    inbox.waiters.push_back(thread);
+                },
+                move |id| thread::remove_waiter(&mut lock!(queue).waiters, id),
+            )?;
+        }
+    }
+
+    /// Blocks until a message arrives. Fails with `ErrNum::InvalidArgument`, and leaves the message where it is, if
+    /// it has more than `max_len` bytes or `max_handles` handles. Returns `None` once the other end has gone and
+    /// every message has been received.
+    pub fn receive(&self, max_len: usize, max_handles: usize) -> Result<Option<Message>> {
+        self.with_messages(|queue| {
+            let len = match queue.messages.front() {
+                Some(message) if message.data.len() > max_len || message.handles.len() > max_handles => {
+                    return Err(ErrNum::InvalidArgument);
+                }
+                Some(message) => message.data.len(),
+                None => return Ok(None),
+            };
+
+            Ok(queue.drain(len, true))
+        })?
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let _ = Queue::wake(&self.outbox, |queue| {
+            queue.closed = true;
+            Ok(())
+        });
+    }
+}
+
+/// Reads the bytes of messages as if they were a stream. Handles attached to the messages are closed.
+impl Read for Channel {
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let len = self.with_messages(|queue| {
+                let message = match queue.messages.front_mut() {
+                    Some(message) => message,
+                    None => return Some(0),
+                };
+
+                let len = cmp::min(buf.len(), message.data.len());
+                buf[..len].copy_from_slice(&message.data[..len]);
+                message.data.drain(..len);
+
+                let pop = message.data.is_empty();
+                queue.drain(len, pop);
+
+                // A message with only handles in it isn't the end of the stream
+                if len > 0 || buf.is_empty() {
+                    Some(len)
+                } else {
+                    None
+                }
+            })?;
+
+            if let Some(len) = len {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+impl Write for Channel {
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        let len = cmp::min(buf.len(), MAX_QUEUED_BYTES);
+        self.send(Message {
+            data: buf[..len].to_vec(),
+            handles: Vec::new(),
+        })?;
+
+        Ok(len)
+    }
+}
+
+impl KObj for Channel {
+    fn read(&self) -> Option<&dyn Read> {
+        Some(self)
+    }
+
+    fn write(&self) -> Option<&dyn Write> {
+        Some(self)
+    }
+
+    fn channel(&self) -> Option<&Channel> {
+        Some(self)
+    }
+
+    fn ready(&self) -> Option<Deferred<()>> {
+        let inbox = lock!(self.inbox);
+        if inbox.messages.is_empty() && !inbox.closed {
+            Some(inbox.ready.clone())
+        } else {
+            Some(Deferred::resolved(()))
+        }
+    }
+
+    /// Channels are writable until too many messages are waiting for the other end.
+    fn poll(&self) -> Option<(u32, Deferred<()>)> {
+        // Lock one queue at a time, since the other end locks them the other way round
+        let changed = Deferred::new();
+        let mut state = 0;
+        {
+            let mut outbox = lock!(self.outbox);
+            outbox.poller(&changed);
+            if !outbox.is_full() {
+                state |= POLL_WRITE;
+            }
+        }
+
+        let mut inbox = lock!(self.inbox);
+        inbox.poller(&changed);
+        if !inbox.messages.is_empty() {
+            state |= POLL_READ;
+        }
+
+        if inbox.closed {
+            state |= POLL_HUP;
+        }
+
+        Some((state, changed))
+    }
+}
+
+#[cfg(feature = "test")]
+pub mod test {
+    use super::*;
+    use crate::io::Pipe;
+    use crate::kobj;
+    use crate::time;
+
+    fn message(data: &[u8], handles: Vec<Arc<dyn KObj>>) -> Message {
+        Message {
+            data: data.to_vec(),
+            handles,
+        }
+    }
+
+    test! {
+        fn messages_keep_their_boundaries() {
+            let (a, b) = Channel::pair();
+            a.send(message(b"hello", Vec::new())).unwrap();
+            a.send(message(b"world", Vec::new())).unwrap();
+
+            assert_eq!(b"hello", &b.receive(10, 0).unwrap().unwrap().data[..]);
+            assert_eq!(b"world", &b.receive(10, 0).unwrap().unwrap().data[..]);
+        }
+
+        fn message_too_big_stays_queued() {
+            let (a, b) = Channel::pair();
+            let pipe: Arc<dyn KObj> = Arc::new(Pipe::new());
+            a.send(message(b"hello", vec![pipe.clone()])).unwrap();
+
+            assert_eq!(Some(ErrNum::InvalidArgument), b.receive(4, 1).err());
+            assert_eq!(Some(ErrNum::InvalidArgument), b.receive(5, 0).err());
+
+            let received = b.receive(5, 1).unwrap().unwrap();
+            assert!(Arc::ptr_eq(&pipe, &received.handles[0]));
+        }
+
+        fn send_fails_once_queue_is_full() {
+            let (a, b) = Channel::pair();
+            for _ in 0..MAX_QUEUED_MESSAGES {
+                a.send(message(b"x", Vec::new())).unwrap();
+            }
+
+            assert_eq!(Some(ErrNum::WouldBlock), a.send(message(b"x", Vec::new())).err());
+            let (state, changed) = a.poll().unwrap();
+            assert_eq!(0, state & POLL_WRITE);
+            assert!(!changed.is_resolved());
+
+            b.receive(1, 0).unwrap().unwrap();
+            assert!(changed.is_resolved());
+            assert_eq!(POLL_WRITE, a.poll().unwrap().0 & POLL_WRITE);
+            a.send(message(b"x", Vec::new())).unwrap();
+
+            let big = vec![0; MAX_QUEUED_BYTES + 1];
+            assert_eq!(Some(ErrNum::InvalidArgument), a.send(message(&big, Vec::new())).err());
+        }
+
+        fn cannot_send_channel_through_itself() {
+            let (a, b) = Channel::pair();
+            let b: Arc<dyn KObj> = Arc::new(b);
+            assert_eq!(Some(ErrNum::InvalidArgument), a.send(message(b"", vec![b.clone()])).err());
+
+            let (_c, d) = Channel::pair();
+            a.send(message(b"", vec![Arc::new(d) as Arc<dyn KObj>])).unwrap();
+        }
+
+        fn cannot_send_channels_through_each_other() {
+            let (a, b) = Channel::pair();
+            let (c, d) = Channel::pair();
+            let b: Arc<dyn KObj> = Arc::new(b);
+            let d: Arc<dyn KObj> = Arc::new(d);
+            c.send(message(b"", vec![b])).unwrap();
+            assert_eq!(Some(ErrNum::InvalidArgument), a.send(message(b"", vec![d.clone()])).err());
+
+            // Once b has been received, d can be sent
+            d.channel().unwrap().receive(0, 1).unwrap().unwrap();
+            a.send(message(b"", vec![d])).unwrap();
+        }
+
+        fn read_sees_end_once_other_end_drops() {
+            let (a, b) = Channel::pair();
+            Write::write(&a, b"hello").unwrap();
+
+            let objs: [Arc<dyn KObj>; 1] = [Arc::new(b)];
+            mem::drop(a);
+            assert_eq!(Ok(0), kobj::wait_for_any(&objs, time::ticks()));
+
+            let mut buf = [0; 10];
+            let b = objs[0].read().unwrap();
+            assert_eq!(Ok(3), b.read(&mut buf[..3]));
+            assert_eq!(Ok(2), b.read(&mut buf));
+            assert_eq!(Ok(0), b.read(&mut buf));
+        }
+    }
+}
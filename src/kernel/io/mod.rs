@@ -1,5 +1,6 @@
 //! Input and output.
 
+pub mod channel;
 pub mod pipe;
 
 mod nodes;
@@ -7,10 +8,13 @@ mod nodes;
 use crate::deferred::Deferred;
 use crate::io::nodes::PromiseNode;
 use crate::prelude::*;
+use core::mem;
 use core::result;
-use syscall::Result;
+use syscall::{ErrNum, Result};
 
+pub use self::channel::{Channel, Message};
 pub use self::pipe::Pipe;
+pub use syscall::SeekFrom;
 
 /// Allows for reading bytes from a source.
 pub trait Read {
@@ -22,6 +26,38 @@ pub trait Write {
     fn write(&self, buf: &[u8]) -> Result<usize>;
 }
 
+/// Provides a cursor which can be moved within a stream of bytes.
+pub trait Seek {
+    /// Seeks to an offset, in bytes, and returns the new position from the start of the stream.
+    fn seek(&self, pos: SeekFrom) -> Result<u64>;
+}
+
+/// Changes the length of a file.
+pub trait Truncate {
+    /// Cuts the file off at `len` bytes, or extends it with zeros up to `len` bytes.
+    fn truncate(&self, len: u64) -> Result<()>;
+}
+
+/// Files whose contents can be mapped into a process's memory.
+pub trait MapFile {
+    /// Copies bytes starting at `offset` into `buf`, without moving the file's position, and returns how many there
+    /// were.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize>;
+
+    /// Returns the physical address of the page at `offset` if the file holds it in memory already, so that it can
+    /// be mapped without copying it. `offset` is a multiple of the page size.
+    fn phys_page(&self, _offset: u64) -> Option<usize> {
+        None
+    }
+}
+
+/// Lists the entries in a directory.
+pub trait ReadDir {
+    /// Copies the name of the next entry into `buf` and returns its length, or returns 0 once there are no more
+    /// entries.
+    fn read_dir(&self, buf: &mut [u8]) -> Result<usize>;
+}
+
 /// A computation that might eventually resolve to a value of type `T`.
 pub struct Promise<T>(Box<dyn PromiseNode<T>>);
 
@@ -43,17 +79,38 @@ impl<T: 'static> Promise<T> {
     pub fn try_get(self) -> result::Result<T, Self> {
         self.0.try_get().map_err(|node| Promise(node))
     }
+
+    /// Blocks until the promise is resolved or the tick count reaches `deadline`.
+    ///
+    /// If the promise is resolved in time, returns `Ok(value)`. Otherwise, returns `Err` containing the original
+    /// promise.
+    pub fn get_timeout(self, deadline: usize) -> result::Result<T, Self> {
+        self.0.get_timeout(deadline).map_err(|node| Promise(node))
+    }
 }
 
 /// Allows for reading bytes asynchronously from a source.
 pub trait AsyncRead {
     fn read_async(&self, buf: Vec<u8>) -> Promise<Result<Vec<u8>>>;
+
+    /// Forgets about reads whose promises have been dropped, such as ones that timed out.
+    fn cancel_abandoned(&self) {}
 }
 
+/// Waits for the read to finish, or until the current process is killed, in which case the read is abandoned and
+/// fails with `ErrNum::Interrupted`.
 impl<T: AsyncRead> Read for T {
     fn read(&self, buf: &mut [u8]) -> Result<usize> {
         let p = self.read_async(vec![0; buf.len()]);
-        let v = p.get()?;
+        let v = match p.get_timeout(usize::MAX) {
+            Ok(result) => result?,
+            Err(p) => {
+                mem::drop(p);
+                self.cancel_abandoned();
+                return Err(ErrNum::Interrupted);
+            }
+        };
+
         &mut buf[..v.len()].copy_from_slice(&v);
         Ok(v.len())
     }
@@ -6,6 +6,7 @@ use core::result;
 pub trait PromiseNode<A> {
     fn get(self: Box<Self>) -> A;
     fn try_get(self: Box<Self>) -> result::Result<A, Box<dyn PromiseNode<A>>>;
+    fn get_timeout(self: Box<Self>, deadline: usize) -> result::Result<A, Box<dyn PromiseNode<A>>>;
     fn try_unwrap(self: Box<Self>) -> result::Result<Promise<A>, Box<dyn PromiseNode<A>>>;
 }
 
@@ -30,6 +31,13 @@ impl<A: 'static> PromiseNode<A> for DeferredNode<A> {
         })
     }
 
+    fn get_timeout(self: Box<Self>, deadline: usize) -> result::Result<A, Box<dyn PromiseNode<A>>> {
+        self.0.get_timeout(deadline).map_err(|d| {
+            let b: Box<dyn PromiseNode<A>> = Box::new(DeferredNode(d));
+            b
+        })
+    }
+
     fn try_unwrap(self: Box<Self>) -> result::Result<Promise<A>, Box<dyn PromiseNode<A>>> {
         Err(self)
     }
@@ -49,6 +57,14 @@ impl<A: 'static, B, F: FnOnce(A) -> B + 'static> PromiseNode<B> for MapNode<A, F
         }
     }
 
+    fn get_timeout(self: Box<Self>, deadline: usize) -> result::Result<B, Box<dyn PromiseNode<B>>> {
+        let p = *self;
+        match p.0.get_timeout(deadline) {
+            Ok(result) => Ok(p.1(result)),
+            Err(node) => Err(Box::new(MapNode(node, p.1))),
+        }
+    }
+
     fn try_unwrap(self: Box<Self>) -> result::Result<Promise<B>, Box<dyn PromiseNode<B>>> {
         Err(self)
     }
@@ -72,6 +88,13 @@ impl<A: 'static> PromiseNode<A> for UnwrapNode<A> {
         }
     }
 
+    fn get_timeout(self: Box<Self>, deadline: usize) -> result::Result<A, Box<dyn PromiseNode<A>>> {
+        match self.0.get_timeout(deadline) {
+            Ok(node) => node.0.get_timeout(deadline),
+            Err(node) => Err(Box::new(UnwrapNode(node))),
+        }
+    }
+
     fn try_unwrap(self: Box<Self>) -> result::Result<Promise<A>, Box<dyn PromiseNode<A>>> {
         Ok(self.0.get())
     }
@@ -86,6 +109,10 @@ impl<A: 'static> PromiseNode<A> for ResolvedNode<A> {
         Ok(self.0)
     }
 
+    fn get_timeout(self: Box<Self>, _deadline: usize) -> result::Result<A, Box<dyn PromiseNode<A>>> {
+        Ok(self.0)
+    }
+
     fn try_unwrap(self: Box<Self>) -> result::Result<Promise<A>, Box<dyn PromiseNode<A>>> {
         Err(self)
     }
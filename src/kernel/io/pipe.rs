@@ -3,9 +3,12 @@ use crate::io::{AsyncRead, Promise, Read, Write};
 use crate::kobj::KObj;
 use crate::prelude::*;
 use crate::spin::Mutex;
+use crate::thread::{self, BlockedThread};
 use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
 use core::cmp;
-use syscall::Result;
+use core::mem;
+use syscall::{ErrNum, Result, POLL_HUP, POLL_READ, POLL_WRITE};
 
 struct IoRequest {
     buf: Vec<u8>,
@@ -19,6 +22,11 @@ impl IoRequest {
     }
 
     pub fn fulfil(mut self, data: &mut VecDeque<u8>) -> Option<Self> {
+        // The reader gave up waiting (say, it timed out), so leave the data for the next one
+        if self.d.is_orphaned() {
+            return None;
+        }
+
         {
             let len = cmp::min(self.buf.len(), data.len());
             let right = data.split_off(len);
@@ -32,51 +40,131 @@ impl IoRequest {
         }
 
         if self.current > 0 || self.buf.len() == 0 {
-            self.buf.truncate(self.current);
-            self.d.resolve(Ok(self.buf));
+            self.finish();
             None
         } else {
             Some(self)
         }
     }
+
+    /// Hands back whatever has been read so far, which is nothing if the pipe has reached its end.
+    pub fn finish(mut self) {
+        self.buf.truncate(self.current);
+        self.d.resolve(Ok(self.buf));
+    }
 }
 
+struct Buffer {
+    bytes: VecDeque<u8>,
+    /// Threads waiting for room to write.
+    writers: VecDeque<BlockedThread>,
+    read_closed: bool,
+    write_closed: bool,
+    /// Resolved whenever the bytes or the state of either end change, for `poll`.
+    changed: Deferred<()>,
+}
+
+/// Bytes written to a pipe are read from it in the same order. Writers wait once the pipe holds `capacity` bytes.
 pub struct Pipe {
-    data: Mutex<VecDeque<u8>>,
+    data: Arc<Mutex<Buffer>>,
     requests: Mutex<VecDeque<IoRequest>>,
+    ready: Mutex<Deferred<()>>,
+    capacity: usize,
 }
 
+unsafe impl Send for Pipe {}
+unsafe impl Sync for Pipe {}
+
 impl Pipe {
     pub fn new() -> Self {
+        Self::with_capacity(syscall::PIPE_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0);
         Pipe {
-            data: Mutex::new(VecDeque::new()),
+            data: Arc::new(Mutex::new(Buffer {
+                bytes: VecDeque::new(),
+                writers: VecDeque::new(),
+                read_closed: false,
+                write_closed: false,
+                changed: Deferred::new(),
+            })),
             requests: Mutex::new(VecDeque::new()),
+            ready: Mutex::new(Deferred::new()),
+            capacity,
         }
     }
 
+    /// Creates a pipe with separate ends for reading and writing. Readers see the end of the data once the write end
+    /// has been dropped, and writers fail with `ErrNum::BrokenPipe` once the read end has been dropped.
+    pub fn ends(capacity: usize) -> (PipeReader, PipeWriter) {
+        let pipe = Arc::new(Self::with_capacity(capacity));
+        (PipeReader(pipe.clone()), PipeWriter(pipe))
+    }
+
     pub fn queue_len(&self) -> usize {
-        lock!(self.data).len()
+        lock!(self.data).bytes.len()
     }
 
-    fn fulfil(&self) -> bool {
+    /// Hands data to waiting readers, and wakes up waiting writers if there's room.
+    fn fulfil(&self) {
         let mut data = lock!(self.data);
-        loop {
-            let mut requests = lock!(self.requests);
-            let request: IoRequest = match requests.pop_front() {
-                Some(request) => request,
-                None => {
-                    return false;
-                }
-            };
-
-            if let Some(request) = request.fulfil(&mut data) {
+        let mut requests = lock!(self.requests);
+        while let Some(request) = requests.pop_front() {
+            if data.bytes.is_empty() && data.write_closed {
+                request.finish();
+            } else if let Some(request) = request.fulfil(&mut data.bytes) {
                 requests.push_front(request);
+                break;
             }
+        }
 
-            if data.len() == 0 {
-                return !requests.is_empty();
+        if data.bytes.len() < self.capacity || data.read_closed {
+            while let Some(thread) = data.writers.pop_front() {
+                thread.resume();
             }
         }
+
+        let changed = mem::replace(&mut data.changed, Deferred::new());
+        mem::drop(requests);
+        mem::drop(data);
+        changed.resolve(());
+    }
+
+    /// Returns the `POLL_` flags for the pipe as a whole. Each end only reports its own direction.
+    pub fn poll_state(&self) -> (u32, Deferred<()>) {
+        let data = lock!(self.data);
+        let mut state = 0;
+        if !data.bytes.is_empty() {
+            state |= POLL_READ;
+        }
+
+        if data.bytes.len() < self.capacity && !data.read_closed {
+            state |= POLL_WRITE;
+        }
+
+        if data.read_closed || data.write_closed {
+            state |= POLL_HUP;
+        }
+
+        (state, data.changed.clone())
+    }
+
+    fn resolve_ready(&self) {
+        let ready = mem::replace(&mut *lock!(self.ready), Deferred::new());
+        ready.resolve(());
+    }
+
+    fn close_read(&self) {
+        lock!(self.data).read_closed = true;
+        self.fulfil();
+    }
+
+    fn close_write(&self) {
+        lock!(self.data).write_closed = true;
+        self.fulfil();
+        self.resolve_ready();
     }
 }
 
@@ -87,13 +175,49 @@ impl AsyncRead for Pipe {
         self.fulfil();
         Promise::new(d)
     }
+
+    fn cancel_abandoned(&self) {
+        lock!(self.requests).retain(|request| !request.d.is_orphaned());
+    }
 }
 
+/// Writes as much of `buf` as there's room for, waiting until there's room for at least one byte. Fails with
+/// `ErrNum::Interrupted` if the current process is killed while waiting.
 impl Write for Pipe {
     fn write(&self, buf: &[u8]) -> Result<usize> {
-        lock!(self.data).extend(buf);
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = loop {
+            let mut data = lock!(self.data);
+            if data.read_closed {
+                return Err(ErrNum::BrokenPipe);
+            }
+
+            let len = cmp::min(buf.len(), self.capacity - data.bytes.len());
+            if len > 0 {
+                data.bytes.extend(&buf[..len]);
+                break len;
+            }
+
+            let buffer = self.data.clone();
+            thread::block_until(
+                usize::MAX,
+                move |thread| {
+                    data.writers.push_back(thread);
+                },
+                move |id| thread::remove_waiter(&mut lock!(buffer).writers, id),
+            )?;
+        };
+
         self.fulfil();
-        Ok(buf.len())
+
+        if self.queue_len() > 0 {
+            self.resolve_ready();
+        }
+
+        Ok(len)
     }
 }
 
@@ -109,12 +233,78 @@ impl KObj for Pipe {
     fn write(&self) -> Option<&dyn Write> {
         Some(self)
     }
+
+    fn ready(&self) -> Option<Deferred<()>> {
+        let data = lock!(self.data);
+        if data.bytes.is_empty() && !data.write_closed {
+            Some(lock!(self.ready).clone())
+        } else {
+            Some(Deferred::resolved(()))
+        }
+    }
+
+    fn poll(&self) -> Option<(u32, Deferred<()>)> {
+        Some(self.poll_state())
+    }
+}
+
+/// The end of a pipe that can only be read from.
+pub struct PipeReader(Arc<Pipe>);
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.close_read();
+    }
+}
+
+impl KObj for PipeReader {
+    fn async_read(&self) -> Option<&dyn AsyncRead> {
+        Some(&*self.0)
+    }
+
+    fn read(&self) -> Option<&dyn Read> {
+        Some(&*self.0)
+    }
+
+    fn ready(&self) -> Option<Deferred<()>> {
+        self.0.ready()
+    }
+
+    fn poll(&self) -> Option<(u32, Deferred<()>)> {
+        let (state, changed) = self.0.poll_state();
+        Some((state & !POLL_WRITE, changed))
+    }
+}
+
+/// The end of a pipe that can only be written to.
+pub struct PipeWriter(Arc<Pipe>);
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.close_write();
+    }
+}
+
+impl KObj for PipeWriter {
+    fn write(&self) -> Option<&dyn Write> {
+        Some(&*self.0)
+    }
+
+    fn poll(&self) -> Option<(u32, Deferred<()>)> {
+        let (state, changed) = self.0.poll_state();
+        Some((state & !POLL_READ, changed))
+    }
 }
 
 #[cfg(feature = "test")]
 pub mod test {
     use super::*;
     use crate::io::{AsyncRead, Write};
+    use crate::kobj;
+    use crate::thread;
+    use crate::time;
+    use alloc::sync::Arc;
+    use syscall::ErrNum;
 
     fn test_read(pipe: &Pipe, expected: &[u8]) {
         let buf = vec![0; expected.len()];
@@ -168,5 +358,140 @@ pub mod test {
             test_read(&pipe, b"r");
             assert_eq!(2, pipe.queue_len());
         }
+
+        fn abandoned_read_does_not_lose_data() {
+            let pipe = Pipe::new();
+            let d = pipe.read_async(vec![0; 10]);
+            assert!(d.try_get().is_err());
+
+            Write::write(&pipe, b"hello").unwrap();
+            test_read(&pipe, b"hello");
+        }
+
+        fn cancel_abandoned_removes_read() {
+            let pipe = Pipe::new();
+            let _pending = pipe.read_async(vec![0; 10]);
+            mem::drop(pipe.read_async(vec![0; 10]));
+            assert_eq!(2, lock!(pipe.requests).len());
+
+            pipe.cancel_abandoned();
+            assert_eq!(1, lock!(pipe.requests).len());
+        }
+
+        fn wait_for_any_returns_ready_pipe() {
+            let p1 = Arc::new(Pipe::new());
+            let p2 = Arc::new(Pipe::new());
+            Write::write(&*p2, b"hello").unwrap();
+
+            let objs: [Arc<dyn KObj>; 2] = [p1, p2.clone()];
+            assert_eq!(Ok(1), kobj::wait_for_any(&objs, time::ticks()));
+            test_read(&p2, b"hello");
+        }
+
+        fn wait_for_any_times_out() {
+            thread::with_scheduler(|| {
+                let objs: [Arc<dyn KObj>; 2] = [Arc::new(Pipe::new()), Arc::new(Pipe::new())];
+                assert_eq!(Err(ErrNum::TimedOut), kobj::wait_for_any(&objs, time::ticks() + 2));
+            });
+        }
+
+        fn wait_for_any_wakes_on_write() {
+            thread::with_scheduler(|| {
+                let p1 = Arc::new(Pipe::new());
+                let p2 = Arc::new(Pipe::new());
+
+                let d = thread::spawn({
+                    let p2 = p2.clone();
+                    move || {
+                        Write::write(&*p2, b"hello").unwrap();
+                        0
+                    }
+                });
+
+                let objs: [Arc<dyn KObj>; 2] = [p1, p2.clone()];
+                assert_eq!(Ok(1), kobj::wait_for_any(&objs, time::ticks() + 1000));
+                assert_eq!(0, d.get());
+                test_read(&p2, b"hello");
+            });
+        }
+
+        fn read_sees_end_once_writer_drops() {
+            let (reader, writer) = Pipe::ends(10);
+            let d = reader.0.read_async(vec![0; 10]);
+            Write::write(&*writer.0, b"hello").unwrap();
+            assert_eq!(Ok(b"hello".to_vec()), d.get());
+
+            let d = reader.0.read_async(vec![0; 10]).try_get().err().expect("didn't expect data");
+
+            mem::drop(writer);
+            assert_eq!(Ok(Vec::new()), d.get());
+            assert_eq!(Ok(Vec::new()), reader.0.read_async(vec![0; 10]).get());
+        }
+
+        fn write_stops_when_full() {
+            let pipe = Pipe::with_capacity(4);
+            assert_eq!(Ok(4), Write::write(&pipe, b"hello"));
+            test_read(&pipe, b"he");
+            assert_eq!(Ok(2), Write::write(&pipe, b"world"));
+            test_read(&pipe, b"llwo");
+        }
+
+        fn write_blocks_until_read() {
+            thread::with_scheduler(|| {
+                let pipe = Arc::new(Pipe::with_capacity(4));
+                Write::write(&*pipe, b"hell").unwrap();
+
+                let d = thread::spawn({
+                    let pipe = pipe.clone();
+                    move || Write::write(&*pipe, b"o").unwrap() as i32
+                });
+
+                test_read(&pipe, b"hell");
+                assert_eq!(1, d.get());
+                test_read(&pipe, b"o");
+            });
+        }
+
+        fn write_fails_once_reader_drops() {
+            let (reader, writer) = Pipe::ends(10);
+            mem::drop(reader);
+            assert_eq!(Err(ErrNum::BrokenPipe), Write::write(&*writer.0, b"hello"));
+        }
+
+        fn poll_reports_each_end() {
+            let (reader, writer) = Pipe::ends(4);
+            let reader: Arc<dyn KObj> = Arc::new(reader);
+            let objs: [Arc<dyn KObj>; 2] = [reader.clone(), Arc::new(writer)];
+            let mut events = [POLL_READ | POLL_WRITE, POLL_READ | POLL_WRITE];
+            assert_eq!(Ok(1), kobj::poll(&objs, &mut events, time::ticks()));
+            assert_eq!([0, POLL_WRITE], events);
+
+            Write::write(objs[1].write().unwrap(), b"hell").unwrap();
+            let mut events = [POLL_READ, POLL_WRITE];
+            assert_eq!(Ok(1), kobj::poll(&objs, &mut events, time::ticks()));
+            assert_eq!([POLL_READ, 0], events);
+
+            mem::drop(objs);
+            let objs = [reader];
+            let mut events = [POLL_READ];
+            assert_eq!(Ok(1), kobj::poll(&objs, &mut events, time::ticks()));
+            assert_eq!([POLL_READ | POLL_HUP], events);
+        }
+
+        fn poll_wakes_on_write() {
+            thread::with_scheduler(|| {
+                let pipe = Arc::new(Pipe::new());
+                let d = thread::spawn({
+                    let pipe = pipe.clone();
+                    move || Write::write(&*pipe, b"hello").unwrap() as i32
+                });
+
+                let objs: [Arc<dyn KObj>; 1] = [pipe.clone()];
+                let mut events = [POLL_READ];
+                assert_eq!(Ok(1), kobj::poll(&objs, &mut events, time::ticks() + 1000));
+                assert_eq!([POLL_READ], events);
+                assert_eq!(5, d.get());
+            });
+        }
     }
 }
@@ -1,12 +1,13 @@
 use crate::deferred::Deferred;
-use crate::io::{AsyncRead, Read, Write};
+use crate::io::{AsyncRead, Channel, MapFile, Read, ReadDir, Seek, Truncate, Write};
 use crate::mutex::UntypedMutex;
+use crate::port::Port;
 use crate::process::{Process, SharedMemBlock};
 use crate::semaphore::Semaphore;
 use alloc::sync::Arc;
 use core::mem;
 use core::ops::Deref;
-use syscall::{ErrNum, Result};
+use syscall::{ErrNum, FileStat, Result, POLL_HUP};
 
 pub trait KObj {
     fn async_read(&self) -> Option<&dyn AsyncRead> {
@@ -18,6 +19,21 @@ pub trait KObj {
     fn write(&self) -> Option<&dyn Write> {
         None
     }
+    fn seek(&self) -> Option<&dyn Seek> {
+        None
+    }
+    fn truncate(&self) -> Option<&dyn Truncate> {
+        None
+    }
+    fn stat(&self) -> Option<FileStat> {
+        None
+    }
+    fn read_dir(&self) -> Option<&dyn ReadDir> {
+        None
+    }
+    fn map_file(&self) -> Option<&dyn MapFile> {
+        None
+    }
     fn deferred_i32(&self) -> Option<Deferred<i32>> {
         None
     }
@@ -33,6 +49,78 @@ pub trait KObj {
     fn semaphore(&self) -> Option<&Semaphore> {
         None
     }
+    fn port(&self) -> Option<&Port> {
+        None
+    }
+    fn channel(&self) -> Option<&Channel> {
+        None
+    }
+    /// Returns a deferred that is resolved once the object is ready, for instance once a read won't block.
+    fn ready(&self) -> Option<Deferred<()>> {
+        None
+    }
+    /// Returns the `POLL_` flags that apply to the object now, and a deferred that is resolved once they might have
+    /// changed.
+    fn poll(&self) -> Option<(u32, Deferred<()>)> {
+        None
+    }
+}
+
+/// Blocks until any of `objs` is ready, or until the tick count reaches `deadline`. Returns the index of the object
+/// that was ready first.
+///
+/// Processes and threads are ready once they have exited.
+pub fn wait_for_any(objs: &[Arc<dyn KObj>], deadline: usize) -> Result<usize> {
+    let any = Deferred::new();
+    for (index, kobj) in objs.iter().enumerate() {
+        if let Some(d) = kobj.deferred_i32() {
+            d.notify(&any, index);
+        } else if let Some(d) = kobj.ready() {
+            d.notify(&any, index);
+        } else {
+            return Err(ErrNum::NotSupported);
+        }
+    }
+
+    any.get_timeout(deadline).map_err(|_| ErrNum::TimedOut)
+}
+
+/// Blocks until any of `objs` has one of the `POLL_` flags asked for in `events`, or has hung up, or until the tick
+/// count reaches `deadline`. Sets each entry of `events` to the flags that were found, and returns how many objects
+/// had any.
+pub fn poll(objs: &[Arc<dyn KObj>], events: &mut [u32], deadline: usize) -> Result<usize> {
+    assert_eq!(objs.len(), events.len());
+
+    let wanted = events.to_vec();
+    loop {
+        let any = Deferred::new();
+        let mut count = 0;
+        for (index, kobj) in objs.iter().enumerate() {
+            let state = if let Some(d) = kobj.deferred_i32() {
+                d.notify(&any, index);
+                // Somebody could have taken the exit code already, by waiting for it
+                if d.has_resolved() {
+                    POLL_HUP
+                } else {
+                    0
+                }
+            } else if let Some((state, changed)) = kobj.poll() {
+                changed.notify(&any, index);
+                state
+            } else {
+                return Err(ErrNum::NotSupported);
+            };
+
+            events[index] = state & (wanted[index] | POLL_HUP);
+            if events[index] != 0 {
+                count += 1;
+            }
+        }
+
+        if count > 0 || any.get_timeout(deadline).is_err() {
+            return Ok(count);
+        }
+    }
 }
 
 pub struct KObjRef<T: ?Sized> {
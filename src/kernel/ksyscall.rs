@@ -1,19 +1,35 @@
 use crate::arch::ps2_mouse::Ps2Mouse;
 use crate::arch::thread as arch_thread;
 use crate::arch::vga_bochs;
-use crate::io::Pipe;
-use crate::kobj::KObj;
+use crate::fs;
+use crate::io::{Channel, Message, Pipe};
+use crate::kobj::{self, KObj};
 use crate::mutex::UntypedMutex;
-use crate::phys_mem;
+use crate::port;
 use crate::prelude::*;
 use crate::process::{self, SharedMemBlock};
 use crate::semaphore::Semaphore;
 use crate::singleton::{DropSingleton, Singleton};
 use crate::thread;
+use crate::time;
 use alloc::sync::Arc;
+use core::cmp;
+use core::mem;
 use core::result;
 use core::str::Utf8Error;
-use syscall::{self, ErrNum, Handle, HandleSyscall, PackedArgs, Result};
+use syscall::{
+    self, ErrNum, FileStat, Handle, HandleSyscall, PackedArgs, Result, SeekFrom, MAX_USER_PRIORITY, PIPE_CAPACITY,
+    POLL_HUP, POLL_READ, POLL_WRITE,
+};
+
+/// Fails with `ErrNum::WouldBlock` if `file` can be polled and has neither `event` nor `POLL_HUP` set. Objects that
+/// can't be polled, such as files, never block for long.
+fn would_block(file: Handle, event: u32) -> Result<()> {
+    match process::resolve_handle_obj(file)?.poll() {
+        Some((state, _)) if state & (event | POLL_HUP) == 0 => Err(ErrNum::WouldBlock),
+        _ => Ok(()),
+    }
+}
 
 pub struct SyscallHandler {
     mouse: Arc<Ps2Mouse>,
@@ -71,11 +87,33 @@ impl HandleSyscall for SyscallHandler {
     }
 
     fn write(&self, file: Handle, bytes: &[u8]) -> Result<usize> {
+        if process::is_non_blocking(file) {
+            would_block(file, POLL_WRITE)?;
+        }
+
         let file = process::resolve_handle_ref(file, |kobj| kobj.write())?;
         file.write(bytes)
     }
 
     fn read(&self, file: Handle, buf: &mut [u8]) -> Result<usize> {
+        if process::is_non_blocking(file) {
+            if let Ok(file) = process::resolve_handle_ref(file, |kobj| kobj.async_read()) {
+                let v = match file.read_async(vec![0; buf.len()]).try_get() {
+                    Ok(result) => result?,
+                    Err(promise) => {
+                        mem::drop(promise);
+                        file.cancel_abandoned();
+                        return Err(ErrNum::WouldBlock);
+                    }
+                };
+
+                buf[..v.len()].copy_from_slice(&v);
+                return Ok(v.len());
+            }
+
+            would_block(file, POLL_READ)?;
+        }
+
         let file = process::resolve_handle_ref(file, |kobj| kobj.read())?;
         file.read(buf)
     }
@@ -94,7 +132,7 @@ impl HandleSyscall for SyscallHandler {
     fn open(&self, filename: result::Result<&str, Utf8Error>) -> Result<Handle> {
         let file: Arc<dyn KObj> = match filename? {
             "ps2_mouse" => self.mouse.clone(),
-            _ => return Err(ErrNum::FileNotFound),
+            filename => fs::open(filename)?,
         };
 
         Ok(process::make_handle(file))
@@ -113,19 +151,25 @@ impl HandleSyscall for SyscallHandler {
         Ok(slice.as_mut_ptr())
     }
 
-    fn spawn_process(&self, executable: result::Result<&str, Utf8Error>, inherit: &[Handle]) -> Result<Handle> {
+    fn spawn_process(
+        &self,
+        executable: result::Result<&str, Utf8Error>,
+        args: &[u8],
+        inherit: &[Handle],
+    ) -> Result<Handle> {
+        let args = process::Args::parse(args)?;
         let handles = inherit
             .iter()
             .map(|&handle| Ok(Some(process::resolve_handle_obj(handle)?)))
             .collect::<Result<Vec<_>>>()?;
 
-        let process = process::spawn(String::from(executable?), handles)?;
+        let process = process::spawn(String::from(executable?), args, handles)?;
         Ok(process::make_handle(process))
     }
 
     fn wait_for_exit(&self, process: Handle) -> Result<i32> {
         let deferred = process::resolve_handle(process, |kobj| kobj.deferred_i32())?;
-        Ok(deferred.get())
+        deferred.get_timeout(usize::MAX).map_err(|_| ErrNum::Interrupted)
     }
 
     fn create_shared_mem(&self) -> Handle {
@@ -154,8 +198,7 @@ impl HandleSyscall for SyscallHandler {
 
     fn lock_mutex(&self, mutex: Handle) -> Result<()> {
         let mutex = process::resolve_handle_ref(mutex, |kobj| kobj.mutex())?;
-        unsafe { mutex.lock_unsafe() }
-        Ok(())
+        unsafe { mutex.lock_timeout_unsafe(usize::MAX) }
     }
 
     fn unlock_mutex(&self, mutex: Handle) -> Result<()> {
@@ -163,23 +206,24 @@ impl HandleSyscall for SyscallHandler {
         unsafe { mutex.unlock_unsafe() }
     }
 
-    fn spawn_thread(&self, entry: extern "C" fn(usize), context: usize) -> Handle {
-        let kernel_entry = move || {
-            let stack_slice = process::alloc::<u8>(phys_mem::PAGE_SIZE * 10, true, true).unwrap();
+    fn spawn_thread(&self, entry: extern "C" fn(usize), context: usize, stack_size: usize) -> Result<Handle> {
+        if stack_size == 0 {
+            return Err(ErrNum::InvalidArgument);
+        }
 
+        let stack_slice = process::alloc_stack(stack_size)?;
+        let kernel_entry = move || {
             if let Some(tls) = process::alloc_tls() {
                 thread::set_tls(tls);
             }
 
-            unsafe {
-                let rsp = stack_slice.as_mut_ptr().offset(stack_slice.len() as isize);
-                arch_thread::jmp_user_mode(entry as *const u8, rsp, context)
-            }
-            // TODO: free stack
+            let rsp = unsafe { stack_slice.as_mut_ptr().offset(stack_slice.len() as isize) };
+            thread::set_user_stack(stack_slice);
+            unsafe { arch_thread::jmp_user_mode(entry as *const u8, rsp, context, 0) }
         };
 
         let thread = thread::spawn(kernel_entry);
-        process::make_handle(Arc::new(thread))
+        Ok(process::make_handle(Arc::new(thread)))
     }
 
     fn schedule(&self) {
@@ -201,12 +245,246 @@ impl HandleSyscall for SyscallHandler {
 
     fn wait_semaphore(&self, semaphore: Handle) -> Result<()> {
         let semaphore = process::resolve_handle_ref(semaphore, |kobj| kobj.semaphore())?;
-        semaphore.wait();
-        Ok(())
+        semaphore.wait_timeout(usize::MAX)
     }
 
     fn post_semaphore(&self, semaphore: Handle) -> Result<()> {
         let semaphore = process::resolve_handle_ref(semaphore, |kobj| kobj.semaphore())?;
         semaphore.post()
     }
+
+    fn sleep(&self, ns: u64) {
+        thread::sleep_until(time::deadline_after(ns));
+    }
+
+    fn now(&self) -> u64 {
+        time::now()
+    }
+
+    fn wait_semaphore_timeout(&self, semaphore: Handle, timeout_ns: u64) -> Result<()> {
+        let deadline = time::deadline_after(timeout_ns);
+        let semaphore = process::resolve_handle_ref(semaphore, |kobj| kobj.semaphore())?;
+        semaphore.wait_timeout(deadline)
+    }
+
+    fn lock_mutex_timeout(&self, mutex: Handle, timeout_ns: u64) -> Result<()> {
+        let deadline = time::deadline_after(timeout_ns);
+        let mutex = process::resolve_handle_ref(mutex, |kobj| kobj.mutex())?;
+        unsafe { mutex.lock_timeout_unsafe(deadline) }
+    }
+
+    fn wait_for_exit_timeout(&self, process: Handle, timeout_ns: u64) -> Result<i32> {
+        let deadline = time::deadline_after(timeout_ns);
+        let deferred = process::resolve_handle(process, |kobj| kobj.deferred_i32())?;
+        deferred.get_timeout(deadline).map_err(|_| ErrNum::TimedOut)
+    }
+
+    fn read_timeout(&self, file: Handle, buf: &mut [u8], timeout_ns: u64) -> Result<usize> {
+        let deadline = time::deadline_after(timeout_ns);
+        let file = process::resolve_handle_ref(file, |kobj| kobj.async_read())?;
+        let v = match file.read_async(vec![0; buf.len()]).get_timeout(deadline) {
+            Ok(result) => result?,
+            Err(promise) => {
+                mem::drop(promise);
+                file.cancel_abandoned();
+                return Err(ErrNum::TimedOut);
+            }
+        };
+
+        buf[..v.len()].copy_from_slice(&v);
+        Ok(v.len())
+    }
+
+    fn wait_for_any(&self, handles: &[Handle], timeout_ns: u64) -> Result<usize> {
+        let deadline = time::deadline_after(timeout_ns);
+        let objs = handles
+            .iter()
+            .map(|&handle| process::resolve_handle_obj(handle))
+            .collect::<Result<Vec<_>>>()?;
+
+        kobj::wait_for_any(&objs, deadline)
+    }
+
+    fn seek(&self, file: Handle, pos: Result<SeekFrom>) -> Result<u64> {
+        let file = process::resolve_handle_ref(file, |kobj| kobj.seek())?;
+        file.seek(pos?)
+    }
+
+    fn stat(&self, file: Handle, stat: &mut FileStat) -> Result<()> {
+        *stat = process::resolve_handle(file, |kobj| kobj.stat())?;
+        Ok(())
+    }
+
+    fn read_dir(&self, dir: Handle, buf: &mut [u8]) -> Result<usize> {
+        let dir = process::resolve_handle_ref(dir, |kobj| kobj.read_dir())?;
+        dir.read_dir(buf)
+    }
+
+    fn create(&self, filename: result::Result<&str, Utf8Error>) -> Result<Handle> {
+        let file = fs::create(filename?)?;
+        Ok(process::make_handle(file))
+    }
+
+    fn truncate(&self, file: Handle, len: u64) -> Result<()> {
+        let file = process::resolve_handle_ref(file, |kobj| kobj.truncate())?;
+        file.truncate(len)
+    }
+
+    fn unlink(&self, filename: result::Result<&str, Utf8Error>) -> Result<()> {
+        fs::unlink(filename?)
+    }
+
+    fn mkdir(&self, filename: result::Result<&str, Utf8Error>) -> Result<()> {
+        fs::mkdir(filename?)
+    }
+
+    fn kill_process(&self, process: Handle, code: i32) -> Result<()> {
+        let process = process::resolve_handle_ref(process, |kobj| kobj.process())?;
+        process.kill(code);
+        Ok(())
+    }
+
+    fn exit_process(&self, code: i32, message: result::Result<&str, Utf8Error>) -> ! {
+        thread::current_process().exit(code, message.unwrap_or(""));
+        thread::exit(code)
+    }
+
+    fn exit_message(&self, process: Handle, buf: &mut [u8]) -> Result<usize> {
+        let process = process::resolve_handle_ref(process, |kobj| kobj.process())?;
+        let message = process.exit_message();
+        let len = cmp::min(buf.len(), message.len());
+        buf[..len].copy_from_slice(&message.as_bytes()[..len]);
+        Ok(len)
+    }
+
+    fn set_thread_priority(&self, priority: u8) -> Result<()> {
+        if priority > MAX_USER_PRIORITY {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        thread::set_priority(priority)
+    }
+
+    fn fork_process(&self, entry: extern "C" fn(usize), context: usize) -> Result<Handle> {
+        let process = process::fork(entry as *const u8, context)?;
+        Ok(process::make_handle(process))
+    }
+
+    fn map_file(&self, file: Handle, offset: u64, len: usize, writable: bool) -> Result<*mut u8> {
+        let file = process::resolve_handle_ref(file, |kobj| kobj.map_file())?;
+        let slice = process::map_file(file, offset, len, true, writable)?;
+        Ok(slice.as_mut_ptr())
+    }
+
+    fn reserve_pages(&self, ptr: *mut u8, len: usize) -> Result<*mut u8> {
+        let ptr = if ptr.is_null() { None } else { Some(ptr) };
+        let slice = process::reserve(ptr, len)?;
+        Ok(slice.as_mut_ptr())
+    }
+
+    fn protect_pages(&self, ptr: *mut u8, len: usize, flags: u32) -> Result<()> {
+        if flags & !(syscall::PROT_READ | syscall::PROT_WRITE | syscall::PROT_EXEC) != 0 {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        let writable = flags & syscall::PROT_WRITE != 0;
+        let executable = flags & syscall::PROT_EXEC != 0;
+        let readable = writable || executable || flags & syscall::PROT_READ != 0;
+        process::protect_pages(ptr, len, readable, writable, executable)
+    }
+
+    fn register_port(&self, name: result::Result<&str, Utf8Error>) -> Result<Handle> {
+        let port = port::register(name?)?;
+        Ok(process::make_handle(port))
+    }
+
+    fn connect_port(&self, name: result::Result<&str, Utf8Error>) -> Result<Handle> {
+        let channel = port::connect(name?)?;
+        Ok(process::make_handle(Arc::new(channel)))
+    }
+
+    fn accept_port(&self, port: Handle) -> Result<Handle> {
+        let port = process::resolve_handle_ref(port, |kobj| kobj.port())?;
+        Ok(process::make_handle(Arc::new(port.accept()?)))
+    }
+
+    fn create_channel(&self, ends: &mut [Handle]) -> Result<()> {
+        if ends.len() != 2 {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        let (a, b) = Channel::pair();
+        ends[0] = process::make_handle(Arc::new(a));
+        ends[1] = process::make_handle(Arc::new(b));
+        Ok(())
+    }
+
+    fn send_message(&self, channel: Handle, data: &[u8], handles: &[Handle]) -> Result<()> {
+        // An empty message is how `receive_message` says that the channel has been closed
+        if data.is_empty() && handles.is_empty() {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        let channel = process::resolve_handle_ref(channel, |kobj| kobj.channel())?;
+        process::take_handles(handles, |handles| {
+            channel.send(Message {
+                data: data.to_vec(),
+                handles,
+            })
+        })
+    }
+
+    fn receive_message(
+        &self,
+        channel: Handle,
+        buf: &mut [u8],
+        handles: &mut [Handle],
+        handle_count: &mut usize,
+    ) -> Result<usize> {
+        let channel = process::resolve_handle_ref(channel, |kobj| kobj.channel())?;
+        let message = match channel.receive(buf.len(), handles.len())? {
+            Some(message) => message,
+            None => {
+                *handle_count = 0;
+                return Ok(0);
+            }
+        };
+
+        buf[..message.data.len()].copy_from_slice(&message.data);
+        for (handle, kobj) in handles.iter_mut().zip(message.handles.iter()) {
+            *handle = process::make_handle(kobj.clone());
+        }
+
+        *handle_count = message.handles.len();
+        Ok(message.data.len())
+    }
+
+    fn create_pipe_ends(&self, capacity: usize, ends: &mut [Handle]) -> Result<()> {
+        if capacity == 0 || capacity > PIPE_CAPACITY || ends.len() != 2 {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        let (reader, writer) = Pipe::ends(capacity);
+        ends[0] = process::make_handle(Arc::new(reader));
+        ends[1] = process::make_handle(Arc::new(writer));
+        Ok(())
+    }
+
+    fn set_non_blocking(&self, handle: Handle, non_blocking: bool) -> Result<()> {
+        process::set_non_blocking(handle, non_blocking)
+    }
+
+    fn poll(&self, handles: &[Handle], events: &mut [u32], timeout_ns: u64) -> Result<usize> {
+        if handles.len() != events.len() {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        let deadline = time::deadline_after(timeout_ns);
+        let objs = handles
+            .iter()
+            .map(|&handle| process::resolve_handle_obj(handle))
+            .collect::<Result<Vec<_>>>()?;
+
+        kobj::poll(&objs, events, deadline)
+    }
 }
@@ -15,6 +15,7 @@
 #![feature(link_args)]
 #![feature(panic_info_message)]
 #![feature(start)]
+#![feature(try_reserve)]
 #![no_std]
 #![cfg_attr(target_arch = "arm", allow(dead_code))]
 #![cfg_attr(target_arch = "arm", allow(unused_imports))]
@@ -41,6 +42,8 @@ mod arch;
 mod deferred;
 mod elf;
 #[cfg(not(target_arch = "arm"))]
+mod fs;
+#[cfg(not(target_arch = "arm"))]
 mod io;
 #[cfg(not(target_arch = "arm"))]
 mod kobj;
@@ -52,6 +55,8 @@ mod logging;
 mod mutex;
 mod once;
 mod phys_mem;
+#[cfg(not(target_arch = "arm"))]
+mod port;
 mod prelude;
 #[cfg(not(target_arch = "arm"))]
 mod process;
@@ -62,6 +67,8 @@ mod singleton;
 mod tar;
 #[cfg(not(target_arch = "arm"))]
 mod thread;
+#[cfg(not(target_arch = "arm"))]
+mod time;
 mod unwind;
 mod virt_mem;
 
@@ -76,12 +83,18 @@ fn run_tests() {
     const TEST_FIXTURES: &'static [Fixture] = &[
         ptr::test::TESTS,
         #[cfg(not(target_arch = "arm"))]
+        arch::acpi::test::TESTS,
+        #[cfg(not(target_arch = "arm"))]
         arch::isr::test::TESTS,
         #[cfg(not(target_arch = "arm"))]
         arch::mmu::test::TESTS,
         #[cfg(not(target_arch = "arm"))]
         arch::phys_mem::test::TESTS,
         #[cfg(not(target_arch = "arm"))]
+        fs::test::TESTS,
+        #[cfg(not(target_arch = "arm"))]
+        io::channel::test::TESTS,
+        #[cfg(not(target_arch = "arm"))]
         io::pipe::test::TESTS,
         phys_mem::test::TESTS,
         virt_mem::test::TESTS,
@@ -90,6 +103,8 @@ fn run_tests() {
         #[cfg(not(target_arch = "arm"))]
         mutex::test::TESTS,
         #[cfg(not(target_arch = "arm"))]
+        port::test::TESTS,
+        #[cfg(not(target_arch = "arm"))]
         process::test::TESTS,
         #[cfg(not(target_arch = "arm"))]
         semaphore::test::TESTS,
@@ -118,7 +133,10 @@ fn run_tests() {}
 pub unsafe fn kmain() -> ! {
     #[cfg(not(target_arch = "arm"))]
     arch::isr::init_once();
+    phys_mem::init();
     libc_helpers::init();
+    #[cfg(not(target_arch = "arm"))]
+    arch::smp::init();
     run_tests();
     loop {
         arch::cpu::wait_for_interrupt();
@@ -1,7 +1,10 @@
 use crate::kobj::KObj;
 use crate::spin;
 use crate::thread::{self, BlockedThread};
+use crate::time;
 use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
+use core::cell::Cell;
 use syscall::{ErrNum, Result};
 
 struct UntypedMutexState {
@@ -10,7 +13,7 @@ struct UntypedMutexState {
 }
 
 pub struct UntypedMutex {
-    state: spin::Mutex<UntypedMutexState>,
+    state: Arc<spin::Mutex<UntypedMutexState>>,
 }
 
 unsafe impl Send for UntypedMutex {}
@@ -19,10 +22,10 @@ unsafe impl Sync for UntypedMutex {}
 impl UntypedMutex {
     pub fn new() -> Self {
         UntypedMutex {
-            state: spin::Mutex::new(UntypedMutexState {
+            state: Arc::new(spin::Mutex::new(UntypedMutexState {
                 waiters: VecDeque::new(),
                 locked: false,
-            }),
+            })),
         }
     }
 
@@ -37,6 +40,41 @@ impl UntypedMutex {
         }
     }
 
+    /// Like `lock_unsafe`, but gives up with `ErrNum::TimedOut` once the tick count reaches `deadline`, or with
+    /// `ErrNum::Interrupted` if the current process is killed.
+    pub unsafe fn lock_timeout_unsafe(&self, deadline: usize) -> Result<()> {
+        loop {
+            let mut state = lock!(self.state);
+            if !state.locked {
+                state.locked = true;
+                return Ok(());
+            }
+
+            if time::ticks() >= deadline {
+                return Err(ErrNum::TimedOut);
+            }
+
+            let parked = Cell::new(false);
+            let cancel_state = self.state.clone();
+            let result = thread::block_until(
+                deadline,
+                {
+                    let parked = &parked;
+                    move |thread| {
+                        parked.set(true);
+                        state.waiters.push_back(thread);
+                    }
+                },
+                move |id| thread::remove_waiter(&mut lock!(cancel_state).waiters, id),
+            );
+
+            // `unlock_unsafe` hands the lock straight to the thread it wakes
+            if parked.get() || result == Err(ErrNum::Interrupted) {
+                return result;
+            }
+        }
+    }
+
     pub unsafe fn unlock_unsafe(&self) -> Result<()> {
         let mut state = lock!(self.state);
         if !state.locked {
@@ -58,6 +96,13 @@ impl UntypedMutex {
         }
         UntypedMutexGuard::new(self)
     }
+
+    pub fn lock_timeout(&self, deadline: usize) -> Result<UntypedMutexGuard> {
+        unsafe {
+            self.lock_timeout_unsafe(deadline)?;
+        }
+        Ok(UntypedMutexGuard::new(self))
+    }
 }
 
 impl KObj for UntypedMutex {
@@ -128,5 +173,26 @@ pub mod test {
                 let _ = write!(logging::Writer, "\n");
             });
         }
+
+        fn lock_timeout_times_out() {
+            thread::with_scheduler(|| {
+                let m = Arc::new(UntypedMutex::new());
+                let _g: UntypedMutexGuard = m.lock();
+
+                let d = thread::spawn({
+                    let m = m.clone();
+                    move || m.lock_timeout(time::ticks() + 2).map(|_| ())
+                });
+
+                assert_eq!(Err(ErrNum::TimedOut), d.get());
+            });
+        }
+
+        fn lock_timeout_succeeds_when_unlocked() {
+            thread::with_scheduler(|| {
+                let m = UntypedMutex::new();
+                let _g: UntypedMutexGuard = m.lock_timeout(time::ticks() + 2).expect("lock_timeout returned Err");
+            });
+        }
     }
 }
@@ -2,12 +2,13 @@ use crate::arch;
 use crate::arch::phys_mem;
 use crate::ptr::{self, Align, PointerInSlice};
 use crate::spin::Mutex;
+use alloc::sync::Arc;
 use bit_vec::BitVec;
 use core::cmp;
 use core::intrinsics;
 use core::mem;
 use core::slice;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use libc::c_void;
 use syscall::{ErrNum, Result};
 
@@ -18,6 +19,7 @@ extern "C" {
     static heap_end: u8;
 }
 
+#[cfg(target_arch = "arm")]
 pub unsafe fn resize_kernel_heap(delta: isize) -> *mut u8 {
     static mut BRK: usize = 0;
     let begin = (&mut heap_start as *mut u8).offset(BRK as isize);
@@ -26,13 +28,142 @@ pub unsafe fn resize_kernel_heap(delta: isize) -> *mut u8 {
     begin
 }
 
+/// Where the kernel heap has got to. Only touched from `sbrk`, which newlib calls with the malloc lock held.
+#[cfg(not(target_arch = "arm"))]
+struct KernelHeap {
+    brk: usize,
+    mapped_pages: usize,
+    /// Pages at the bottom of the heap that came from the linker's `heap_start..heap_end` region, which was set aside
+    /// for the heap to use before the machine's bitmap exists. They stay mapped for good.
+    boot_pages: usize,
+    next_boot_addr: usize,
+}
+
+#[cfg(not(target_arch = "arm"))]
+static mut HEAP: KernelHeap = KernelHeap {
+    brk: 0,
+    mapped_pages: 0,
+    boot_pages: 0,
+    next_boot_addr: 0,
+};
+
+/// Number of pages, including page tables, that the kernel heap has taken from the machine's bitmap.
+static HEAP_BITMAP_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once the machine's bitmap has been built; the heap can't build it itself, since that allocates memory.
+static MACHINE_BITMAP: AtomicPtr<PhysicalBitmap> = AtomicPtr::new(0 as *mut PhysicalBitmap);
+
+#[cfg(not(target_arch = "arm"))]
+unsafe fn alloc_heap_page(heap: &mut KernelHeap) -> Option<usize> {
+    if heap.next_boot_addr == 0 {
+        heap.next_boot_addr = virt2phys(&heap_start);
+    }
+
+    if heap.next_boot_addr < virt2phys(&heap_end) {
+        let addr = heap.next_boot_addr;
+        heap.next_boot_addr += PAGE_SIZE;
+        return Some(addr);
+    }
+
+    let bitmap = MACHINE_BITMAP.load(Ordering::SeqCst).as_ref()?;
+    let addr = bitmap.alloc_page().ok()?;
+    HEAP_BITMAP_PAGES.fetch_add(1, Ordering::SeqCst);
+    Some(addr)
+}
+
+#[cfg(not(target_arch = "arm"))]
+unsafe fn is_boot_heap_page(addr: usize) -> bool {
+    addr >= virt2phys(&heap_start) && addr < virt2phys(&heap_end)
+}
+
+/// Gives a page back to the machine's bitmap, unless it's one of the boot pages, which aren't in the bitmap.
+#[cfg(not(target_arch = "arm"))]
+unsafe fn free_heap_page(addr: usize) {
+    if !is_boot_heap_page(addr) {
+        (*MACHINE_BITMAP.load(Ordering::SeqCst)).free_page(addr);
+        HEAP_BITMAP_PAGES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Grows or shrinks the kernel heap by mapping pages from the machine's bitmap, and gives pages back when it shrinks.
+/// Returns the old end of the heap, or -1 when there's no memory left, which is what newlib expects from `sbrk`.
+#[cfg(not(target_arch = "arm"))]
+pub unsafe fn resize_kernel_heap(delta: isize) -> *mut u8 {
+    use crate::arch::mmu;
+
+    let heap = &mut HEAP;
+    let old_brk = heap.brk;
+    let new_brk = (old_brk as isize + delta) as usize;
+    if new_brk > mmu::HEAP_MAX_LEN {
+        return usize::max_value() as *mut u8;
+    }
+
+    while heap.mapped_pages * PAGE_SIZE < new_brk {
+        let addr = match alloc_heap_page(heap) {
+            Some(addr) => addr,
+            None => return usize::max_value() as *mut u8,
+        };
+
+        // Any page tables come from the same place as the heap's own pages
+        let mapped = mmu::map_heap_page(heap.mapped_pages, addr, &mut || alloc_heap_page(heap));
+        if !mapped {
+            free_heap_page(addr);
+            return usize::max_value() as *mut u8;
+        }
+
+        // The boot pages run out before the heap takes anything from the bitmap, so they're always at the bottom
+        if is_boot_heap_page(addr) {
+            heap.boot_pages += 1;
+        }
+
+        heap.mapped_pages += 1;
+    }
+
+    while heap.mapped_pages > heap.boot_pages && (heap.mapped_pages - 1) * PAGE_SIZE >= new_brk {
+        heap.mapped_pages -= 1;
+        free_heap_page(mmu::unmap_heap_page(heap.mapped_pages));
+    }
+
+    heap.brk = new_brk;
+    (mmu::KADDR_HEAP + old_brk) as *mut u8
+}
+
+/// Returns how much memory the kernel heap has taken from the machine's bitmap.
+pub fn heap_bytes() -> usize {
+    HEAP_BITMAP_PAGES.load(Ordering::SeqCst) * PAGE_SIZE
+}
+
+lazy_static! {
+    static ref MACHINE: Arc<PhysicalBitmap> = {
+        let bitmap = Arc::new(phys_mem::machine());
+        let ptr = &*bitmap as *const PhysicalBitmap as *mut PhysicalBitmap;
+        MACHINE_BITMAP.store(ptr, Ordering::SeqCst);
+        bitmap
+    };
+}
+
+/// Builds the machine's bitmap, so that the kernel heap can grow past the memory the linker set aside for it.
+pub fn init() {
+    lazy_static::initialize(&MACHINE);
+}
+
+const NO_CPU: usize = usize::max_value();
+
+/// newlib takes the malloc lock recursively, so the lock remembers which CPU holds it and how many times.
+static MALLOC_OWNER: AtomicUsize = AtomicUsize::new(NO_CPU);
 static MALLOC_LOCK: AtomicUsize = AtomicUsize::new(0);
 static mut MALLOC_LOCK_TOKEN: usize = 0;
 
 #[no_mangle]
 pub extern "C" fn __malloc_lock(_reent: *mut c_void) {
     let token = arch::disable_interrupts();
-    // TODO: multi CPU
+    let cpu = arch::cpu_index();
+    if MALLOC_OWNER.load(Ordering::SeqCst) != cpu {
+        while MALLOC_OWNER.compare_and_swap(NO_CPU, cpu, Ordering::SeqCst) != NO_CPU {
+            arch::spin_wait();
+        }
+    }
+
     if MALLOC_LOCK.fetch_add(1, Ordering::SeqCst) == 0 {
         unsafe {
             MALLOC_LOCK_TOKEN = token;
@@ -42,9 +173,9 @@ pub extern "C" fn __malloc_lock(_reent: *mut c_void) {
 
 #[no_mangle]
 pub extern "C" fn __malloc_unlock(_reent: *mut c_void) {
-    // TODO: multi CPU
     if MALLOC_LOCK.fetch_sub(1, Ordering::SeqCst) == 1 {
         let token = mem::replace(unsafe { &mut MALLOC_LOCK_TOKEN }, 0);
+        MALLOC_OWNER.store(NO_CPU, Ordering::SeqCst);
         arch::restore_interrupts(token);
     }
 }
@@ -61,8 +192,9 @@ impl PhysicalBitmap {
         PhysicalBitmap { free: Mutex::new(free) }
     }
 
-    pub fn machine() -> PhysicalBitmap {
-        phys_mem::machine()
+    /// Returns the bitmap for all of the machine's memory. There's only one, shared with the kernel heap.
+    pub fn machine() -> Arc<PhysicalBitmap> {
+        MACHINE.clone()
     }
 
     pub fn reserve_pages(&self, start_page: usize, page_count: usize) {
@@ -0,0 +1,174 @@
+//! Named ports, which let processes that don't share any handles find each other.
+
+use crate::deferred::Deferred;
+use crate::io::Channel;
+use crate::kobj::KObj;
+use crate::prelude::*;
+use crate::spin::Mutex;
+use crate::thread::{self, BlockedThread};
+use alloc::collections::vec_deque::VecDeque;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use core::mem;
+use syscall::{ErrNum, Result};
+
+lazy_static! {
+    static ref PORTS: Mutex<BTreeMap<String, Weak<Port>>> = Mutex::new(BTreeMap::new());
+}
+
+struct PortState {
+    pending: VecDeque<Channel>,
+    waiters: VecDeque<BlockedThread>,
+    ready: Deferred<()>,
+}
+
+/// Held by the process that registered a name. The name is free again once the port is dropped.
+pub struct Port {
+    name: String,
+    state: Arc<Mutex<PortState>>,
+}
+
+unsafe impl Send for Port {}
+unsafe impl Sync for Port {}
+
+impl Port {
+    /// Blocks until a client connects, and returns the server's end of the new channel. Fails with
+    /// `ErrNum::Interrupted` if the current process is killed first.
+    pub fn accept(&self) -> Result<Channel> {
+        loop {
+            let mut state = lock!(self.state);
+            if let Some(channel) = state.pending.pop_front() {
+                return Ok(channel);
+            }
+
+            let port_state = self.state.clone();
+            thread::block_until(
+                usize::MAX,
+                move |thread| {
+                    state.waiters.push_back(thread);
+                },
+                move |id| thread::remove_waiter(&mut lock!(port_state).waiters, id),
+            )?;
+        }
+    }
+
+    fn connect(&self) -> Channel {
+        let (client, server) = Channel::pair();
+        let mut state = lock!(self.state);
+        state.pending.push_back(server);
+
+        if let Some(thread) = state.waiters.pop_front() {
+            thread.resume();
+        }
+
+        let ready = mem::replace(&mut state.ready, Deferred::new());
+        mem::drop(state);
+        ready.resolve(());
+        client
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        let mut ports = lock!(PORTS);
+
+        // Someone could have registered the name again since the last reference went away
+        if ports.get(&self.name).map_or(false, |port| port.strong_count() == 0) {
+            ports.remove(&self.name);
+        }
+    }
+}
+
+impl KObj for Port {
+    fn port(&self) -> Option<&Port> {
+        Some(self)
+    }
+
+    fn ready(&self) -> Option<Deferred<()>> {
+        let state = lock!(self.state);
+        if state.pending.is_empty() {
+            Some(state.ready.clone())
+        } else {
+            Some(Deferred::resolved(()))
+        }
+    }
+}
+
+/// Claims `name`, failing with `ErrNum::AlreadyExists` if another port has it.
+pub fn register(name: &str) -> Result<Arc<Port>> {
+    let mut ports = lock!(PORTS);
+    // Check the count rather than upgrading, since dropping the last reference here would deadlock in `Port::drop`
+    if ports.get(name).map_or(false, |port| port.strong_count() > 0) {
+        return Err(ErrNum::AlreadyExists);
+    }
+
+    let port = Arc::new(Port {
+        name: name.to_string(),
+        state: Arc::new(Mutex::new(PortState {
+            pending: VecDeque::new(),
+            waiters: VecDeque::new(),
+            ready: Deferred::new(),
+        })),
+    });
+
+    ports.insert(name.to_string(), Arc::downgrade(&port));
+    Ok(port)
+}
+
+/// Connects to the port registered as `name`, and returns the client's end of a new channel. The server gets the
+/// other end from `Port::accept`.
+pub fn connect(name: &str) -> Result<Channel> {
+    let port = lock!(PORTS)
+        .get(name)
+        .and_then(Weak::upgrade)
+        .ok_or(ErrNum::FileNotFound)?;
+    Ok(port.connect())
+}
+
+#[cfg(feature = "test")]
+pub mod test {
+    use super::*;
+    use crate::io::{AsyncRead, Write};
+    use crate::kobj;
+    use crate::time;
+
+    fn read_all(channel: &Channel, len: usize) -> Vec<u8> {
+        channel
+            .read_async(vec![0; len])
+            .try_get()
+            .unwrap_or_else(|_| panic!("didn't expect to block"))
+            .unwrap()
+    }
+
+    test! {
+        fn can_connect_to_port() {
+            let port = register("test_port").unwrap();
+            let client = connect("test_port").unwrap();
+            let server = port.accept().unwrap();
+
+            Write::write(&client, b"hello").unwrap();
+            assert_eq!(b"hello", &read_all(&server, 5)[..]);
+
+            Write::write(&server, b"world").unwrap();
+            assert_eq!(b"world", &read_all(&client, 5)[..]);
+        }
+
+        fn port_is_ready_once_client_connects() {
+            let port = register("test_ready").unwrap();
+            assert!(port.ready().unwrap().try_get().is_err());
+
+            let _client = connect("test_ready").unwrap();
+            let objs: [Arc<dyn KObj>; 1] = [port];
+            assert_eq!(Ok(0), kobj::wait_for_any(&objs, time::ticks()));
+        }
+
+        fn name_is_freed_when_port_is_dropped() {
+            let port = register("test_name").unwrap();
+            assert_eq!(Some(ErrNum::AlreadyExists), register("test_name").err());
+
+            mem::drop(port);
+            assert_eq!(Some(ErrNum::FileNotFound), connect("test_name").err());
+            register("test_name").unwrap();
+        }
+    }
+}
@@ -1,21 +1,26 @@
-use crate::arch::process::ArchProcess;
+use crate::arch::cpu;
+use crate::arch::process::{ArchProcess, USER_END};
+use crate::arch::smp;
 use crate::arch::thread as arch_thread;
 use crate::deferred::Deferred;
 use crate::elf::*;
+use crate::fs;
+use crate::io::{MapFile, SeekFrom};
 use crate::kobj::{KObj, KObjRef};
 use crate::phys_mem::{self, PhysicalBitmap};
 use crate::prelude::*;
 use crate::process;
 use crate::ptr::{self, Align, PointerInSlice};
 use crate::spin::Mutex;
-use crate::tar;
-use crate::thread;
+use crate::thread::{self, BlockedThread};
 use crate::virt_mem::VirtualTree;
+use alloc::collections::BTreeSet;
 use alloc::sync::Arc;
 use core::intrinsics;
 use core::mem;
 use core::num::NonZeroUsize;
 use core::slice;
+use core::str;
 use syscall::{ErrNum, Handle, Result};
 
 macro_rules! try_or_none {
@@ -52,16 +57,28 @@ impl KObj for SharedMemBlock {
 
 struct ProcessState {
     handles: Vec<Option<Arc<dyn KObj>>>,
+    /// Handles whose reads and writes fail with `ErrNum::WouldBlock` instead of waiting.
+    non_blocking: BTreeSet<Handle>,
     exit_code: Deferred<i32>,
     tls: Option<(usize, &'static [u8])>,
+    threads: usize,
+    killed: Option<i32>,
+    exit_message: String,
+    /// Threads blocked in `thread::block_until`, and how to wake each one if the process is killed.
+    parked: Vec<(usize, Arc<dyn Fn(ErrNum)>)>,
 }
 
 impl ProcessState {
     fn new(handles: Vec<Option<Arc<dyn KObj>>>) -> Self {
         ProcessState {
             handles,
+            non_blocking: BTreeSet::new(),
             exit_code: Deferred::new(),
             tls: None,
+            threads: 0,
+            killed: None,
+            exit_message: String::new(),
+            parked: Vec::new(),
         }
     }
 
@@ -79,15 +96,47 @@ impl ProcessState {
             .ok_or(ErrNum::InvalidHandle)
     }
 
+    /// Removes all of `handles` from the table and returns the objects they referred to, or fails without removing
+    /// any of them if one isn't a valid handle.
+    fn take_handles<T>(&mut self, handles: &[Handle], f: impl FnOnce(Vec<Arc<dyn KObj>>) -> Result<T>) -> Result<T> {
+        let mut objs = Vec::with_capacity(handles.len());
+        for (index, &handle) in handles.iter().enumerate() {
+            if handles[..index].contains(&handle) {
+                return Err(ErrNum::InvalidArgument);
+            }
+
+            objs.push(self.resolve_handle_obj(handle)?);
+        }
+
+        let result = f(objs)?;
+        for &handle in handles {
+            self.close_handle(handle);
+        }
+
+        Ok(result)
+    }
+
     fn close_handle(&mut self, handle: Handle) -> bool {
         if let Some(r @ &mut Some(_)) = self.handles.get_mut(handle) {
             *r = None;
+            self.non_blocking.remove(&handle);
             true
         } else {
             false
         }
     }
 
+    fn set_non_blocking(&mut self, handle: Handle, non_blocking: bool) -> Result<()> {
+        self.resolve_handle_obj(handle)?;
+        if non_blocking {
+            self.non_blocking.insert(handle);
+        } else {
+            self.non_blocking.remove(&handle);
+        }
+
+        Ok(())
+    }
+
     fn set_deferred(&mut self, d: Deferred<i32>) {
         self.exit_code = d;
     }
@@ -97,14 +146,145 @@ impl ProcessState {
     }
 }
 
+/// A file mapped into memory. Pages that the file can't hand over as they are get copied the first time they're
+/// touched, and are shared between processes forked from the one that mapped the file.
+pub struct MappedFile {
+    phys: Arc<PhysicalBitmap>,
+    file: KObjRef<dyn MapFile>,
+    offset: u64,
+    copies: Mutex<Vec<Option<NonZeroUsize>>>,
+}
+
+impl MappedFile {
+    fn file_offset(&self, index: usize) -> u64 {
+        self.offset + (index * phys_mem::PAGE_SIZE) as u64
+    }
+
+    /// Returns the page at `index` if it's in memory already.
+    fn page(&self, index: usize) -> Option<usize> {
+        if let Some(addr) = self.file.phys_page(self.file_offset(index)) {
+            return Some(addr);
+        }
+
+        let copies = lock!(self.copies);
+        copies.get(index).and_then(|&addr| addr).map(NonZeroUsize::get)
+    }
+
+    /// Returns the page at `index`, copying it from the file if needed, or `None` if it's past the end of the file.
+    fn load(&self, index: usize) -> Result<Option<usize>> {
+        if let Some(addr) = self.page(index) {
+            return Ok(Some(addr));
+        }
+
+        let addr = self.phys.alloc_zeroed_page()?;
+        let buf: &mut [u8; phys_mem::PAGE_SIZE] = unsafe { phys_mem::phys2virt(addr) };
+        match self.file.read_at(self.file_offset(index), buf) {
+            Ok(len) if len > 0 => (),
+            result => {
+                self.phys.free_page(addr);
+                return result.map(|_| None);
+            }
+        }
+
+        let mut copies = lock!(self.copies);
+        if copies.len() <= index {
+            copies.resize(index + 1, None);
+        }
+
+        // Another CPU might have copied the same page while this one was reading it
+        match copies[index] {
+            Some(other) => {
+                self.phys.free_page(addr);
+                Ok(Some(other.get()))
+            }
+            None => {
+                copies[index] = NonZeroUsize::new(addr);
+                Ok(Some(addr))
+            }
+        }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        for addr in lock!(self.copies).iter().filter_map(|&addr| addr) {
+            self.phys.free_page(addr.get());
+        }
+    }
+}
+
+/// The pages that a block of memory had when its process was forked. The processes on both sides of the fork read
+/// these pages until they write to them, and then each one gets a copy of its own.
+pub struct CowPages {
+    phys: Arc<PhysicalBitmap>,
+    pages: Vec<Option<NonZeroUsize>>,
+    /// The pages from an earlier fork that were still shared when this one happened.
+    parent: Option<Arc<CowPages>>,
+    /// The file that the block was mapped from, for pages that nothing had copied from it yet.
+    file: Option<Arc<MappedFile>>,
+}
+
+impl CowPages {
+    fn page(&self, index: usize) -> Option<usize> {
+        match self.pages.get(index) {
+            Some(&Some(addr)) => Some(addr.get()),
+            _ => match self.parent {
+                Some(ref parent) => parent.page(index),
+                None => self.file.as_ref()?.page(index),
+            },
+        }
+    }
+
+    fn load(&self, index: usize) -> Result<Option<usize>> {
+        match self.pages.get(index) {
+            Some(&Some(addr)) => Ok(Some(addr.get())),
+            _ => match (&self.parent, &self.file) {
+                (Some(parent), _) => parent.load(index),
+                (None, Some(file)) => file.load(index),
+                (None, None) => Ok(None),
+            },
+        }
+    }
+}
+
+impl Drop for CowPages {
+    fn drop(&mut self) {
+        for addr in self.pages.iter().filter_map(|&addr| addr) {
+            self.phys.free_page(addr.get());
+        }
+    }
+}
+
 #[derive(Clone)]
 enum Pager {
     Zeroed,
     Physical(usize),
     Shared(KObjRef<SharedMemBlock>),
+    CopyOnWrite(Arc<CowPages>),
+    File(Arc<MappedFile>),
 }
 
 impl Pager {
+    /// Returns the page that's shared with other blocks at `index`, for pagers whose pages are only copied once
+    /// they're written to.
+    fn shared_page(&self, index: usize) -> Option<usize> {
+        match self {
+            &Pager::CopyOnWrite(ref cow) => cow.page(index),
+            &Pager::File(ref file) => file.page(index),
+            _ => None,
+        }
+    }
+
+    /// Like `shared_page`, but copies the page from the file if it hasn't been already. Returns `None` for other
+    /// pagers.
+    fn load_shared_page(&self, index: usize) -> Option<Result<Option<usize>>> {
+        match self {
+            &Pager::CopyOnWrite(ref cow) => Some(cow.load(index)),
+            &Pager::File(ref file) => Some(file.load(index)),
+            _ => None,
+        }
+    }
+
     fn alloc<AllocPage: Fn() -> Option<usize>>(&self, offset: usize, alloc_page: AllocPage) -> Option<(bool, usize)> {
         let result = match self {
             &Pager::Zeroed => (true, try_or_none!(alloc_page())),
@@ -127,6 +307,9 @@ impl Pager {
                     }
                 }
             }
+
+            // These need copying rather than zeroing, which resolve_copy_on_write does
+            &Pager::CopyOnWrite(_) | &Pager::File(_) => return None,
         };
 
         Some(result)
@@ -136,10 +319,40 @@ impl Pager {
 #[derive(Clone)]
 struct MemBlock {
     user: bool,
+    /// Cleared for address space that's been reserved without being committed, and for pages protected against any
+    /// access.
+    readable: bool,
     writable: bool,
+    executable: bool,
+    guard_page: bool,
+    /// Set when `protect_pages` splits a block, on the blocks after the first, so that they're freed along with it.
+    continued: bool,
+    /// Where the block starts within its pager's pages. Only non-zero for blocks that have been split.
+    pager_offset: usize,
     pager: Option<Pager>,
 }
 
+impl MemBlock {
+    /// Address space that nothing gets mapped into, such as the page at address 0.
+    fn unmappable() -> Self {
+        MemBlock {
+            user: false,
+            readable: false,
+            writable: false,
+            executable: false,
+            guard_page: false,
+            continued: false,
+            pager_offset: 0,
+            pager: None,
+        }
+    }
+
+    /// Returns the index of the page at `offset` within the block, as its pager counts pages.
+    fn page_index(&self, offset: usize) -> usize {
+        (self.pager_offset + offset) / phys_mem::PAGE_SIZE
+    }
+}
+
 pub struct Process {
     name: String,
     arch: ArchProcess,
@@ -158,15 +371,6 @@ impl Process {
     ) -> Result<Self> {
         let arch = ArchProcess::new(phys.clone())?;
         let user_virt = VirtualTree::new();
-        user_virt.reserve(
-            unsafe { slice::from_raw_parts_mut(0 as *mut u8, 4096) },
-            MemBlock {
-                user: false,
-                writable: false,
-                pager: None,
-            },
-        );
-
         Ok(Process {
             name,
             arch,
@@ -178,7 +382,7 @@ impl Process {
     }
 
     pub fn for_kernel() -> Result<Self> {
-        let phys = Arc::new(PhysicalBitmap::machine());
+        let phys = PhysicalBitmap::machine();
         let kernel_virt = Arc::new(VirtualTree::new());
         let identity = phys_mem::identity_range();
 
@@ -190,14 +394,7 @@ impl Process {
             )
         };
 
-        kernel_virt.reserve(
-            user_plus_identity,
-            MemBlock {
-                user: false,
-                writable: false,
-                pager: None,
-            },
-        );
+        kernel_virt.reserve(user_plus_identity, MemBlock::unmappable())?;
 
         Process::new("<kernel>".into(), phys, kernel_virt, Vec::new())
     }
@@ -214,36 +411,295 @@ impl Process {
         self.arch.switch();
     }
 
-    unsafe fn alloc_inner(
-        &self,
-        ptr_opt: Option<*mut u8>,
-        len: usize,
-        user: bool,
-        writable: bool,
-        pager: Pager,
-    ) -> Result<*mut u8> {
-        let virt = if user { &self.user_virt } else { &*self.kernel_virt };
-
-        let block = MemBlock {
-            user,
-            writable,
-            pager: Some(pager),
+    unsafe fn alloc_inner(&self, ptr_opt: Option<*mut u8>, len: usize, block: MemBlock) -> Result<*mut u8> {
+        let virt = if block.user {
+            &self.user_virt
+        } else {
+            &*self.kernel_virt
         };
 
         match ptr_opt {
             Some(ptr) => {
-                if virt.reserve(slice::from_raw_parts_mut(ptr, len), block) {
-                    Ok(ptr)
-                } else {
+                if let Err(num) = virt.reserve(slice::from_raw_parts_mut(ptr, len), block) {
                     log!("can't reserve {} bytes at {:p}", len, ptr);
-                    Err(ErrNum::InvalidArgument)
+                    return Err(num);
                 }
+
+                Ok(ptr)
             }
 
             None => virt.alloc(len, block).map(|slice| slice.as_mut_ptr()),
         }
     }
 
+    /// Changes the permissions of the block of user memory at `ptr`, including any of its pages that are already
+    /// mapped.
+    fn protect(&self, ptr: *mut u8, readable: bool, writable: bool, executable: bool) -> Result<()> {
+        let mut updated = None;
+        let slice = self
+            .user_virt
+            .update_tag(ptr, |block| {
+                block.readable = readable;
+                block.writable = writable;
+                block.executable = executable;
+                updated = Some(block.clone());
+            })
+            .ok_or(ErrNum::InvalidArgument)?;
+
+        let block = updated.unwrap();
+        for offset in (0..slice.len()).step_by(phys_mem::PAGE_SIZE) {
+            unsafe {
+                let ptr = slice.as_ptr().offset(offset as isize);
+
+                // Pages shared with a forked process or a file stay read-only until they're copied
+                let shared = block.pager.as_ref().map_or(false, |pager| {
+                    let addr = self.arch.translate(ptr);
+                    addr.is_some() && addr == pager.shared_page(block.page_index(offset))
+                });
+
+                // Pages that can't be read keep their contents, but fault even when the kernel touches them, so that
+                // syscalls handed a pointer into them can't read them either
+                if readable {
+                    self.arch.protect(ptr, block.user, writable && !shared, executable)
+                } else {
+                    self.arch.hide(ptr)
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Changes the permissions of the pages from `ptr` to `ptr + len`, splitting blocks where the range starts or
+    /// ends part of the way through one.
+    fn protect_range(&self, ptr: *mut u8, len: usize, readable: bool, writable: bool, executable: bool) -> Result<()> {
+        // As with loaded segments, pages can be writable or executable but not both
+        if !Align::is_aligned(ptr, phys_mem::PAGE_SIZE) || len == 0 || (writable && executable) {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        let end = (ptr as usize)
+            .checked_add(Align::up(len, phys_mem::PAGE_SIZE))
+            .ok_or(ErrNum::InvalidArgument)? as *mut u8;
+
+        // Every page in the range has to be allocated or reserved already
+        let mut block_ptr = ptr;
+        while block_ptr < end {
+            let (slice, block) = self.user_virt.tag_at(block_ptr).ok_or(ErrNum::InvalidArgument)?;
+            if block.pager.is_none() {
+                return Err(ErrNum::InvalidArgument);
+            }
+
+            block_ptr = unsafe { slice.as_mut_ptr().offset(slice.len() as isize) };
+        }
+
+        let split = |block: &mut MemBlock, offset| {
+            block.guard_page = false;
+            block.continued = true;
+            block.pager_offset += offset;
+        };
+
+        self.user_virt.split(ptr, split);
+        if block_ptr > end {
+            self.user_virt.split(end, split);
+        }
+
+        let mut block_ptr = ptr;
+        while block_ptr < end {
+            self.protect(block_ptr, readable, writable, executable)?;
+            let (slice, _) = self.user_virt.tag_at(block_ptr).ok_or(ErrNum::InvalidArgument)?;
+            block_ptr = unsafe { slice.as_mut_ptr().offset(slice.len() as isize) };
+        }
+
+        Ok(())
+    }
+
+    /// Moves the pages of a block into a `CowPages`, so that this process and a forked one can share them until one
+    /// of them writes to a page. Returns the block for the forked process.
+    fn share_block(&self, slice: &mut [u8], block: MemBlock) -> MemBlock {
+        let (parent, file) = match block.pager {
+            Some(Pager::CopyOnWrite(ref cow)) => (Some(cow.clone()), None),
+            Some(Pager::File(ref file)) => (None, Some(file.clone())),
+            _ => (None, None),
+        };
+
+        // Unmapping the pages means that this process faults on them next time, and maps them read-only. The new
+        // pages are counted the same way as the parent's, which for a block that's been split doesn't start at 0.
+        let mut pages = vec![None; block.page_index(0)];
+        pages.extend((0..slice.len()).step_by(phys_mem::PAGE_SIZE).map(|offset| {
+            let addr = unsafe { self.arch.unmap(slice.as_ptr().offset(offset as isize)) };
+            let shared = block
+                .pager
+                .as_ref()
+                .and_then(|pager| pager.shared_page(block.page_index(offset)));
+
+            addr.filter(|&addr| Some(addr) != shared).and_then(NonZeroUsize::new)
+        }));
+
+        let pager = Pager::CopyOnWrite(Arc::new(CowPages {
+            phys: self.phys.clone(),
+            pages,
+            parent,
+            file,
+        }));
+
+        self.user_virt
+            .update_tag(slice.as_mut_ptr(), |block| block.pager = Some(pager.clone()));
+
+        MemBlock {
+            pager: Some(pager),
+            ..block
+        }
+    }
+
+    /// Starts a process with the same handles as this one, and a copy of its memory. Pages are only copied when one
+    /// of the two processes writes to them. Other threads in this process shouldn't write to memory while it's
+    /// being forked.
+    pub fn fork(&self, name: String) -> Result<Self> {
+        let (handles, non_blocking, tls) = {
+            let state = lock!(self.state);
+            (state.handles.clone(), state.non_blocking.clone(), state.tls)
+        };
+
+        let child = self.spawn(name, handles)?;
+        {
+            let mut state = lock!(child.state);
+            state.non_blocking = non_blocking;
+            state.tls = tls;
+        }
+
+        for (slice, block) in self.user_virt.blocks() {
+            let block = match block.pager {
+                // Blocks that can't be mapped have no pages to share
+                None => continue,
+                Some(Pager::Zeroed) | Some(Pager::CopyOnWrite(_)) | Some(Pager::File(_)) => {
+                    self.share_block(slice, block)
+                }
+
+                Some(Pager::Physical(_)) | Some(Pager::Shared(_)) => block,
+            };
+
+            child.user_virt.reserve(slice, block)?;
+        }
+
+        Ok(child)
+    }
+
+    /// Unmaps a block of memory, and frees the pages that belong to it.
+    fn free_block(&self, slice: &mut [u8], block: &MemBlock) {
+        assert!(Align::is_aligned(slice.len(), phys_mem::PAGE_SIZE));
+
+        for offset in (0..slice.len()).step_by(phys_mem::PAGE_SIZE) {
+            let addr = unsafe { self.arch.unmap(slice.as_ptr().offset(offset as isize)) };
+            match (addr, &block.pager) {
+                (Some(addr), Some(Pager::Zeroed)) => self.phys.free_page(addr),
+
+                // Pages that are still shared belong to the CowPages or MappedFile, and get freed along with it
+                (Some(addr), Some(pager @ Pager::CopyOnWrite(_))) | (Some(addr), Some(pager @ Pager::File(_)))
+                    if pager.shared_page(block.page_index(offset)) != Some(addr) =>
+                {
+                    self.phys.free_page(addr)
+                }
+
+                _ => (),
+            }
+        }
+    }
+
+    pub fn add_thread(&self) {
+        lock!(self.state).threads += 1;
+    }
+
+    /// Called as each thread exits. The last thread to exit closes the process's handles and frees its memory; the
+    /// page tables stay until the `Process` itself is dropped, since other processes can still hold handles to it.
+    pub fn remove_thread(&self) {
+        let handles = {
+            let mut state = lock!(self.state);
+            state.threads -= 1;
+            if state.threads > 0 {
+                return;
+            }
+
+            state.tls = None;
+            mem::replace(&mut state.handles, Vec::new())
+        };
+
+        mem::drop(handles);
+
+        for (slice, block) in self.user_virt.drain() {
+            self.free_block(slice, &block);
+        }
+    }
+
+    /// Terminates the process with the given exit code. Its handles are closed straight away, threads blocked in the
+    /// kernel are woken, and every thread stops the next time it's about to return to user mode.
+    pub fn kill(&self, code: i32) {
+        let (handles, parked) = {
+            let mut state = lock!(self.state);
+            if state.killed.is_none() {
+                state.killed = Some(code);
+            }
+
+            (
+                mem::replace(&mut state.handles, Vec::new()),
+                mem::replace(&mut state.parked, Vec::new()),
+            )
+        };
+
+        mem::drop(handles);
+
+        for (_, cancel) in parked {
+            cancel(ErrNum::Interrupted);
+        }
+
+        self.exit_code().try_resolve(code);
+    }
+
+    /// Called by `thread::block_until` to park one of this process's threads with `park`. `kill` calls `cancel` to
+    /// wake the thread again. Returns the thread without parking it if the process has been killed already.
+    pub fn park_thread<Park: FnOnce(BlockedThread)>(
+        &self,
+        thread: BlockedThread,
+        park: Park,
+        cancel: Arc<dyn Fn(ErrNum)>,
+    ) -> Option<BlockedThread> {
+        let mut state = lock!(self.state);
+        if state.killed.is_some() {
+            return Some(thread);
+        }
+
+        state.parked.push((thread.id(), cancel));
+        park(thread);
+        None
+    }
+
+    /// Called by `thread::block_until` once the thread is running again.
+    pub fn forget_parked_thread(&self, id: usize) {
+        lock!(self.state).parked.retain(|&(parked_id, _)| parked_id != id);
+    }
+
+    /// Returns the exit code passed to `kill`, if the process has been killed.
+    pub fn killed(&self) -> Option<i32> {
+        lock!(self.state).killed
+    }
+
+    /// Ends the process from within. Like `kill`, but leaves a message, such as why the process panicked, for
+    /// whoever is waiting for it.
+    pub fn exit(&self, code: i32, message: &str) {
+        {
+            let mut state = lock!(self.state);
+            if state.killed.is_none() {
+                state.exit_message = message.into();
+            }
+        }
+
+        self.kill(code);
+    }
+
+    pub fn exit_message(&self) -> String {
+        lock!(self.state).exit_message.clone()
+    }
+
     pub fn make_handle(&self, obj: Arc<dyn KObj>) -> Handle {
         let mut state = lock!(self.state);
         state.make_handle(obj)
@@ -267,6 +723,18 @@ impl Process {
         lock!(self.state).resolve_handle_obj(handle)
     }
 
+    pub fn take_handles<T>(&self, handles: &[Handle], f: impl FnOnce(Vec<Arc<dyn KObj>>) -> Result<T>) -> Result<T> {
+        lock!(self.state).take_handles(handles, f)
+    }
+
+    pub fn set_non_blocking(&self, handle: Handle, non_blocking: bool) -> Result<()> {
+        lock!(self.state).set_non_blocking(handle, non_blocking)
+    }
+
+    pub fn is_non_blocking(&self, handle: Handle) -> bool {
+        lock!(self.state).non_blocking.contains(&handle)
+    }
+
     pub fn exit_code(&self) -> Deferred<i32> {
         lock!(self.state).exit_code.clone()
     }
@@ -302,164 +770,574 @@ pub fn alloc_tls() -> Option<&'static mut [u8]> {
     })
 }
 
-#[cfg(not(target_arch = "arm"))]
-pub fn spawn(executable: String, handles: Vec<Option<Arc<dyn KObj>>>) -> Result<Arc<Process>> {
-    let current = thread::current_process();
-    let process = Arc::new(current.spawn(executable.clone(), handles)?);
-
-    let init_in_new_process = move || -> Result<_> {
-        let image_slice = unsafe {
-            use crate::arch::multiboot::multiboot_module_t;
-            use crate::arch::phys_mem::multiboot_info;
-
-            let info = multiboot_info();
+fn read_exact_at(file: &dyn KObj, offset: u64, mut buf: &mut [u8]) -> Result<()> {
+    let seek = file.seek().ok_or(ErrNum::NotSupported)?;
+    let read = file.read().ok_or(ErrNum::NotSupported)?;
+    seek.seek(SeekFrom::Start(offset))?;
 
-            let mods: &[multiboot_module_t] =
-                slice::from_raw_parts(phys_mem::phys2virt(info.mods_addr as usize), info.mods_count as usize);
+    while !buf.is_empty() {
+        let len = read.read(buf)?;
+        if len == 0 {
+            return Err(ErrNum::InvalidArgument);
+        }
 
-            assert_eq!(1, mods.len());
+        let rest = buf;
+        buf = &mut rest[len..];
+    }
 
-            let mod_data: &[u8] = slice::from_raw_parts(
-                phys_mem::phys2virt(mods[0].mod_start as usize),
-                (mods[0].mod_end - mods[0].mod_start) as usize,
-            );
+    Ok(())
+}
 
-            tar::locate(mod_data, &executable).ok_or(ErrNum::FileNotFound)?
-        };
+unsafe fn read_struct_at<T>(file: &dyn KObj, offset: u64) -> Result<T> {
+    let mut value: T = mem::zeroed();
+    let buf = slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, mem::size_of::<T>());
+    read_exact_at(file, offset, buf)?;
+    Ok(value)
+}
 
-        mem::drop(executable);
-
-        let ehdr = unsafe { &*(image_slice.as_ptr() as *const Elf64_Ehdr) };
-        assert_eq!(
-            [ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3, ELFCLASS64, ELFDATA2LSB, EV_CURRENT],
-            &ehdr.e_ident[0..7]
-        );
-        assert_eq!((ET_EXEC, EM_X86_64), (ehdr.e_type, ehdr.e_machine));
-        assert_ne!(ehdr.e_entry, 0);
-
-        let entry = ehdr.e_entry as *const u8;
-        let mut slices = Vec::new();
-        let mut tls = None;
-        for i in 0..ehdr.e_phnum {
-            let phdr_offset = ehdr.e_phoff as isize + (i as isize) * (ehdr.e_phentsize as isize);
-            let phdr = unsafe { &*(image_slice.as_ptr().offset(phdr_offset) as *const Elf64_Phdr) };
-            match phdr.p_type {
-                PT_LOAD => {
-                    assert!(phdr.p_memsz >= phdr.p_filesz);
-
-                    let slice = unsafe {
-                        process::alloc_at::<u8>(phdr.p_vaddr as *mut u8, phdr.p_memsz as usize, true, true).unwrap()
-                    };
+/// Opens an executable by its path, or by its name alone if it's in `/bin`.
+fn open_executable(executable: &str) -> Result<Arc<dyn KObj>> {
+    if executable.contains('/') {
+        fs::open(executable)
+    } else {
+        fs::open(&(String::from("/bin/") + executable))
+    }
+}
 
-                    let file_slice = &image_slice[phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize];
-                    slice[..file_slice.len()].copy_from_slice(file_slice);
-                    slices.push(slice);
-                }
+/// Position-independent executables are loaded into one of `PIE_SLOTS` slots, each `PIE_SLOT_SIZE` bytes, starting at
+/// `PIE_REGION_START`.
+const PIE_REGION_START: usize = 0x10_0000_0000;
+const PIE_SLOTS: usize = 1 << 16;
+const PIE_SLOT_SIZE: usize = 16 * phys_mem::PAGE_SIZE;
 
-                PT_TLS => {
-                    assert!(phdr.p_memsz >= phdr.p_filesz);
-                    assert!(tls.is_none(), "segment {}: didn't expect another TLS segment", i);
+lazy_static! {
+    static ref PIE_SLOT: Mutex<usize> = Mutex::new(cpu::rdtsc() as usize % PIE_SLOTS);
+}
 
-                    let slice = process::alloc::<u8>(phdr.p_filesz as usize, true, false).unwrap();
-                    let file_slice = &image_slice[phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize];
-                    slice.copy_from_slice(file_slice);
-                    tls = Some((phdr.p_memsz as usize, slice as &[u8]));
-                }
+/// Picks the base address for the next position-independent executable.
+///
+/// The slot is seeded from the timestamp counter and stepped with a full-period generator, so consecutive processes
+/// never share a base.
+fn next_pie_base() -> usize {
+    let mut slot = lock!(PIE_SLOT);
+    *slot = (*slot * 0x5851 + 0x2d2f) % PIE_SLOTS;
+    PIE_REGION_START + *slot * PIE_SLOT_SIZE
+}
 
-                PT_GNU_STACK => {
-                    assert_eq!(phdr.p_memsz, 0);
-                }
+/// A range of an executable file to be loaded into memory.
+struct Segment {
+    offset: u64,
+    vaddr: usize,
+    filesz: usize,
+    memsz: usize,
+    writable: bool,
+    executable: bool,
+}
 
-                _ => {
-                    panic!("segment {}: don't know how to handle type {}", i, phdr.p_type);
-                }
-            }
+impl Segment {
+    fn new(phdr: &Elf64_Phdr, file_len: u64, base: usize) -> Result<Self> {
+        let in_file = phdr
+            .p_offset
+            .checked_add(phdr.p_filesz)
+            .map_or(false, |end| end <= file_len);
+
+        // Leave room to round the end of the segment up to a page boundary
+        let vaddr = phdr.p_vaddr.checked_add(base as u64);
+        let in_memory = vaddr
+            .and_then(|vaddr| vaddr.checked_add(phdr.p_memsz))
+            .and_then(|end| end.checked_add(phys_mem::PAGE_SIZE as u64))
+            .is_some();
+
+        if !in_file || !in_memory || phdr.p_filesz > phdr.p_memsz {
+            return Err(ErrNum::BadExecutable);
         }
 
-        assert!(slices.iter().any(|slice| slice.contains_ptr(entry)));
+        Ok(Segment {
+            offset: phdr.p_offset,
+            vaddr: vaddr.unwrap() as usize,
+            filesz: phdr.p_filesz as usize,
+            memsz: phdr.p_memsz as usize,
+            writable: phdr.p_flags & PF_W != 0,
+            executable: phdr.p_flags & PF_X != 0,
+        })
+    }
 
-        let stack_slice = process::alloc::<u8>(phys_mem::PAGE_SIZE * 10, true, true).unwrap();
+    /// Returns the pages that the segment occupies once loaded.
+    fn pages(&self) -> (usize, usize) {
+        (
+            Align::down(self.vaddr, phys_mem::PAGE_SIZE),
+            Align::up(self.vaddr + self.memsz, phys_mem::PAGE_SIZE),
+        )
+    }
 
-        if let Some((len, data)) = tls {
-            process::set_tls(len, data);
+    fn contains(&self, vaddr: usize, len: usize) -> bool {
+        match vaddr.checked_add(len) {
+            Some(end) => vaddr >= self.vaddr && end <= self.vaddr + self.memsz,
+            None => false,
         }
+    }
 
-        Ok((entry, stack_slice))
-    };
-
-    let deferred = thread::spawn_remote(process.clone(), move || {
-        let (rip, stack_slice) = match init_in_new_process() {
-            Ok(tuple) => tuple,
-            Err(num) => thread::exit(-(num as i32)),
-        };
-
-        if let Some(tls) = process::alloc_tls() {
-            thread::set_tls(tls);
+    /// Returns where in the file to find the bytes that get loaded at `vaddr`.
+    fn file_offset(&self, vaddr: usize, len: usize) -> Option<u64> {
+        if vaddr >= self.vaddr && vaddr.checked_add(len)? <= self.vaddr + self.filesz {
+            Some(self.offset + (vaddr - self.vaddr) as u64)
+        } else {
+            None
         }
+    }
+}
 
-        unsafe {
-            let rsp = stack_slice.as_mut_ptr().offset(stack_slice.len() as isize);
-            arch_thread::jmp_user_mode(rip, rsp, 0)
+/// A pointer to be stored in the loaded image, once the load address is known.
+struct Relocation {
+    addr: usize,
+    value: u64,
+}
+
+impl Relocation {
+    fn new(rela: &Elf64_Rela, base: usize, load: &[Segment]) -> Result<Self> {
+        let addr = (rela.r_offset as usize)
+            .checked_add(base)
+            .filter(|&addr| load.iter().any(|segment| segment.contains(addr, mem::size_of::<u64>())))
+            .ok_or(ErrNum::BadExecutable)?;
+
+        match (rela.r_info & 0xffff_ffff) as u32 {
+            R_X86_64_RELATIVE => Ok(Relocation {
+                addr,
+                value: (base as u64).wrapping_add(rela.r_addend as u64),
+            }),
+            _ => Err(ErrNum::BadExecutable),
         }
-        // TODO: free stack
-    });
+    }
+}
 
-    process.set_exit_code(deferred);
-    Ok(process)
+/// The parts of an ELF executable that the loader needs, checked against the size of the file.
+struct Executable {
+    entry: usize,
+    load: Vec<Segment>,
+    tls: Option<Segment>,
+    relocations: Vec<Relocation>,
 }
 
-pub struct Allocation<T> {
-    len: usize,
-    base: Option<*mut T>,
-    user: bool,
-    writable: bool,
-    pager: Pager,
+fn read_header_at<T>(image: &dyn KObj, file_len: u64, offset: u64) -> Result<T> {
+    match offset.checked_add(mem::size_of::<T>() as u64) {
+        Some(end) if end <= file_len => unsafe { read_struct_at(image, offset) },
+        _ => Err(ErrNum::BadExecutable),
+    }
 }
 
-impl<T> Allocation<T> {
-    fn new(len: usize, pager: Pager) -> Self {
-        Allocation {
-            base: None,
-            len,
-            user: false,
-            writable: false,
-            pager,
+/// Reads the relocation table that the PT_DYNAMIC segment points to.
+fn read_relocations(image: &dyn KObj, file_len: u64, dynamic: &Segment, load: &[Segment]) -> Result<Vec<Elf64_Rela>> {
+    let mut rela = None;
+    let mut relasz = 0;
+    let mut relaent = mem::size_of::<Elf64_Rela>();
+    for i in 0..dynamic.filesz / mem::size_of::<Elf64_Dyn>() {
+        let offset = dynamic.offset + (i * mem::size_of::<Elf64_Dyn>()) as u64;
+        let mut entry: Elf64_Dyn = read_header_at(image, file_len, offset)?;
+        let value = unsafe { *entry.d_un.d_val() } as usize;
+        if entry.d_tag == DT_NULL as i64 {
+            break;
+        } else if entry.d_tag == DT_RELA as i64 {
+            rela = Some(value);
+        } else if entry.d_tag == DT_RELASZ as i64 {
+            relasz = value;
+        } else if entry.d_tag == DT_RELAENT as i64 {
+            relaent = value;
         }
     }
 
-    pub fn zeroed(len: usize) -> Self {
-        Allocation::new(len, Pager::Zeroed)
-    }
+    let rela = match rela {
+        Some(rela) => rela,
+        None => return Ok(Vec::new()),
+    };
 
-    pub fn phys(len: usize, addr: usize) -> Self {
-        Allocation::new(len, Pager::Physical(addr))
+    if relaent != mem::size_of::<Elf64_Rela>() || relasz % relaent != 0 {
+        return Err(ErrNum::BadExecutable);
     }
 
-    pub fn shared(len: usize, shared: KObjRef<SharedMemBlock>) -> Self {
-        Allocation::new(len, Pager::Shared(shared))
-    }
+    let offset = load
+        .iter()
+        .filter_map(|segment| segment.file_offset(rela, relasz))
+        .next()
+        .ok_or(ErrNum::BadExecutable)?;
 
-    pub fn user(mut self, user: bool) -> Self {
-        self.user = user;
-        self
-    }
+    let mut relas = vec![Elf64_Rela::default(); relasz / relaent];
+    let buf = unsafe { slice::from_raw_parts_mut(relas.as_mut_ptr() as *mut u8, relasz) };
+    read_exact_at(image, offset, buf)?;
+    Ok(relas)
+}
 
-    pub fn writable(mut self, writable: bool) -> Self {
-        self.writable = writable;
-        self
+/// Checks an executable's headers against the size of the file. Position-independent executables are given
+/// addresses starting at `pie_base`.
+fn parse_executable(image: &dyn KObj, pie_base: usize) -> Result<Executable> {
+    let file_len = image.stat().ok_or(ErrNum::NotSupported)?.size;
+    let ehdr: Elf64_Ehdr = read_header_at(image, file_len, 0)?;
+    if ehdr.e_ident[0..7] != [ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3, ELFCLASS64, ELFDATA2LSB, EV_CURRENT]
+        || ehdr.e_machine != EM_X86_64
+        || ehdr.e_phentsize as usize != mem::size_of::<Elf64_Phdr>()
+    {
+        return Err(ErrNum::BadExecutable);
     }
 
-    pub fn base(mut self, base: *mut T) -> Self {
-        self.base = Some(base);
-        self
-    }
+    let base = match ehdr.e_type {
+        ET_EXEC => 0,
+        ET_DYN => pie_base,
+        _ => return Err(ErrNum::BadExecutable),
+    };
+
+    let mut load = Vec::new();
+    let mut tls = None;
+    let mut dynamic = None;
+    for i in 0..ehdr.e_phnum {
+        let phdr_offset = ehdr
+            .e_phoff
+            .checked_add((i as u64) * (ehdr.e_phentsize as u64))
+            .ok_or(ErrNum::BadExecutable)?;
+
+        let phdr: Elf64_Phdr = read_header_at(image, file_len, phdr_offset)?;
+        match phdr.p_type {
+            PT_LOAD => load.push(Segment::new(&phdr, file_len, base)?),
+            PT_TLS if tls.is_none() => tls = Some(Segment::new(&phdr, file_len, base)?),
+            PT_DYNAMIC if dynamic.is_none() => dynamic = Some(Segment::new(&phdr, file_len, base)?),
+            PT_NULL | PT_NOTE | PT_PHDR | PT_GNU_EH_FRAME | PT_GNU_STACK | PT_GNU_RELRO => {}
+            _ => return Err(ErrNum::BadExecutable),
+        }
+    }
+
+    // Loaded segments have to fit below the kernel, without sharing pages with each other, and can't be both
+    // writable and executable
+    for (i, segment) in load.iter().enumerate() {
+        let (start, end) = segment.pages();
+        if start < phys_mem::PAGE_SIZE || end > USER_END || (segment.writable && segment.executable) {
+            return Err(ErrNum::BadExecutable);
+        }
+
+        if load[..i].iter().any(|other| {
+            let (other_start, other_end) = other.pages();
+            start < other_end && other_start < end
+        }) {
+            return Err(ErrNum::BadExecutable);
+        }
+    }
+
+    let entry = (ehdr.e_entry as usize)
+        .checked_add(base)
+        .filter(|&entry| load.iter().any(|segment| segment.contains(entry, 1)))
+        .ok_or(ErrNum::BadExecutable)?;
+
+    let relocations = match dynamic {
+        Some(dynamic) => read_relocations(image, file_len, &dynamic, &load)?
+            .iter()
+            .map(|rela| Relocation::new(rela, base, &load))
+            .collect::<Result<_>>()?,
+        None => Vec::new(),
+    };
+
+    Ok(Executable {
+        entry,
+        load,
+        tls,
+        relocations,
+    })
+}
+
+/// The size of a process's main thread stack, and of other threads' stacks unless they ask for something else.
+pub const STACK_SIZE: usize = phys_mem::PAGE_SIZE * 10;
+
+/// The arguments and environment for a new process.
+pub struct Args {
+    strings: Vec<u8>,
+    offsets: Vec<usize>,
+    argc: usize,
+}
+
+impl Args {
+    /// Reads arguments in the form that `spawn_process` takes them: NUL-terminated strings, with an empty string
+    /// between the arguments and the environment.
+    pub fn parse(block: &[u8]) -> Result<Self> {
+        let mut offsets = Vec::new();
+        let mut argc = None;
+        let mut start = 0;
+        for (i, &b) in block.iter().enumerate() {
+            if b != 0 {
+                continue;
+            }
+
+            str::from_utf8(&block[start..i])?;
+
+            if i > start {
+                offsets.push(start);
+            } else if argc.is_none() {
+                argc = Some(offsets.len());
+            }
+
+            start = i + 1;
+        }
+
+        if start != block.len() {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        let args = Args {
+            strings: block.to_vec(),
+            argc: argc.unwrap_or(offsets.len()),
+            offsets,
+        };
+
+        // Leave most of the stack for the program itself
+        if args.strings.len() + (args.offsets.len() + 4) * mem::size_of::<usize>() > STACK_SIZE / 2 {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        Ok(args)
+    }
+
+    /// Copies the strings to the top of `stack`, and below them the `argv` and `envp` arrays, each of which ends
+    /// with a null pointer. Returns the new top of the stack, which is also the start of `argv`.
+    unsafe fn push(&self, stack: &mut [u8]) -> *mut u8 {
+        let top = stack.as_mut_ptr().offset(stack.len() as isize);
+        let strings = Align::down(top.offset(-(self.strings.len() as isize)), 16);
+        intrinsics::copy_nonoverlapping(self.strings.as_ptr(), strings, self.strings.len());
+
+        let (args, env) = self.offsets.split_at(self.argc);
+        let mut pointers = Vec::with_capacity(self.offsets.len() + 2);
+        pointers.extend(args.iter().map(|&offset| strings as usize + offset));
+        pointers.push(0);
+        pointers.extend(env.iter().map(|&offset| strings as usize + offset));
+        pointers.push(0);
+
+        let pointers_len = pointers.len() * mem::size_of::<usize>();
+        let argv = Align::down(strings.offset(-(pointers_len as isize)), 16);
+        intrinsics::copy_nonoverlapping(pointers.as_ptr(), argv as *mut usize, pointers.len());
+        argv
+    }
+}
+
+/// Puts a segment in the current process's memory, writable for now. Its pages are mapped from the file if it can be
+/// mapped and the segment is aligned the same way in the file as in memory; otherwise they're read from the file.
+/// Returns the blocks of memory that the segment occupies.
+#[cfg(not(target_arch = "arm"))]
+unsafe fn load_segment(image: &Arc<dyn KObj>, segment: &Segment) -> Result<Vec<*mut u8>> {
+    let (start, end) = segment.pages();
+    let page_offset = segment.vaddr - start;
+    let file = KObjRef::new(image.clone(), |kobj| kobj.map_file());
+    let file = match file {
+        Ok(file) if segment.filesz > 0 && segment.offset % phys_mem::PAGE_SIZE as u64 == page_offset as u64 => file,
+        _ => {
+            let slice = process::alloc_at::<u8>(segment.vaddr as *mut u8, segment.memsz, true, true)?;
+            read_exact_at(&**image, segment.offset, &mut slice[..segment.filesz])?;
+            return Ok(vec![slice.as_mut_ptr()]);
+        }
+    };
+
+    let file_end = Align::up(segment.vaddr + segment.filesz, phys_mem::PAGE_SIZE);
+    Allocation::file(file_end - start, file, segment.offset - page_offset as u64)?
+        .user(true)
+        .writable(true)
+        .base(start as *mut u8)
+        .allocate()?;
+
+    // The last page from the file has whatever comes after the segment in the file, which needs zeroing. Writing to
+    // it gives the process its own copy of the page.
+    let tail = (segment.vaddr + segment.filesz) as *mut u8;
+    intrinsics::write_bytes(tail, 0, file_end - tail as usize);
+
+    let mut ptrs = vec![start as *mut u8];
+    if end > file_end {
+        process::alloc_at::<u8>(file_end as *mut u8, end - file_end, true, true)?;
+        ptrs.push(file_end as *mut u8);
+    }
+
+    Ok(ptrs)
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub fn spawn(executable: String, args: Args, handles: Vec<Option<Arc<dyn KObj>>>) -> Result<Arc<Process>> {
+    let image = open_executable(&executable)?;
+    let Executable {
+        entry,
+        load,
+        tls,
+        relocations,
+    } = parse_executable(&*image, next_pie_base())?;
+
+    let current = thread::current_process();
+    let process = Arc::new(current.spawn(executable, handles)?);
+
+    let init_in_new_process = move || -> Result<_> {
+        let mut blocks = Vec::new();
+        for segment in &load {
+            let ptrs = unsafe { load_segment(&image, segment)? };
+            blocks.extend(ptrs.into_iter().map(|ptr| (ptr, segment)));
+        }
+
+        for relocation in relocations {
+            unsafe { (relocation.addr as *mut u64).write_unaligned(relocation.value) };
+        }
+
+        // Segments stay writable until they're loaded and relocated
+        let current = thread::current_process();
+        for (ptr, segment) in blocks {
+            current.protect(ptr, true, segment.writable, segment.executable)?;
+        }
+
+        let stack_slice = process::alloc_stack(STACK_SIZE)?;
+
+        if let Some(segment) = tls {
+            let slice = process::alloc::<u8>(segment.filesz, true, false)?;
+            read_exact_at(&*image, segment.offset, &mut slice[..])?;
+            process::set_tls(segment.memsz, slice);
+        }
+
+        Ok((entry as *const u8, stack_slice))
+    };
+
+    let deferred = thread::spawn_remote(process.clone(), move || {
+        let (rip, stack_slice) = match init_in_new_process() {
+            Ok(tuple) => tuple,
+            Err(num) => thread::exit(-(num as i32)),
+        };
+
+        if let Some(tls) = process::alloc_tls() {
+            thread::set_tls(tls);
+        }
+
+        let argv = unsafe { args.push(stack_slice) };
+        thread::set_user_stack(stack_slice);
+        unsafe { arch_thread::jmp_user_mode(rip, argv, args.argc, argv as usize) }
+    });
+
+    process.set_exit_code(deferred);
+    Ok(process)
+}
+
+/// Forks the current process. The new process's one thread calls `entry(context)` in user mode, on a stack of its
+/// own.
+#[cfg(not(target_arch = "arm"))]
+pub fn fork(entry: *const u8, context: usize) -> Result<Arc<Process>> {
+    let current = thread::current_process();
+    let process = Arc::new(current.fork(current.name().into())?);
+
+    let deferred = thread::spawn_remote(process.clone(), move || {
+        let stack_slice = match process::alloc_stack(STACK_SIZE) {
+            Ok(slice) => slice,
+            Err(num) => thread::exit(-(num as i32)),
+        };
+
+        if let Some(tls) = process::alloc_tls() {
+            thread::set_tls(tls);
+        }
+
+        let rsp = unsafe { stack_slice.as_mut_ptr().offset(stack_slice.len() as isize) };
+        thread::set_user_stack(stack_slice);
+        unsafe { arch_thread::jmp_user_mode(entry, rsp, context, 0) }
+    });
+
+    process.set_exit_code(deferred);
+    Ok(process)
+}
+
+pub struct Allocation<T> {
+    len: usize,
+    base: Option<*mut T>,
+    user: bool,
+    readable: bool,
+    writable: bool,
+    executable: bool,
+    guard_page: bool,
+    pager: Pager,
+}
+
+impl<T> Allocation<T> {
+    fn new(len: usize, pager: Pager) -> Self {
+        Allocation {
+            base: None,
+            len,
+            user: false,
+            readable: true,
+            writable: false,
+            executable: false,
+            guard_page: false,
+            pager,
+        }
+    }
+
+    pub fn zeroed(len: usize) -> Self {
+        Allocation::new(len, Pager::Zeroed)
+    }
+
+    pub fn phys(len: usize, addr: usize) -> Self {
+        Allocation::new(len, Pager::Physical(addr))
+    }
+
+    pub fn shared(len: usize, shared: KObjRef<SharedMemBlock>) -> Self {
+        Allocation::new(len, Pager::Shared(shared))
+    }
+
+    pub fn file(len: usize, file: KObjRef<dyn MapFile>, offset: u64) -> Result<Self> {
+        if offset % phys_mem::PAGE_SIZE as u64 != 0 {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        let file = MappedFile {
+            phys: thread::current_process().phys.clone(),
+            file,
+            offset,
+            copies: Mutex::new(Vec::new()),
+        };
+
+        Ok(Allocation::new(len, Pager::File(Arc::new(file))))
+    }
+
+    pub fn user(mut self, user: bool) -> Self {
+        self.user = user;
+        self
+    }
+
+    /// Allocations that aren't readable only reserve address space, and fault when touched until `protect_pages`
+    /// changes them.
+    pub fn readable(mut self, readable: bool) -> Self {
+        self.readable = readable;
+        self
+    }
+
+    pub fn writable(mut self, writable: bool) -> Self {
+        self.writable = writable;
+        self
+    }
+
+    pub fn executable(mut self, executable: bool) -> Self {
+        self.executable = executable;
+        self
+    }
+
+    /// Leaves the first page of the allocation unmapped, so that running off the bottom of a stack causes a fault.
+    pub fn guard_page(mut self, guard_page: bool) -> Self {
+        self.guard_page = guard_page;
+        self
+    }
+
+    pub fn base(mut self, base: *mut T) -> Self {
+        self.base = Some(base);
+        self
+    }
 
     pub fn allocate(self) -> Result<&'static mut [T]> {
         let process = thread::current_process();
         let base = self.base.map(|ptr| ptr as *mut u8);
         let len = (self.len * mem::size_of::<T>()).max(phys_mem::PAGE_SIZE);
+        let block = MemBlock {
+            user: self.user,
+            readable: self.readable,
+            writable: self.writable,
+            executable: self.executable,
+            guard_page: self.guard_page,
+            continued: false,
+            pager_offset: 0,
+            pager: Some(self.pager),
+        };
+
         unsafe {
-            let ptr = process.alloc_inner(base, len, self.user, self.writable, self.pager)?;
+            let ptr = process.alloc_inner(base, len, block)?;
             Ok(slice::from_raw_parts_mut(ptr as *mut T, self.len))
         }
     }
@@ -469,6 +1347,28 @@ pub fn alloc<T>(len: usize, user: bool, writable: bool) -> Result<&'static mut [
     Allocation::zeroed(len).user(user).writable(writable).allocate()
 }
 
+/// Allocates a user-mode stack of `len` bytes, plus a guard page below it. The thread's stack pointer starts at the
+/// end of the returned slice.
+pub fn alloc_stack(len: usize) -> Result<&'static mut [u8]> {
+    let len = len
+        .checked_add(phys_mem::PAGE_SIZE * 2 - 1)
+        .map(|len| Align::down(len, phys_mem::PAGE_SIZE))
+        .ok_or(ErrNum::InvalidArgument)?;
+
+    Allocation::zeroed(len)
+        .user(true)
+        .writable(true)
+        .guard_page(true)
+        .allocate()
+}
+
+/// Returns true if `ptr` is in the guard page below a stack in the current process.
+pub fn is_guard_page(ptr: *mut u8) -> bool {
+    let process = try_or_false!(thread::try_current_process());
+    let (slice, block) = try_or_false!(process.user_virt.tag_at(ptr));
+    block.guard_page && ptr::bytes_between(slice.as_mut_ptr(), ptr) < phys_mem::PAGE_SIZE
+}
+
 pub unsafe fn alloc_at<T>(base: *mut T, len: usize, user: bool, writable: bool) -> Result<&'static mut [T]> {
     Allocation::zeroed(len)
         .user(user)
@@ -490,34 +1390,136 @@ pub fn map_shared<T>(
     Allocation::shared(len, block).user(user).writable(writable).allocate()
 }
 
+/// Maps `len` bytes of a file, starting at `offset`, which has to be a multiple of the page size. Writing to the
+/// memory changes this process's copy of the page, not the file.
+pub fn map_file(
+    file: KObjRef<dyn MapFile>,
+    offset: u64,
+    len: usize,
+    user: bool,
+    writable: bool,
+) -> Result<&'static mut [u8]> {
+    Allocation::file(len, file, offset)?
+        .user(user)
+        .writable(writable)
+        .allocate()
+}
+
 pub fn free(ptr: *mut u8) -> bool {
     let process = thread::current_process();
-    let ptr = Align::down(ptr, phys_mem::PAGE_SIZE);
-    let (len, _) = if let Some(tuple) = process.user_virt.free(ptr) {
-        tuple
-    } else {
+    let mut ptr = Align::down(ptr, phys_mem::PAGE_SIZE);
+    let mut freed = false;
+
+    // Blocks that protect_pages split off from this one get freed along with it
+    loop {
+        match process.user_virt.tag_at(ptr) {
+            Some((_, ref block)) if !freed || block.continued => (),
+            _ => return freed,
+        }
+
+        let (len, block) = match process.user_virt.free(ptr) {
+            Some((len, Some(block))) => (len, block),
+            _ => return freed,
+        };
+
+        process.free_block(unsafe { slice::from_raw_parts_mut(ptr, len) }, &block);
+        ptr = unsafe { ptr.offset(len as isize) };
+        freed = true;
+    }
+}
+
+/// Reserves `len` bytes of user address space, at `ptr` if given. The pages fault when they're touched until
+/// `protect_pages` makes them readable.
+pub fn reserve(ptr: Option<*mut u8>, len: usize) -> Result<&'static mut [u8]> {
+    let allocation = Allocation::zeroed(len).user(true).readable(false);
+    match ptr {
+        Some(ptr) => {
+            if (ptr as usize).checked_add(len).map_or(true, |end| end > USER_END) {
+                return Err(ErrNum::InvalidArgument);
+            }
+
+            allocation.base(ptr).allocate()
+        }
+        None => allocation.allocate(),
+    }
+}
+
+/// Changes the permissions of the pages from `ptr` to `ptr + len` in the current process, which have to be
+/// allocated or reserved already. Pages that aren't readable can't be touched at all, and pages can't be both
+/// writable and executable.
+pub fn protect_pages(ptr: *mut u8, len: usize, readable: bool, writable: bool, executable: bool) -> Result<()> {
+    thread::current_process().protect_range(ptr, len, readable, writable, executable)
+}
+
+/// Handles a fault on a page of a block that's shared with a forked process or mapped from a file. Reading maps the
+/// shared page read-only; writing maps a copy of it, or a zeroed page if there's nothing to copy.
+fn resolve_copy_on_write(
+    process: &Process,
+    ptr: *mut u8,
+    block: &MemBlock,
+    shared: Option<usize>,
+    mapped: Option<usize>,
+    writing: bool,
+) -> bool {
+    if writing && !block.writable {
         return false;
-    };
+    }
 
-    assert!(Align::is_aligned(len, phys_mem::PAGE_SIZE));
+    match (shared, mapped) {
+        // The page has been copied already, and the fault came from a TLB entry that hadn't been flushed yet
+        (_, Some(mapped)) if shared != Some(mapped) => true,
 
-    for offset in (0..len).step_by(phys_mem::PAGE_SIZE) {
-        unsafe {
+        (Some(shared), None) if !writing => unsafe {
             process
                 .arch
-                .map(ptr.offset(offset as isize), None, false, false)
-                .unwrap()
+                .map(ptr, Some(shared), block.user, false, block.executable)
+                .is_ok()
+        },
+
+        _ => {
+            let addr = try_or_false!(process.phys.alloc_page().ok());
+
+            unsafe {
+                let dest: &mut u8 = phys_mem::phys2virt(addr);
+                match shared {
+                    Some(shared) => {
+                        let src: &u8 = phys_mem::phys2virt(shared);
+                        intrinsics::copy_nonoverlapping(src, dest, phys_mem::PAGE_SIZE)
+                    }
+                    None => intrinsics::write_bytes(dest, 0, phys_mem::PAGE_SIZE),
+                }
+
+                if process
+                    .arch
+                    .map(ptr, Some(addr), block.user, block.writable, block.executable)
+                    .is_err()
+                {
+                    process.phys.free_page(addr);
+                    return false;
+                }
+            }
+
+            // Other CPUs running this process's threads could still have the shared page in their TLBs
+            if mapped.is_some() {
+                smp::flush_tlb_others();
+            }
+
+            true
         }
     }
-
-    true
 }
 
-pub fn resolve_page_fault(ptr: *mut u8) -> bool {
+/// Maps the page at `ptr` when it's first touched, or when it's written to and has to be copied from a forked
+/// process or a file.
+pub fn resolve_page_fault(ptr: *mut u8, writing: bool) -> bool {
     let process = try_or_false!(thread::try_current_process());
     let ptr = Align::down(ptr, phys_mem::PAGE_SIZE);
     let identity = phys_mem::identity_range();
-    assert!(!identity.contains_ptr(ptr));
+
+    // The kernel's own pages are always mapped, so this is user code touching something it mustn't
+    if identity.contains_ptr(ptr) {
+        return false;
+    }
 
     let (slice, block) = try_or_false!(process
         .user_virt
@@ -525,21 +1527,35 @@ pub fn resolve_page_fault(ptr: *mut u8) -> bool {
         .or_else(|| process.kernel_virt.tag_at(ptr)));
     assert!(slice.contains_ptr(ptr));
 
-    let pager = try_or_false!(block.pager);
+    let pager = try_or_false!(block.pager.clone());
     let offset = ptr::bytes_between(slice.as_mut_ptr(), ptr);
-    let (dirty, addr) = try_or_false!(pager.alloc(offset, || process.phys.alloc_page().ok()));
+    if !block.readable || (block.guard_page && offset < phys_mem::PAGE_SIZE) {
+        return false;
+    }
+
+    let mapped = unsafe { process.arch.translate(ptr) };
+    if let Some(shared) = pager.load_shared_page(block.page_index(offset)) {
+        let shared = try_or_false!(shared.ok());
+        return resolve_copy_on_write(&process, ptr, &block, shared, mapped, writing);
+    }
+
+    // Otherwise pages only need mapping the first time they're touched
+    if mapped.is_some() {
+        return false;
+    }
+
+    let (dirty, addr) = try_or_false!(pager.alloc(block.pager_offset + offset, || process.phys.alloc_page().ok()));
 
     unsafe {
         if dirty {
-            try_or_false!(process.arch.map(ptr, Some(addr), block.user, true).ok());
+            try_or_false!(process.arch.map(ptr, Some(addr), block.user, true, false).ok());
             intrinsics::write_bytes(ptr, 0, phys_mem::PAGE_SIZE);
-
-            if !block.writable {
-                try_or_false!(process.arch.map(ptr, Some(addr), block.user, false).ok());
-            }
-        } else {
-            try_or_false!(process.arch.map(ptr, Some(addr), block.user, block.writable).ok());
         }
+
+        try_or_false!(process
+            .arch
+            .map(ptr, Some(addr), block.user, block.writable, block.executable)
+            .ok());
     }
 
     true
@@ -564,20 +1580,262 @@ pub fn resolve_handle_obj(handle: Handle) -> Result<Arc<dyn KObj>> {
     thread::current_process().resolve_handle_obj(handle)
 }
 
+/// Hands the objects behind `handles` to `f`, then closes the handles in the current process if `f` succeeds, so
+/// that the objects can be moved to another one.
+pub fn take_handles<T>(handles: &[Handle], f: impl FnOnce(Vec<Arc<dyn KObj>>) -> Result<T>) -> Result<T> {
+    thread::current_process().take_handles(handles, f)
+}
+
 pub fn close_handle(handle: Handle) -> bool {
     let process = thread::current_process();
     let mut state = lock!(process.state);
     state.close_handle(handle)
 }
 
+pub fn set_non_blocking(handle: Handle, non_blocking: bool) -> Result<()> {
+    thread::current_process().set_non_blocking(handle, non_blocking)
+}
+
+pub fn is_non_blocking(handle: Handle) -> bool {
+    thread::current_process().is_non_blocking(handle)
+}
+
 #[cfg(feature = "test")]
 pub mod test {
     use super::*;
+    use crate::fs::initrd::InitrdFile;
+    use crate::io::{Pipe, Read};
     use crate::thread;
     use alloc::sync::Arc;
     use core::intrinsics;
 
+    const BASE: u64 = 0x40_0000;
+
+    fn ehdr(phnum: u16) -> Elf64_Ehdr {
+        let mut ehdr = Elf64_Ehdr::default();
+        ehdr.e_ident[0..7].copy_from_slice(&[ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3, ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+        ehdr.e_type = ET_EXEC;
+        ehdr.e_machine = EM_X86_64;
+        ehdr.e_entry = BASE + 0x100;
+        ehdr.e_phoff = mem::size_of::<Elf64_Ehdr>() as u64;
+        ehdr.e_phentsize = mem::size_of::<Elf64_Phdr>() as u16;
+        ehdr.e_phnum = phnum;
+        ehdr
+    }
+
+    fn phdr(p_type: u32, vaddr: u64) -> Elf64_Phdr {
+        let mut phdr = Elf64_Phdr::default();
+        phdr.p_type = p_type;
+        phdr.p_vaddr = vaddr;
+        phdr.p_filesz = 0x200;
+        phdr.p_memsz = 0x1000;
+        phdr
+    }
+
+    fn image_bytes(ehdr: Elf64_Ehdr, phdrs: &[Elf64_Phdr], len: usize) -> Vec<u8> {
+        unsafe fn bytes_of<T>(value: &T) -> &[u8] {
+            slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+        }
+
+        let mut data = unsafe { bytes_of(&ehdr).to_vec() };
+        for phdr in phdrs {
+            data.extend_from_slice(unsafe { bytes_of(phdr) });
+        }
+
+        data.resize(len, 0);
+        data
+    }
+
+    fn put_words(data: &mut [u8], offset: usize, words: &[u64]) {
+        for (i, word) in words.iter().enumerate() {
+            let offset = offset + i * 8;
+            data[offset..offset + 8].copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    /// Makes a position-independent executable whose relocation table holds `relas`, three words per entry.
+    fn pie_image(relas: &[u64]) -> Vec<u8> {
+        let mut ehdr = ehdr(2);
+        ehdr.e_type = ET_DYN;
+        ehdr.e_entry = 0x100;
+
+        let mut load = phdr(PT_LOAD, 0);
+        load.p_filesz = 0x1000;
+        load.p_memsz = 0x2000;
+
+        let mut dynamic = phdr(PT_DYNAMIC, 0x200);
+        dynamic.p_offset = 0x200;
+        dynamic.p_filesz = 0x40;
+
+        let relasz = (relas.len() * 8) as u64;
+        let mut data = image_bytes(ehdr, &[load, dynamic], 0x1000);
+        put_words(&mut data, 0x200, &[DT_RELA as u64, 0x300, DT_RELASZ as u64, relasz]);
+        put_words(&mut data, 0x220, &[DT_RELAENT as u64, 24, DT_NULL as u64, 0]);
+        put_words(&mut data, 0x300, relas);
+        data
+    }
+
+    const PIE_BASE: usize = 0x40_0000;
+
+    fn parse_bytes(data: Vec<u8>) -> Result<Executable> {
+        let image: Arc<dyn KObj> = Arc::new(InitrdFile::new(Box::leak(data.into_boxed_slice()), 0o755));
+        parse_executable(&*image, PIE_BASE)
+    }
+
+    fn parse(ehdr: Elf64_Ehdr, phdrs: &[Elf64_Phdr], len: usize) -> Result<Executable> {
+        parse_bytes(image_bytes(ehdr, phdrs, len))
+    }
+
     test! {
+        fn can_parse_executable() {
+            let phdrs = [
+                phdr(PT_LOAD, BASE),
+                phdr(PT_NOTE, 0),
+                phdr(PT_GNU_EH_FRAME, 0),
+                phdr(PT_GNU_RELRO, 0),
+                phdr(PT_TLS, 0),
+            ];
+
+            let executable = parse(ehdr(5), &phdrs, 0x1000).unwrap();
+            assert_eq!(BASE as usize + 0x100, executable.entry);
+            assert_eq!(1, executable.load.len());
+            assert!(executable.tls.is_some());
+        }
+
+        fn rejects_bad_headers() {
+            let phdrs = [phdr(PT_LOAD, BASE)];
+            assert_eq!(Some(ErrNum::BadExecutable), parse(ehdr(1), &phdrs, 10).err());
+
+            let mut bad_magic = ehdr(1);
+            bad_magic.e_ident[1] = b'X';
+            assert_eq!(Some(ErrNum::BadExecutable), parse(bad_magic, &phdrs, 0x1000).err());
+
+            let mut bad_machine = ehdr(1);
+            bad_machine.e_machine = EM_386;
+            assert_eq!(Some(ErrNum::BadExecutable), parse(bad_machine, &phdrs, 0x1000).err());
+
+            let mut bad_phentsize = ehdr(1);
+            bad_phentsize.e_phentsize = 1;
+            assert_eq!(Some(ErrNum::BadExecutable), parse(bad_phentsize, &phdrs, 0x1000).err());
+
+            let mut bad_phoff = ehdr(1);
+            bad_phoff.e_phoff = u64::max_value() - 1;
+            assert_eq!(Some(ErrNum::BadExecutable), parse(bad_phoff, &phdrs, 0x1000).err());
+
+            assert_eq!(Some(ErrNum::BadExecutable), parse(ehdr(1000), &phdrs, 0x1000).err());
+        }
+
+        fn rejects_bad_segments() {
+            let mut past_end = phdr(PT_LOAD, BASE);
+            past_end.p_offset = 0xf00;
+            assert_eq!(Some(ErrNum::BadExecutable), parse(ehdr(1), &[past_end], 0x1000).err());
+
+            let mut too_big = phdr(PT_LOAD, BASE);
+            too_big.p_filesz = 0x2000;
+            assert_eq!(Some(ErrNum::BadExecutable), parse(ehdr(1), &[too_big], 0x4000).err());
+
+            let mut wraps = phdr(PT_LOAD, BASE);
+            wraps.p_vaddr = u64::max_value() - 0x100;
+            assert_eq!(Some(ErrNum::BadExecutable), parse(ehdr(1), &[wraps], 0x1000).err());
+
+            let null_page = phdr(PT_LOAD, 0);
+            assert_eq!(Some(ErrNum::BadExecutable), parse(ehdr(1), &[null_page], 0x1000).err());
+
+            let overlapping = [phdr(PT_LOAD, BASE), phdr(PT_LOAD, BASE + 0x800)];
+            assert_eq!(Some(ErrNum::BadExecutable), parse(ehdr(2), &overlapping, 0x1000).err());
+
+            let two_tls = [phdr(PT_LOAD, BASE), phdr(PT_TLS, 0), phdr(PT_TLS, 0)];
+            assert_eq!(Some(ErrNum::BadExecutable), parse(ehdr(3), &two_tls, 0x1000).err());
+
+            let mut writable_code = phdr(PT_LOAD, BASE);
+            writable_code.p_flags = PF_R | PF_W | PF_X;
+            assert_eq!(Some(ErrNum::BadExecutable), parse(ehdr(1), &[writable_code], 0x1000).err());
+
+            let unknown = [phdr(PT_LOAD, BASE), phdr(PT_LOPROC, 0)];
+            assert_eq!(Some(ErrNum::BadExecutable), parse(ehdr(2), &unknown, 0x1000).err());
+        }
+
+        fn can_relocate_position_independent_executable() {
+            let relative = R_X86_64_RELATIVE as u64;
+            let executable = parse_bytes(pie_image(&[0x400, relative, 0x100, 0x1800, relative, 0x10])).unwrap();
+            assert_eq!(PIE_BASE + 0x100, executable.entry);
+            assert_eq!(PIE_BASE, executable.load[0].vaddr);
+
+            let relocations: Vec<_> = executable.relocations.iter().map(|r| (r.addr, r.value)).collect();
+            let base = PIE_BASE as u64;
+            assert_eq!(vec![(PIE_BASE + 0x400, base + 0x100), (PIE_BASE + 0x1800, base + 0x10)], relocations);
+        }
+
+        fn position_independent_executables_get_their_own_base() {
+            let data = pie_image(&[0x400, R_X86_64_RELATIVE as u64, 0x100]);
+            let image: Arc<dyn KObj> = Arc::new(InitrdFile::new(Box::leak(data.into_boxed_slice()), 0o755));
+            let first = parse_executable(&*image, next_pie_base()).unwrap();
+            let second = parse_executable(&*image, next_pie_base()).unwrap();
+            assert_ne!(first.load[0].vaddr, second.load[0].vaddr);
+
+            for executable in &[first, second] {
+                let base = executable.load[0].vaddr;
+                assert_eq!(0, base % phys_mem::PAGE_SIZE);
+                assert!(base >= PIE_REGION_START && base < PIE_REGION_START + PIE_SLOTS * PIE_SLOT_SIZE);
+                assert_eq!(base + 0x100, executable.entry);
+            }
+        }
+
+        fn rejects_bad_relocations() {
+            let relative = R_X86_64_RELATIVE as u64;
+            assert_eq!(Some(ErrNum::BadExecutable), parse_bytes(pie_image(&[0x400, R_X86_64_64 as u64, 0])).err());
+            assert_eq!(Some(ErrNum::BadExecutable), parse_bytes(pie_image(&[0x5000, relative, 0])).err());
+            assert_eq!(Some(ErrNum::BadExecutable), parse_bytes(pie_image(&[0x400, relative])).err());
+        }
+
+        fn can_parse_args() {
+            let args = Args::parse(b"echo\0hello\0\0HOME=/\0").unwrap();
+            assert_eq!(2, args.argc);
+            assert_eq!(vec![0, 5, 12], args.offsets);
+
+            assert_eq!(0, Args::parse(b"").unwrap().argc);
+            assert_eq!(Some(ErrNum::InvalidArgument), Args::parse(b"echo").err());
+            assert_eq!(Some(ErrNum::Utf8Error), Args::parse(b"\xff\0").err());
+        }
+
+        fn can_push_args() {
+            unsafe fn c_str(ptr: *const u8) -> &'static [u8] {
+                let mut len = 0;
+                while *ptr.offset(len as isize) != 0 {
+                    len += 1;
+                }
+
+                slice::from_raw_parts(ptr, len)
+            }
+
+            let args = Args::parse(b"echo\0hello\0\0HOME=/\0").unwrap();
+            let mut stack = vec![0u8; 256];
+            unsafe {
+                let argv = args.push(&mut stack) as *const *const u8;
+                assert_eq!(0, argv as usize % 16);
+                assert_eq!(b"echo", c_str(*argv));
+                assert_eq!(b"hello", c_str(*argv.offset(1)));
+                assert!((*argv.offset(2)).is_null());
+                assert_eq!(b"HOME=/", c_str(*argv.offset(3)));
+                assert!((*argv.offset(4)).is_null());
+            }
+        }
+
+        fn rejects_entry_outside_segments() {
+            let mut ehdr = ehdr(1);
+            ehdr.e_entry = BASE + 0x2000;
+            assert_eq!(Some(ErrNum::BadExecutable), parse(ehdr, &[phdr(PT_LOAD, BASE)], 0x1000).err());
+        }
+
+        fn can_parse_linked_executable() {
+            // Linked by syscall/arch/amd64/link.ld, with the thread-locals that every program gets from the os crate
+            let image = open_executable("hello").unwrap();
+            let executable = parse_executable(&*image, PIE_BASE).unwrap();
+            assert!(executable.tls.is_some());
+            assert!(executable.load.iter().any(|segment| segment.executable));
+            assert!(executable.load.iter().any(|segment| segment.writable));
+        }
+
         fn can_alloc() {
             thread::with_scheduler(|| {
                 let len = 4096;
@@ -644,6 +1902,319 @@ pub mod test {
             });
         }
 
+        fn can_kill_processes_without_leaking() {
+            thread::with_scheduler(|| {
+                let p = thread::current_process();
+                let free_bytes = p.phys.free_bytes() + phys_mem::heap_bytes();
+                let inherited: Arc<dyn KObj> = Arc::new(SharedMemBlock::new());
+
+                for code in 0..200 {
+                    let victim = Arc::new(p.spawn("can_kill_processes_without_leaking".into(), vec![Some(inherited.clone())]).unwrap());
+                    let d = thread::spawn_remote(victim.clone(), || {
+                        let slice = alloc::<u8>(0x4000, true, true).unwrap();
+                        for offset in (0..slice.len()).step_by(phys_mem::PAGE_SIZE) {
+                            unsafe { intrinsics::volatile_store(&mut slice[offset], 1) };
+                        }
+
+                        // Stand in for user code, which gets stopped on its way back from a syscall
+                        loop {
+                            thread::schedule();
+                            thread::exit_if_killed();
+                        }
+                    });
+
+                    thread::schedule();
+                    victim.kill(code);
+                    assert_eq!(code, victim.exit_code().get());
+                    assert_eq!(code, d.get());
+
+                    // Wait for the victim's thread to let go of the process
+                    while Arc::strong_count(&victim) > 1 {
+                        thread::schedule();
+                    }
+                }
+
+                assert_eq!(1, Arc::strong_count(&inherited));
+                assert_eq!(free_bytes, p.phys.free_bytes() + phys_mem::heap_bytes());
+            });
+        }
+
+        fn kill_wakes_thread_blocked_in_pipe_read() {
+            thread::with_scheduler(|| {
+                let p = thread::current_process();
+                let pipe = Arc::new(Pipe::new());
+                let victim = Arc::new(p.spawn("kill_wakes_thread_blocked_in_pipe_read".into(), vec![]).unwrap());
+                let d = thread::spawn_remote(victim.clone(), {
+                    let pipe = pipe.clone();
+                    move || {
+                        let mut buf = [0; 4];
+                        let result = Read::read(&*pipe, &mut buf);
+                        assert_eq!(Err(ErrNum::Interrupted), result);
+                        thread::exit_if_killed();
+                        0
+                    }
+                });
+
+                while pipe.queue_len() == 0 {
+                    thread::schedule();
+                }
+
+                victim.kill(9);
+                assert_eq!(9, d.get());
+                assert_eq!(0, pipe.queue_len());
+            });
+        }
+
+        fn exiting_process_stops_other_threads() {
+            thread::with_scheduler(|| {
+                let p = thread::current_process();
+                let process = Arc::new(p.spawn("exiting_process_stops_other_threads".into(), vec![]).unwrap());
+                let other = thread::spawn_remote(process.clone(), || loop {
+                    thread::schedule();
+                    thread::exit_if_killed();
+                });
+
+                let main = thread::spawn_remote(process.clone(), || {
+                    thread::current_process().exit(5, "main failed");
+                    thread::exit_if_killed();
+                    0
+                });
+
+                assert_eq!(5, process.exit_code().get());
+                assert_eq!(5, main.get());
+                assert_eq!(5, other.get());
+                assert_eq!("main failed", process.exit_message());
+            });
+        }
+
+        fn stack_has_guard_page() {
+            thread::with_scheduler(|| {
+                let p = thread::current_process();
+                let d = thread::spawn_remote(Arc::new(p.spawn("stack_has_guard_page".into(), vec![]).unwrap()), || {
+                    let stack = alloc_stack(0x4000).unwrap();
+                    assert_eq!(0x5000, stack.len());
+
+                    let guard = stack.as_mut_ptr();
+                    let bottom = unsafe { guard.offset(phys_mem::PAGE_SIZE as isize) };
+                    assert!(is_guard_page(guard));
+                    assert!(!is_guard_page(bottom));
+                    assert!(!resolve_page_fault(guard, false));
+                    assert!(resolve_page_fault(bottom, false));
+                    0
+                });
+
+                assert_eq!(0, d.get());
+            });
+        }
+
+        fn writing_to_kernel_page_is_not_resolved() {
+            thread::with_scheduler(|| {
+                let kernel_page = phys_mem::identity_range().as_ptr() as *mut u8;
+                assert!(!resolve_page_fault(kernel_page, true));
+            });
+        }
+
+        fn exiting_thread_frees_its_stack() {
+            thread::with_scheduler(|| {
+                let p = thread::current_process();
+                let process = Arc::new(p.spawn("exiting_thread_frees_its_stack".into(), vec![]).unwrap());
+                let block_count = process.user_virt.block_count();
+
+                // Keeps the process alive, so that its memory isn't all freed when the first thread exits
+                let release = Deferred::new();
+                let other = thread::spawn_remote(process.clone(), {
+                    let release = release.clone();
+                    move || release.get()
+                });
+
+                let d = thread::spawn_remote(process.clone(), || {
+                    thread::set_user_stack(alloc_stack(0x4000).unwrap());
+                    0
+                });
+
+                assert_eq!(0, d.get());
+                assert_eq!(block_count, process.user_virt.block_count());
+
+                release.resolve(0);
+                assert_eq!(0, other.get());
+            });
+        }
+
+        fn forked_process_copies_pages_on_write() {
+            thread::with_scheduler(|| {
+                let p = thread::current_process();
+                let free_bytes = p.phys.free_bytes() + phys_mem::heap_bytes();
+                let parent = Arc::new(p.spawn("forked_process_copies_pages_on_write".into(), vec![]).unwrap());
+                let d = thread::spawn_remote(parent.clone(), || {
+                    // Two pages, only the first of which is touched before the fork
+                    let slice = alloc::<u32>(0x2000 / 4, true, true).unwrap();
+                    unsafe { intrinsics::volatile_store(&mut slice[0], 1) };
+
+                    let child = Arc::new(thread::current_process().fork("child".into()).unwrap());
+                    let ptr = slice.as_mut_ptr() as usize;
+                    let d = thread::spawn_remote(child.clone(), move || unsafe {
+                        let ptr = ptr as *mut u32;
+                        let value = intrinsics::volatile_load(ptr);
+                        intrinsics::volatile_store(ptr, 2);
+                        intrinsics::volatile_store(ptr.offset(1024), 3);
+                        value as i32 + intrinsics::volatile_load(ptr) as i32 * 10
+                    });
+
+                    assert_eq!(21, d.get());
+
+                    unsafe {
+                        assert_eq!(1, intrinsics::volatile_load(&slice[0]));
+                        assert_eq!(0, intrinsics::volatile_load(&slice[1024]));
+                        intrinsics::volatile_store(&mut slice[0], 4);
+                        assert_eq!(4, intrinsics::volatile_load(&slice[0]));
+                    }
+
+                    while Arc::strong_count(&child) > 1 {
+                        thread::schedule();
+                    }
+
+                    0
+                });
+
+                assert_eq!(0, d.get());
+                while Arc::strong_count(&parent) > 1 {
+                    thread::schedule();
+                }
+
+                mem::drop(parent);
+                assert_eq!(free_bytes, p.phys.free_bytes() + phys_mem::heap_bytes());
+            });
+        }
+
+        fn mapped_file_is_copied_on_write() {
+            thread::with_scheduler(|| {
+                let data = (0..phys_mem::PAGE_SIZE + 10).map(|i| i as u8 | 1).collect::<Vec<_>>();
+                let file: Arc<dyn KObj> = Arc::new(InitrdFile::new(Box::leak(data.into_boxed_slice()), 0o644));
+                let p = thread::current_process();
+                let free_bytes = p.phys.free_bytes() + phys_mem::heap_bytes();
+                let process = Arc::new(p.spawn("mapped_file_is_copied_on_write".into(), vec![]).unwrap());
+                let d = thread::spawn_remote(process.clone(), move || {
+                    let map = |offset, writable| {
+                        let file = KObjRef::new(file.clone(), |kobj| kobj.map_file()).unwrap();
+                        map_file(file, offset, phys_mem::PAGE_SIZE * 3, true, writable)
+                    };
+
+                    assert_eq!(Some(ErrNum::InvalidArgument), map(1, false).err());
+
+                    let slice = map(0, true).unwrap();
+                    let before = map(0, false).unwrap();
+                    unsafe {
+                        assert_eq!(1, intrinsics::volatile_load(&slice[0]));
+                        assert_eq!(5, intrinsics::volatile_load(&slice[phys_mem::PAGE_SIZE + 5]));
+                        assert_eq!(0, intrinsics::volatile_load(&slice[phys_mem::PAGE_SIZE + 10]));
+                        assert_eq!(0, intrinsics::volatile_load(&slice[phys_mem::PAGE_SIZE * 2]));
+                        intrinsics::volatile_store(&mut slice[0], 0xff);
+                        assert_eq!(0xff, intrinsics::volatile_load(&slice[0]));
+                        assert_eq!(1, intrinsics::volatile_load(&before[0]));
+                    }
+
+                    0
+                });
+
+                assert_eq!(0, d.get());
+                while Arc::strong_count(&process) > 1 {
+                    thread::schedule();
+                }
+
+                mem::drop(process);
+                assert_eq!(free_bytes, p.phys.free_bytes() + phys_mem::heap_bytes());
+            });
+        }
+
+        fn can_protect_and_reserve_pages() {
+            thread::with_scheduler(|| {
+                let p = thread::current_process();
+                let free_bytes = p.phys.free_bytes() + phys_mem::heap_bytes();
+                let process = Arc::new(p.spawn("can_protect_and_reserve_pages".into(), vec![]).unwrap());
+                let d = thread::spawn_remote(process.clone(), || {
+                    let current = thread::current_process();
+                    let slice = alloc::<u8>(0x3000, true, true).unwrap();
+                    let ptr = slice.as_mut_ptr();
+                    let (middle, last) = unsafe { (ptr.offset(0x1000), ptr.offset(0x2000)) };
+                    unsafe {
+                        intrinsics::volatile_store(middle, 1);
+                        intrinsics::volatile_store(last, 2);
+                    }
+
+                    let read_only = |ptr, len| protect_pages(ptr, len, true, false, false);
+                    assert_eq!(Some(ErrNum::InvalidArgument), read_only(unsafe { middle.offset(1) }, 0x1000).err());
+                    assert_eq!(Some(ErrNum::InvalidArgument), read_only(middle, 0x10_0000).err());
+                    read_only(middle, 0x1000).unwrap();
+
+                    let (first_block, first) = current.user_virt.tag_at(ptr).unwrap();
+                    let (middle_block, middle_tag) = current.user_virt.tag_at(middle).unwrap();
+                    let (_, last_tag) = current.user_virt.tag_at(last).unwrap();
+                    assert_eq!(0x1000, first_block.len());
+                    assert_eq!(0x1000, middle_block.len());
+                    assert!(first.writable && !first.continued);
+                    assert!(!middle_tag.writable && middle_tag.continued);
+                    assert!(last_tag.writable && last_tag.continued);
+
+                    // Pages keep their contents, but writing to a read-only one isn't resolved
+                    assert!(!resolve_page_fault(middle, true));
+                    unsafe {
+                        assert_eq!(1, intrinsics::volatile_load(middle));
+                        assert_eq!(2, intrinsics::volatile_load(last));
+                    }
+
+                    // Pages that can't be read aren't even mapped for the kernel, but come back as they were
+                    let last_addr = unsafe { current.arch.translate(last) };
+                    protect_pages(last, 0x1000, false, false, false).unwrap();
+                    assert!(!resolve_page_fault(last, false));
+                    protect_pages(last, 0x1000, true, true, false).unwrap();
+                    assert_eq!(last_addr, unsafe { current.arch.translate(last) });
+                    assert_eq!(2, unsafe { intrinsics::volatile_load(last) });
+                    protect_pages(last, 0x1000, false, false, false).unwrap();
+
+                    let reserved = reserve(None, 0x2000).unwrap().as_mut_ptr();
+                    assert!(!resolve_page_fault(reserved, false));
+                    assert_eq!(Some(ErrNum::InvalidArgument), protect_pages(reserved, 0x1000, true, true, true).err());
+                    protect_pages(reserved, 0x1000, true, true, false).unwrap();
+                    assert!(resolve_page_fault(reserved, true));
+                    assert!(!resolve_page_fault(unsafe { reserved.offset(0x1000) }, false));
+
+                    assert!(free(ptr));
+                    assert!(current.user_virt.tag_at(last).is_none());
+                    assert!(free(reserved));
+                    0
+                });
+
+                assert_eq!(0, d.get());
+                while Arc::strong_count(&process) > 1 {
+                    thread::schedule();
+                }
+
+                mem::drop(process);
+                assert_eq!(free_bytes, p.phys.free_bytes() + phys_mem::heap_bytes());
+            });
+        }
+
+        fn exiting_frees_user_memory() {
+            thread::with_scheduler(|| {
+                let p = thread::current_process();
+                let free_bytes = p.phys.free_bytes() + phys_mem::heap_bytes();
+                let process = Arc::new(p.spawn("exiting_frees_user_memory".into(), vec![]).unwrap());
+                let d = thread::spawn_remote(process.clone(), || {
+                    let slice = alloc::<u8>(0x4000, true, true).unwrap();
+                    unsafe { intrinsics::volatile_store(slice.as_mut_ptr(), 1) };
+                    0
+                });
+
+                assert_eq!(0, d.get());
+                while Arc::strong_count(&process) > 1 {
+                    thread::schedule();
+                }
+
+                mem::drop(process);
+                assert_eq!(free_bytes, p.phys.free_bytes() + phys_mem::heap_bytes());
+            });
+        }
+
         /*fn kernel_addresses_are_shared() {
             thread::with_scheduler(|| {
                 let p1 = Arc::new(Process::new(phys.clone(), kernel_virt.clone()).unwrap());
@@ -1,16 +1,22 @@
+use crate::deferred::Deferred;
 use crate::kobj::KObj;
 use crate::spin::Mutex;
 use crate::thread::{self, BlockedThread};
+use crate::time;
 use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
+use core::cell::Cell;
+use core::mem;
 use syscall::{ErrNum, Result};
 
 struct SemaphoreState {
     waiters: VecDeque<BlockedThread>,
     value: usize,
+    ready: Deferred<()>,
 }
 
 pub struct Semaphore {
-    state: Mutex<SemaphoreState>,
+    state: Arc<Mutex<SemaphoreState>>,
 }
 
 unsafe impl Send for Semaphore {}
@@ -19,10 +25,11 @@ unsafe impl Sync for Semaphore {}
 impl Semaphore {
     pub fn new(value: usize) -> Self {
         Semaphore {
-            state: Mutex::new(SemaphoreState {
+            state: Arc::new(Mutex::new(SemaphoreState {
                 waiters: VecDeque::new(),
                 value,
-            }),
+                ready: Deferred::new(),
+            })),
         }
     }
 
@@ -37,12 +44,51 @@ impl Semaphore {
         }
     }
 
+    /// Like `wait`, but gives up with `ErrNum::TimedOut` once the tick count reaches `deadline`, or with
+    /// `ErrNum::Interrupted` if the current process is killed.
+    pub fn wait_timeout(&self, deadline: usize) -> Result<()> {
+        loop {
+            let mut state = lock!(self.state);
+            if let Some(value) = state.value.checked_sub(1) {
+                state.value = value;
+                return Ok(());
+            }
+
+            if time::ticks() >= deadline {
+                return Err(ErrNum::TimedOut);
+            }
+
+            let parked = Cell::new(false);
+            let cancel_state = self.state.clone();
+            let result = thread::block_until(
+                deadline,
+                {
+                    let parked = &parked;
+                    move |thread| {
+                        parked.set(true);
+                        state.waiters.push_back(thread);
+                    }
+                },
+                move |id| thread::remove_waiter(&mut lock!(cancel_state).waiters, id),
+            );
+
+            // `post` hands its count straight to the thread it wakes
+            if parked.get() || result == Err(ErrNum::Interrupted) {
+                return result;
+            }
+        }
+    }
+
     pub fn post(&self) -> Result<()> {
         let mut state = lock!(self.state);
         if let Some(thread) = state.waiters.pop_front() {
             thread.resume();
         } else {
             state.value = state.value.checked_add(1).ok_or(ErrNum::InvalidArgument)?;
+
+            let ready = mem::replace(&mut state.ready, Deferred::new());
+            mem::drop(state);
+            ready.resolve(());
         }
 
         Ok(())
@@ -53,11 +99,21 @@ impl KObj for Semaphore {
     fn semaphore(&self) -> Option<&Semaphore> {
         Some(self)
     }
+
+    fn ready(&self) -> Option<Deferred<()>> {
+        let state = lock!(self.state);
+        if state.value > 0 {
+            Some(Deferred::resolved(()))
+        } else {
+            Some(state.ready.clone())
+        }
+    }
 }
 
 #[cfg(feature = "test")]
 pub mod test {
     use super::*;
+    use crate::kobj;
     use crate::logging;
     use alloc::sync::Arc;
     use alloc::vec::Vec;
@@ -101,5 +157,46 @@ pub mod test {
                 let _ = write!(logging::Writer, "\n");
             });
         }
+
+        fn wait_timeout_times_out() {
+            thread::with_scheduler(|| {
+                let s = Semaphore::new(0);
+                let start = time::ticks();
+                assert_eq!(Err(ErrNum::TimedOut), s.wait_timeout(start + 2));
+                assert!(time::ticks() >= start + 2);
+            });
+        }
+
+        fn wait_timeout_succeeds_when_posted() {
+            thread::with_scheduler(|| {
+                let s = Arc::new(Semaphore::new(0));
+
+                let d = thread::spawn({
+                    let s = s.clone();
+                    move || s.wait_timeout(time::ticks() + 1000)
+                });
+
+                thread::schedule();
+                s.post().expect("post returned Err");
+                assert_eq!(Ok(()), d.get());
+            });
+        }
+
+        fn wait_for_any_returns_posted_semaphore() {
+            thread::with_scheduler(|| {
+                let s1 = Arc::new(Semaphore::new(0));
+                let s2 = Arc::new(Semaphore::new(0));
+
+                let d = thread::spawn({
+                    let s2 = s2.clone();
+                    move || s2.post()
+                });
+
+                let objs: [Arc<dyn KObj>; 2] = [s1, s2.clone()];
+                assert_eq!(Ok(1), kobj::wait_for_any(&objs, time::ticks() + 1000));
+                assert_eq!(Ok(()), d.get());
+                s2.wait();
+            });
+        }
     }
 }
@@ -124,7 +124,7 @@ impl<T> Mutex<T> {
         let token = arch::disable_interrupts();
         let null = 0;
         while self.lock.compare_and_swap(null, file_line as usize, Ordering::SeqCst) != null {
-            // Do nothing
+            arch::spin_wait();
         }
         token
     }
@@ -1,6 +1,5 @@
 use crate::ptr::Align;
 use core::mem;
-use core::str;
 
 #[repr(C)]
 struct Header {
@@ -12,24 +11,42 @@ struct Header {
     pub mtime: [u8; 12],
     pub chksum: [u8; 8],
     pub typeflag: [u8; 1],
+    pub linkname: [u8; 100],
+    pub magic: [u8; 6],
+    pub version: [u8; 2],
+    pub uname: [u8; 32],
+    pub gname: [u8; 32],
+    pub devmajor: [u8; 8],
+    pub devminor: [u8; 8],
+    pub prefix: [u8; 155],
 }
 
 impl Header {
     pub fn parse_size(&self) -> usize {
-        let mut size = 0usize;
-        let mut j = 11;
-        let mut count = 1;
-
-        while j > 0 {
-            size += (self.size[j - 1] - ('0' as u8)) as usize * count;
-            j -= 1;
-            count *= 8;
-        }
+        parse_octal(&self.size[..])
+    }
+
+    pub fn parse_mode(&self) -> u32 {
+        parse_octal(&self.mode[..]) as u32
+    }
 
-        size
+    pub fn prefix(&self) -> &[u8] {
+        // GNU tar writes "ustar  \0" and uses the prefix field for something else
+        if &self.magic == b"ustar\0" {
+            nul_terminate(&self.prefix[..])
+        } else {
+            &[]
+        }
     }
 }
 
+fn parse_octal(s: &[u8]) -> usize {
+    s.iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b >= b'0' && b <= b'7')
+        .fold(0, |n, &b| n * 8 + (b - b'0') as usize)
+}
+
 fn nul_terminate(s: &[u8]) -> &[u8] {
     match s.iter().position(|b| *b == 0) {
         Some(index) => &s[0..index],
@@ -37,30 +54,81 @@ fn nul_terminate(s: &[u8]) -> &[u8] {
     }
 }
 
-pub fn locate<'a>(data: &'a [u8], filename: &str) -> Option<&'a [u8]> {
-    let mut offset = 0;
-    while offset < data.len() {
+fn path_components(s: &[u8]) -> impl Iterator<Item = &[u8]> {
+    s.split(|&b| b == b'/').filter(|c| !c.is_empty() && *c != b".")
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Other,
+}
+
+/// One file or directory in a tar archive.
+pub struct Entry<'a> {
+    prefix: &'a [u8],
+    filename: &'a [u8],
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    /// Returns the parts of the entry's path, without any empty or `.` components.
+    pub fn components(&self) -> impl Iterator<Item = &'a [u8]> {
+        path_components(self.prefix).chain(path_components(self.filename))
+    }
+}
+
+/// Iterates over the entries in a tar archive.
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+pub fn entries(data: &[u8]) -> Entries {
+    Entries { data, offset: 0 }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Entry<'a>> {
+        let data = self.data;
+        let header_end = self.offset.checked_add(512)?;
+        if header_end > data.len() {
+            return None;
+        }
+
         let header = unsafe {
-            let header_slice = &data[offset..offset + mem::size_of::<Header>()];
+            let header_slice = &data[self.offset..self.offset + mem::size_of::<Header>()];
             &*(header_slice.as_ptr() as *const Header)
         };
 
-        let header_filename = nul_terminate(&header.filename[..]);
-        if header_filename.len() == 0 {
-            break;
+        let filename = nul_terminate(&header.filename[..]);
+        if filename.len() == 0 {
+            return None;
         }
 
-        offset += 512;
-
         let size = header.parse_size();
-        if let Ok(header_filename) = str::from_utf8(header_filename) {
-            if header_filename == filename {
-                return Some(&data[offset..offset + size]);
-            }
-        }
+        let data_end = header_end.checked_add(size).filter(|&end| end <= data.len())?;
 
-        offset += Align::up(size, 512);
-    }
+        let kind = match header.typeflag[0] {
+            b'5' => EntryKind::Directory,
+            b'0' | b'\0' | b'7' if filename.ends_with(b"/") => EntryKind::Directory,
+            b'0' | b'\0' | b'7' => EntryKind::File,
+            _ => EntryKind::Other,
+        };
+
+        self.offset = header_end + Align::up(size, 512);
 
-    None
+        Some(Entry {
+            prefix: header.prefix(),
+            filename,
+            kind,
+            mode: header.parse_mode(),
+            data: &data[header_end..data_end],
+        })
+    }
 }
@@ -1,23 +1,43 @@
+use crate::arch;
 use crate::arch::cpu;
 use crate::arch::isr;
+use crate::arch::mmu;
+use crate::arch::smp;
 use crate::arch::thread;
 use crate::deferred::Deferred;
 use crate::prelude::*;
-use crate::process::Process;
+use crate::process::{self, Process};
 use crate::ptr::Align;
 use crate::singleton::Singleton;
-use crate::spin::Mutex;
+use crate::spin::{Mutex, StaticMutex, STATIC_MUTEX_INIT};
+use crate::time;
 use alloc::alloc::{self as alloc_mod, Layout};
 use alloc::collections::vec_deque::VecDeque;
 use alloc::sync::Arc;
 use bitflags::_core::mem::MaybeUninit;
+use core::cmp;
 use core::mem;
 use core::slice;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use libc::{self, jmp_buf};
+use syscall::{ErrNum, Result, DEFAULT_PRIORITY, MAX_PRIORITY};
 
 static SCHEDULER: Singleton<Mutex<SchedulerState>> = Singleton::new();
 
+/// Held by processors other than the first while they start or stop taking part in the registered scheduler, so that
+/// it can't go away underneath them.
+static JOIN_LOCK: StaticMutex = STATIC_MUTEX_INIT;
+
+/// One bit for each processor, apart from the first, that's taking part in the registered scheduler.
+static JOINED_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of timer ticks a thread can run for before it is preempted.
+pub const DEFAULT_QUANTUM: usize = 2;
+
+/// Number of timer ticks a runnable thread waits before it gets a turn ahead of threads with a higher priority, so
+/// that busy threads can't keep less important ones from ever running.
+pub const STARVATION_TICKS: usize = 20;
+
 fn setjmp() -> Option<jmp_buf> {
     let mut jmp_buf = MaybeUninit::uninit();
     unsafe {
@@ -44,21 +64,32 @@ impl HWToken {
 
 struct Thread {
     id: usize,
+    priority: u8,
+    /// Set on the thread that a CPU runs when it has nothing else to do. Idle threads never go in the run queues.
+    idle_cpu: Option<usize>,
     stack: &'static mut [u8],
     tls: Option<&'static mut [u8]>,
+    user_stack: Option<&'static mut [u8]>,
     process: Arc<Process>,
     exited: Deferred<i32>,
+    /// The tick count when the thread last went in a run queue.
+    runnable_since: usize,
 }
 
 impl Thread {
-    pub fn new(process: Arc<Process>, stack: &'static mut [u8]) -> Thread {
+    pub fn new(process: Arc<Process>, stack: &'static mut [u8], priority: u8) -> Thread {
         static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(0);
+        process.add_thread();
         Thread {
             id: NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed),
+            priority,
+            idle_cpu: None,
             stack,
             tls: None,
+            user_stack: None,
             process,
             exited: Deferred::new(),
+            runnable_since: 0,
         }
     }
 
@@ -81,73 +112,425 @@ impl Thread {
 
 pub struct BlockedThread(jmp_buf, Thread);
 
-struct SchedulerState {
+struct Timer {
+    id: usize,
+    deadline: usize,
+    f: Box<dyn FnOnce()>,
+}
+
+struct CpuState {
     current: Thread,
-    threads: VecDeque<BlockedThread>,
+    /// This CPU's idle thread, whenever some other thread is running.
+    idle: Option<BlockedThread>,
+    /// Parks the thread that this CPU has just switched away from. It runs on the new thread's stack, so that no
+    /// other CPU can pick up the old thread while its stack is still in use.
+    park: Option<Box<dyn FnOnce()>>,
+    ticks_left: usize,
+    need_resched: bool,
+    switching: bool,
+}
+
+impl CpuState {
+    fn new(current: Thread, idle: Option<BlockedThread>, quantum: usize) -> Self {
+        CpuState {
+            current,
+            idle,
+            park: None,
+            ticks_left: quantum,
+            need_resched: false,
+            switching: false,
+        }
+    }
+}
+
+struct SchedulerState {
+    /// One entry for each CPU that this scheduler can use, which is `None` while that CPU isn't taking part.
+    cpus: Vec<Option<CpuState>>,
+    idle_process: Arc<Process>,
+    /// Runnable threads, with one queue for each priority level.
+    run_queues: [VecDeque<BlockedThread>; MAX_PRIORITY as usize + 1],
     garbage_stacks: Vec<&'static mut [u8]>,
+    quantum: usize,
+    /// Set when the scheduler is about to go away, to send the other CPUs back to their idle threads.
+    stopping: bool,
+    timers: Vec<Timer>,
+    next_timer_id: usize,
+}
+
+impl SchedulerState {
+    fn cpu(&self) -> &CpuState {
+        self.cpus[arch::cpu_index()]
+            .as_ref()
+            .expect("this CPU isn't running the scheduler")
+    }
+
+    fn cpu_mut(&mut self) -> &mut CpuState {
+        self.cpus[arch::cpu_index()]
+            .as_mut()
+            .expect("this CPU isn't running the scheduler")
+    }
+
+    fn current(&self) -> &Thread {
+        &self.cpu().current
+    }
+
+    fn current_mut(&mut self) -> &mut Thread {
+        &mut self.cpu_mut().current
+    }
+
+    /// Returns `true` if this CPU should stop running threads and go back to its idle thread, ready to leave.
+    fn must_go_idle(&self) -> bool {
+        self.stopping && arch::cpu_index() != 0 && self.cpu().idle.is_some()
+    }
+
+    fn pop_runnable(&mut self) -> Option<BlockedThread> {
+        if self.must_go_idle() {
+            return self.cpu_mut().idle.take();
+        }
+
+        if self.stopping && arch::cpu_index() != 0 {
+            return None;
+        }
+
+        match self.run_queues.iter_mut().rev().find_map(VecDeque::pop_front) {
+            Some(thread) => Some(thread),
+            None => self.cpu_mut().idle.take(),
+        }
+    }
+
+    fn has_runnable(&self, min_priority: u8) -> bool {
+        self.run_queues[min_priority as usize..]
+            .iter()
+            .any(|queue| !queue.is_empty())
+    }
+
+    /// Moves threads that have been runnable for `STARVATION_TICKS` to the back of the highest priority queue. They
+    /// go back to their own queue the next time they're resumed.
+    fn boost_starved(&mut self) {
+        let now = time::ticks();
+        let top = MAX_PRIORITY as usize;
+        for priority in 0..top {
+            while self.run_queues[priority].front().map_or(false, |thread| {
+                now.saturating_sub(thread.1.runnable_since) >= STARVATION_TICKS
+            }) {
+                let mut thread = self.run_queues[priority].pop_front().unwrap();
+                thread.1.runnable_since = now;
+                self.run_queues[top].push_back(thread);
+            }
+        }
+    }
 }
 
 impl Drop for SchedulerState {
     fn drop(&mut self) {
-        let garbage_stacks = mem::replace(&mut self.garbage_stacks, Vec::new());
+        let mut garbage_stacks = mem::replace(&mut self.garbage_stacks, Vec::new());
+
+        // Idle threads that were spawned have their own stacks; the other CPUs' idle threads use their boot stacks
+        for cpu in self.cpus.iter_mut().flatten() {
+            if let Some(idle) = &mut cpu.idle {
+                garbage_stacks.push(mem::replace(&mut idle.1.stack, &mut []));
+            }
+        }
 
         for stack in garbage_stacks {
-            unsafe { alloc_mod::dealloc(stack.as_mut_ptr(), Layout::from_size_align_unchecked(stack.len(), 16)) };
+            if !stack.is_empty() {
+                unsafe { alloc_mod::dealloc(stack.as_mut_ptr(), Layout::from_size_align_unchecked(stack.len(), 16)) };
+            }
         }
     }
 }
 
+/// Returns the registered scheduler, if the current CPU is taking part in it.
+fn this_cpu_sched() -> Option<&'static Mutex<SchedulerState>> {
+    let index = arch::cpu_index();
+    if index == 0 || JOINED_CPUS.load(Ordering::SeqCst) & (1 << index) != 0 {
+        SCHEDULER.get()
+    } else {
+        None
+    }
+}
+
+fn current_sched() -> &'static Mutex<SchedulerState> {
+    this_cpu_sched().expect("no Scheduler registered")
+}
+
+macro_rules! lock_sched {
+    () => {
+        lock!(current_sched())
+    };
+}
+
+/// Runs `f` with a scheduler that can use every CPU in the machine.
 pub fn with_scheduler<F: FnOnce()>(f: F) {
+    with_scheduler_on_cpus(smp::cpu_count(), f)
+}
+
+/// Runs `f` with a scheduler that uses no more than `cpu_count` CPUs, starting with the boot processor.
+pub fn with_scheduler_on_cpus<F: FnOnce()>(cpu_count: usize, f: F) {
     let idle_process = Arc::new(Process::for_kernel().unwrap());
+    let cpu_count = cmp::max(1, cmp::min(cpu_count, smp::cpu_count()));
+
+    let mut idle = new_thread(idle_process.clone(), 0, Box::new(idle_loop));
+    idle.1.idle_cpu = Some(0);
+
+    let mut cpus = Vec::new();
+    cpus.resize_with(cpu_count, || None);
+    cpus[0] = Some(CpuState::new(
+        Thread::new(idle_process.clone(), &mut [], DEFAULT_PRIORITY),
+        Some(idle),
+        DEFAULT_QUANTUM,
+    ));
 
     let state = SchedulerState {
-        current: Thread::new(idle_process.clone(), &mut []),
-        threads: VecDeque::new(),
+        cpus,
+        idle_process: idle_process.clone(),
+        run_queues: Default::default(),
         garbage_stacks: Vec::new(),
+        quantum: DEFAULT_QUANTUM,
+        stopping: false,
+        timers: Vec::new(),
+        next_timer_id: 0,
     };
 
-    let _d = SCHEDULER.register(Mutex::new(state));
+    let d = SCHEDULER.register(Mutex::new(state));
+    let timer = isr::register_irq_handler(0, tick);
     unsafe { idle_process.switch() };
-    f()
+    f();
+    stop_other_cpus();
+    mem::drop(timer);
+
+    let _join = lock!(JOIN_LOCK);
+    mem::drop(d);
 }
 
-fn current_sched() -> &'static Mutex<SchedulerState> {
-    SCHEDULER.get().expect("no Scheduler registered")
+/// Waits for the other CPUs to leave the scheduler. The thread calling this might itself be running on one of them, in
+/// which case it moves back to the boot processor.
+fn stop_other_cpus() {
+    {
+        let _join = lock!(JOIN_LOCK);
+        lock_sched!().stopping = true;
+    }
+
+    while lock_sched!().cpus[1..].iter().any(Option::is_some) {
+        cpu::wait_for_interrupt();
+    }
 }
 
-macro_rules! lock_sched {
-    () => {
-        lock!(current_sched())
+fn idle_loop() {
+    loop {
+        schedule();
+        cpu::wait_for_interrupt();
+    }
+}
+
+/// Runs threads on a CPU other than the first, whenever a scheduler is registered that has room for it.
+pub fn run_ap(index: usize) -> ! {
+    loop {
+        if join_scheduler(index) {
+            loop {
+                schedule();
+                if leave_scheduler_if_stopping(index) {
+                    break;
+                }
+
+                cpu::wait_for_interrupt();
+            }
+        }
+
+        cpu::wait_for_interrupt();
+    }
+}
+
+fn join_scheduler(index: usize) -> bool {
+    let _join = lock!(JOIN_LOCK);
+    let sched = match SCHEDULER.get() {
+        Some(sched) => sched,
+        None => return false,
+    };
+
+    let (process, quantum) = {
+        let state = lock!(sched);
+        if state.stopping || index >= state.cpus.len() {
+            return false;
+        }
+
+        (state.idle_process.clone(), state.quantum)
     };
+
+    // The CPU's boot stack becomes the stack of its idle thread
+    let mut idle = Thread::new(process.clone(), &mut [], 0);
+    idle.idle_cpu = Some(index);
+    lock!(sched).cpus[index] = Some(CpuState::new(idle, None, quantum));
+    JOINED_CPUS.fetch_or(1 << index, Ordering::SeqCst);
+    unsafe { process.switch() };
+    true
+}
+
+fn leave_scheduler_if_stopping(index: usize) -> bool {
+    let mut state = lock_sched!();
+    if !state.stopping {
+        return false;
+    }
+
+    // The scheduler's page tables are about to be freed
+    unsafe { mmu::switch_to_init() };
+    JOINED_CPUS.fetch_and(!(1 << index), Ordering::SeqCst);
+    state.cpus[index] = None;
+    true
 }
 
 pub fn try_current_process() -> Option<Arc<Process>> {
-    if let Some(sched) = SCHEDULER.get() {
-        Some(lock!(sched).current.process.clone())
+    if let Some(sched) = this_cpu_sched() {
+        Some(lock!(sched).current().process.clone())
     } else {
         None
     }
 }
 
 pub fn current_process() -> Arc<Process> {
-    lock_sched!().current.process.clone()
+    lock_sched!().current().process.clone()
+}
+
+/// Counts down the quantum of the thread running on this CPU.
+fn tick_cpu(state: &mut SchedulerState) {
+    let quantum = state.quantum;
+    let must_go_idle = state.must_go_idle();
+    let cpu = state.cpu_mut();
+    if cpu.ticks_left > 1 && !must_go_idle {
+        cpu.ticks_left -= 1;
+    } else {
+        cpu.ticks_left = quantum;
+        cpu.need_resched = true;
+    }
+}
+
+fn tick() {
+    time::tick();
+
+    if let Some(sched) = this_cpu_sched() {
+        let expired = {
+            let mut state = lock!(sched);
+            tick_cpu(&mut state);
+            state.boost_starved();
+
+            let now = time::ticks();
+            let mut expired = Vec::new();
+            let mut i = 0;
+            while i < state.timers.len() {
+                if state.timers[i].deadline <= now {
+                    expired.push(state.timers.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+
+            expired
+        };
+
+        for timer in expired {
+            (timer.f)();
+        }
+    }
+}
+
+/// Called from the local timer of each CPU apart from the first, which gets `tick` instead.
+pub fn local_tick() {
+    if let Some(sched) = this_cpu_sched() {
+        tick_cpu(&mut lock!(sched));
+    }
+}
+
+fn add_timer<F: FnOnce() + 'static>(deadline: usize, f: F) -> usize {
+    let mut state = lock_sched!();
+    let id = state.next_timer_id;
+    state.next_timer_id += 1;
+    state.timers.push(Timer {
+        id,
+        deadline,
+        f: Box::new(f),
+    });
+    id
+}
+
+fn cancel_timer(id: usize) {
+    let mut state = lock_sched!();
+    if let Some(pos) = state.timers.iter().position(|timer| timer.id == id) {
+        state.timers.swap_remove(pos);
+    }
+}
+
+/// Called by a thread as soon as it's running on its own stack.
+fn finish_switch() {
+    let park = {
+        let mut state = lock_sched!();
+        let cpu = state.cpu_mut();
+        cpu.switching = false;
+        cpu.park.take()
+    };
+
+    if let Some(park) = park {
+        park();
+    }
+
+    assert!(
+        cpu::interrupts_enabled(),
+        "previous thread was holding a spinlock when it was blocked"
+    );
+}
+
+/// Sets the number of timer ticks each thread runs for before another runnable thread gets the CPU.
+pub fn set_quantum(ticks: usize) {
+    assert!(ticks > 0, "quantum must be at least one tick");
+    let mut state = lock_sched!();
+    state.quantum = ticks;
+    for cpu in state.cpus.iter_mut().flatten() {
+        cpu.ticks_left = cpu.ticks_left.min(ticks);
+    }
+}
+
+/// Switches to the next runnable thread if the current thread has used up its quantum.
+///
+/// Called on the way out of an interrupt handler, once the interrupt controller has been acknowledged.
+pub fn preempt() {
+    if let Some(sched) = this_cpu_sched() {
+        {
+            let mut state = lock!(sched);
+            let cpu = state.cpu_mut();
+            if !cpu.need_resched || cpu.switching {
+                return;
+            }
+
+            cpu.need_resched = false;
+        }
+
+        schedule();
+    }
 }
 
 pub fn block<Park: FnOnce(BlockedThread)>(park: Park) -> bool {
     let mut state = lock_sched!();
-    match state.threads.pop_front() {
+    match state.pop_runnable() {
         Some(BlockedThread(new_jmp_buf, mut new_current)) => {
             let switch = {
                 let new_token = new_current.hw_token();
-                let old_current = mem::replace(&mut state.current, new_current);
+                let quantum = state.quantum;
+                let cpu = state.cpu_mut();
+                let old_current = mem::replace(&mut cpu.current, new_current);
+
+                // Until the new thread is running on its own stack, the timer must not preempt us
+                cpu.switching = true;
+                cpu.ticks_left = quantum;
+                cpu.need_resched = false;
                 mem::drop(state);
 
                 move |old_jmp_buf| {
-                    park(BlockedThread(old_jmp_buf, old_current));
-                    assert!(
-                        cpu::interrupts_enabled(),
-                        "current thread is holding a spinlock and it's about to be blocked"
-                    );
+                    let park: Box<dyn FnOnce() + '_> = Box::new(move || park(BlockedThread(old_jmp_buf, old_current)));
+
+                    // The old thread can't run again until `park` has run, so anything `park` borrows from its stack
+                    // outlives it
+                    let park: Box<dyn FnOnce()> = unsafe { mem::transmute(park) };
+                    lock_sched!().cpu_mut().park = Some(park);
+
                     unsafe {
                         new_token.switch();
                         libc::longjmp(&new_jmp_buf, 1)
@@ -159,6 +542,7 @@ pub fn block<Park: FnOnce(BlockedThread)>(park: Park) -> bool {
                 Some(old_jmp_buf) => switch(old_jmp_buf),
                 None => {
                     mem::forget(switch);
+                    finish_switch();
                     true
                 }
             }
@@ -168,20 +552,159 @@ pub fn block<Park: FnOnce(BlockedThread)>(park: Park) -> bool {
     }
 }
 
+/// Like `block`, but gives up once the tick count reaches `deadline`, or when the current process is killed.
+/// `deadline` can be `usize::MAX` to wait until the thread is woken or killed.
+///
+/// If the wait is given up, `cancel` is called with the id of the blocked thread, and should take that thread back out
+/// of wherever `park` put it. Returns `Err(ErrNum::TimedOut)` if the deadline passed and `Err(ErrNum::Interrupted)`
+/// if the process was killed; callers should loop on `Ok`, since it doesn't guarantee that whatever they were waiting
+/// for has happened.
+pub fn block_until<Park, Cancel>(deadline: usize, park: Park, cancel: Cancel) -> Result<()>
+where
+    Park: FnOnce(BlockedThread),
+    Cancel: FnOnce(usize) -> Option<BlockedThread> + 'static,
+{
+    let id = current_thread_id();
+    let process = current_process();
+    if process.killed().is_some() {
+        return Err(ErrNum::Interrupted);
+    }
+
+    // Whichever of the timer and `Process::kill` comes first takes the thread back out and says why
+    let outcome = Arc::new(Mutex::new(Ok(())));
+    let cancel: Arc<dyn Fn(ErrNum)> = Arc::new({
+        let cancel = Mutex::new(Some(cancel));
+        let outcome = outcome.clone();
+        move |num: ErrNum| {
+            let cancel = lock!(cancel).take();
+            if let Some(thread) = cancel.and_then(|cancel| cancel(id)) {
+                *lock!(outcome) = Err(num);
+                thread.resume();
+            }
+        }
+    });
+
+    let timer_id = if deadline < usize::MAX {
+        let cancel = cancel.clone();
+        Some(add_timer(deadline, move || cancel(ErrNum::TimedOut)))
+    } else {
+        None
+    };
+
+    let found_new_thread = block({
+        let process = process.clone();
+        let outcome = outcome.clone();
+        move |thread| {
+            if let Some(thread) = process.park_thread(thread, park, cancel) {
+                *lock!(outcome) = Err(ErrNum::Interrupted);
+                thread.resume();
+            }
+        }
+    });
+
+    if let Some(timer_id) = timer_id {
+        cancel_timer(timer_id);
+    }
+
+    process.forget_parked_thread(id);
+    if !found_new_thread {
+        // Only an interrupt can wake us now; the caller checks its condition and the deadline again
+        cpu::wait_for_interrupt();
+    }
+
+    mem::replace(&mut *lock!(outcome), Ok(()))
+}
+
+/// Removes the thread with the given id from a queue of blocked threads.
+pub fn remove_waiter(waiters: &mut VecDeque<BlockedThread>, id: usize) -> Option<BlockedThread> {
+    let pos = waiters.iter().position(|thread| thread.id() == id)?;
+    waiters.remove(pos)
+}
+
 impl BlockedThread {
-    pub fn resume(self) {
+    pub fn id(&self) -> usize {
+        self.1.id
+    }
+
+    pub fn resume(mut self) {
+        self.1.runnable_since = time::ticks();
+
         let mut state = lock_sched!();
-        state.threads.push_back(self);
+        if let Some(index) = self.1.idle_cpu {
+            state.cpus[index].as_mut().unwrap().idle = Some(self);
+            return;
+        }
+
+        // Take a CPU away from its thread as soon as a more important one is ready, preferring CPUs with nothing to do
+        let priority = self.1.priority;
+        let target = state
+            .cpus
+            .iter_mut()
+            .flatten()
+            .filter(|cpu| cpu.current.idle_cpu.is_some() || cpu.current.priority < priority)
+            .min_by_key(|cpu| cpu.current.idle_cpu.map_or(cpu.current.priority as usize + 1, |_| 0));
+
+        if let Some(cpu) = target {
+            cpu.need_resched = true;
+        }
+
+        state.run_queues[priority as usize].push_back(self);
     }
 }
 
+/// Gives the CPU to the next runnable thread with the same or a higher priority, if there is one.
 pub fn schedule() {
+    {
+        let state = lock_sched!();
+        if !state.must_go_idle() && !state.has_runnable(state.current().priority) {
+            return;
+        }
+    }
+
     block(move |thread| thread.resume());
 }
 
+/// Changes the priority of the current thread. Lowering it lets any threads that now outrank it run straight away.
+pub fn set_priority(priority: u8) -> Result<()> {
+    if priority > MAX_PRIORITY {
+        return Err(ErrNum::InvalidArgument);
+    }
+
+    lock_sched!().current_mut().priority = priority;
+    schedule();
+    Ok(())
+}
+
+/// Blocks the current thread until the tick count reaches `deadline`.
+pub fn sleep_until(deadline: usize) {
+    while time::ticks() < deadline {
+        let found_new_thread = block(move |thread| {
+            add_timer(deadline, move || thread.resume());
+        });
+
+        if !found_new_thread {
+            cpu::wait_for_interrupt();
+        }
+    }
+}
+
 pub fn exit(code: i32) -> ! {
-    let exited = lock_sched!().current.exited.clone();
-    exited.resolve(code);
+    let (exited, process, user_memory) = {
+        let mut state = lock_sched!();
+        let current = state.current_mut();
+        let user_memory = [current.tls.take(), current.user_stack.take()];
+        (current.exited.clone(), current.process.clone(), user_memory)
+    };
+
+    for slice in user_memory.iter().flatten() {
+        process::free(slice.as_ptr() as *mut u8);
+    }
+
+    process.remove_thread();
+    mem::drop(process);
+
+    // A thread that's been killed might already have an exit code
+    exited.try_resolve(code);
 
     block(move |thread| {
         let mut state = lock_sched!();
@@ -191,18 +714,36 @@ pub fn exit(code: i32) -> ! {
     panic!("exit: no more threads")
 }
 
+/// Exits the current thread if its process has been killed. Called where the thread can stop without leaking
+/// anything, such as on its way back to user mode.
+pub fn exit_if_killed() {
+    let killed = current_process().killed();
+    if let Some(code) = killed {
+        exit(code);
+    }
+}
+
 pub fn current_thread_id() -> usize {
-    lock_sched!().current.id
+    lock_sched!().current().id
 }
 
-fn spawn_inner<'a>(process: Arc<Process>, start: Box<dyn FnOnce() + 'a>) -> Deferred<i32> {
+fn new_thread<'a>(process: Arc<Process>, priority: u8, start: Box<dyn FnOnce() + 'a>) -> BlockedThread {
     let stack_len = 4096 * 8;
     let stack_base_ptr = unsafe { alloc_mod::alloc_zeroed(Layout::from_size_align_unchecked(stack_len, 16)) };
-    let b: Box<dyn FnOnce() + 'a> = Box::new(start);
+    let b: Box<dyn FnOnce() + 'a> = Box::new(move || {
+        finish_switch();
+        start()
+    });
     let jmp_buf = thread::new_jmp_buf(b, unsafe { stack_base_ptr.offset(stack_len as isize) });
-    let thread = Thread::new(process, unsafe { slice::from_raw_parts_mut(stack_base_ptr, stack_len) });
-    let exited = thread.exited.clone();
-    BlockedThread(jmp_buf, thread).resume();
+    let stack = unsafe { slice::from_raw_parts_mut(stack_base_ptr, stack_len) };
+    BlockedThread(jmp_buf, Thread::new(process, stack, priority))
+}
+
+fn spawn_inner<'a>(process: Arc<Process>, start: Box<dyn FnOnce() + 'a>) -> Deferred<i32> {
+    let priority = lock_sched!().current().priority;
+    let thread = new_thread(process, priority, start);
+    let exited = thread.1.exited.clone();
+    thread.resume();
     exited
 }
 
@@ -215,15 +756,16 @@ where
 }
 
 pub fn spawn<'a, T: FnOnce() -> i32 + Send + 'a>(start: T) -> Deferred<i32> {
-    let process = lock_sched!().current.process.clone();
+    let process = lock_sched!().current().process.clone();
     spawn_remote(process, start)
 }
 
 pub fn set_tls(tls: &'static mut [u8]) {
     let hw_token = {
         let mut state = lock_sched!();
-        state.current.tls = Some(tls);
-        state.current.hw_token()
+        let current = state.current_mut();
+        current.tls = Some(tls);
+        current.hw_token()
     };
 
     unsafe {
@@ -231,9 +773,17 @@ pub fn set_tls(tls: &'static mut [u8]) {
     }
 }
 
+/// Records the user-mode stack of the current thread, so that it can be freed when the thread exits.
+pub fn set_user_stack(stack: &'static mut [u8]) {
+    lock_sched!().current_mut().user_stack = Some(stack);
+}
+
 #[cfg(feature = "test")]
 pub mod test {
     use super::*;
+    use crate::kobj::{self, KObj};
+    use core::sync::atomic::{spin_loop_hint, AtomicBool};
+    use syscall::{POLL_HUP, POLL_READ};
 
     test! {
         fn can_spawn_exit_thread() {
@@ -243,6 +793,20 @@ pub mod test {
             });
         }
 
+        fn poll_sees_exit_after_wait() {
+            with_scheduler(|| {
+                let d = spawn(|| 123);
+                assert_eq!(123, d.clone().get());
+                assert!(!d.try_resolve(456));
+
+                let objs: [Arc<dyn KObj>; 1] = [Arc::new(d)];
+                let mut events = [POLL_READ];
+                assert_eq!(Ok(1), kobj::poll(&objs, &mut events, time::ticks()));
+                assert_eq!([POLL_HUP], events);
+                assert_eq!(Ok(0), kobj::wait_for_any(&objs, time::ticks()));
+            });
+        }
+
         fn can_spawn_exit_two_threads() {
             with_scheduler(|| {
                 let d1 = spawn(|| 456);
@@ -275,5 +839,207 @@ pub mod test {
                 assert_eq!(1234 * 2, d.get());
             });
         }
+
+        fn cpu_bound_thread_is_preempted() {
+            with_scheduler(|| {
+                let counter = Arc::new(AtomicUsize::new(0));
+                let d = spawn({
+                    let counter = counter.clone();
+                    move || {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        0
+                    }
+                });
+
+                // Never blocks: the other thread only gets to run if the timer takes the CPU away from us
+                while counter.load(Ordering::SeqCst) == 0 {
+                    spin_loop_hint();
+                }
+
+                assert_eq!(0, d.get());
+            });
+        }
+
+        fn cpu_bound_thread_does_not_starve_others() {
+            with_scheduler(|| {
+                let stop = Arc::new(AtomicBool::new(false));
+                let spinner = spawn({
+                    let stop = stop.clone();
+                    move || {
+                        let mut spins = 0;
+                        while !stop.load(Ordering::SeqCst) {
+                            spins += 1;
+                            spin_loop_hint();
+                        }
+
+                        (spins > 0) as i32
+                    }
+                });
+
+                let d1 = spawn(|| 456);
+                let d2 = spawn(|| 789);
+                assert_eq!(456, d1.get());
+                assert_eq!(789, d2.get());
+
+                stop.store(true, Ordering::SeqCst);
+                assert_eq!(1, spinner.get());
+            });
+        }
+
+        fn can_set_quantum() {
+            with_scheduler(|| {
+                set_quantum(1);
+
+                let counter = Arc::new(AtomicUsize::new(0));
+                let deferreds = (0..3)
+                    .map(|_| {
+                        let counter = counter.clone();
+                        spawn(move || {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                            while counter.load(Ordering::SeqCst) < 3 {
+                                spin_loop_hint();
+                            }
+
+                            0
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                for d in deferreds {
+                    assert_eq!(0, d.get());
+                }
+            });
+        }
+
+        fn higher_priority_thread_runs_first() {
+            // Another CPU could start a low priority thread while this one is busy
+            with_scheduler_on_cpus(1, || {
+                let order = Arc::new(Mutex::new(Vec::new()));
+                let spawn_at = |priority, name| {
+                    set_priority(priority).unwrap();
+                    let order = order.clone();
+                    spawn(move || {
+                        lock!(order).push(name);
+                        0
+                    })
+                };
+
+                // Each thread starts at the priority of the thread that spawned it
+                let low = spawn_at(0, "low");
+                let high = spawn_at(MAX_PRIORITY, "high");
+                let normal = spawn_at(DEFAULT_PRIORITY, "normal");
+                assert_eq!(0, low.get());
+                assert_eq!(0, high.get());
+                assert_eq!(0, normal.get());
+                assert_eq!(vec!["high", "normal", "low"], *lock!(order));
+                assert_eq!(Some(ErrNum::InvalidArgument), set_priority(MAX_PRIORITY + 1).err());
+            });
+        }
+
+        fn low_priority_thread_is_not_starved() {
+            with_scheduler_on_cpus(1, || {
+                let stop = Arc::new(AtomicBool::new(false));
+
+                set_priority(0).unwrap();
+                let low = spawn({
+                    let stop = stop.clone();
+                    move || {
+                        stop.store(true, Ordering::SeqCst);
+                        0
+                    }
+                });
+
+                // Never gives up the CPU until the low priority thread has run
+                set_priority(MAX_PRIORITY).unwrap();
+                let high = spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        spin_loop_hint();
+                    }
+
+                    0
+                });
+
+                set_priority(DEFAULT_PRIORITY).unwrap();
+                assert_eq!(0, low.get());
+                assert_eq!(0, high.get());
+            });
+        }
+
+        fn threads_at_same_priority_take_turns() {
+            with_scheduler_on_cpus(1, || {
+                // Only let threads switch when they ask to
+                set_quantum(1000);
+
+                let order = Arc::new(Mutex::new(Vec::new()));
+                let deferreds = (0..3)
+                    .map(|id| {
+                        let order = order.clone();
+                        spawn(move || {
+                            for _ in 0..2 {
+                                lock!(order).push(id);
+                                schedule();
+                            }
+
+                            0
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                for d in deferreds {
+                    assert_eq!(0, d.get());
+                }
+
+                assert_eq!(vec![0, 1, 2, 0, 1, 2], *lock!(order));
+            });
+        }
+
+        fn threads_run_on_every_cpu() {
+            with_scheduler(|| {
+                let cpu_count = smp::cpu_count();
+                let all_cpus = (1 << cpu_count) - 1;
+                let seen = Arc::new(AtomicUsize::new(0));
+                let deferreds = (0..cpu_count)
+                    .map(|_| {
+                        let seen = seen.clone();
+                        spawn(move || {
+                            // Each CPU picks up one of these threads, since none of them finish until every CPU has
+                            while seen.fetch_or(1 << arch::cpu_index(), Ordering::SeqCst) != all_cpus {
+                                spin_loop_hint();
+                            }
+
+                            0
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                for d in deferreds {
+                    assert_eq!(0, d.get());
+                }
+
+                assert_eq!(all_cpus, seen.load(Ordering::SeqCst));
+            });
+        }
+
+        fn can_sleep() {
+            with_scheduler(|| {
+                let start = time::ticks();
+                sleep_until(start + 3);
+                assert!(time::ticks() >= start + 3);
+            });
+        }
+
+        fn sleeping_thread_lets_others_run() {
+            with_scheduler(|| {
+                let sleeper = spawn(|| {
+                    let deadline = time::ticks() + 5;
+                    sleep_until(deadline);
+                    (time::ticks() >= deadline) as i32
+                });
+
+                let d = spawn(|| 456);
+                assert_eq!(456, d.get());
+                assert_eq!(1, sleeper.get());
+            });
+        }
     }
 }
@@ -0,0 +1,33 @@
+//! Kernel clock, driven by the timer interrupt.
+
+use crate::arch::pit::TICK_HZ;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const NANOS_PER_TICK: u64 = 1_000_000_000 / TICK_HZ as u64;
+
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Returns the number of timer ticks since the timer started.
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Returns a monotonic time in nanoseconds.
+pub fn now() -> u64 {
+    ticks() as u64 * NANOS_PER_TICK
+}
+
+/// Converts a duration to a number of ticks, rounding up so that waits are never cut short.
+pub fn ticks_from_ns(ns: u64) -> usize {
+    let ticks = ns / NANOS_PER_TICK + if ns % NANOS_PER_TICK != 0 { 1 } else { 0 };
+    ticks as usize
+}
+
+/// Returns the tick at which a timeout of `ns` nanoseconds, starting now, expires.
+pub fn deadline_after(ns: u64) -> usize {
+    ticks().saturating_add(ticks_from_ns(ns))
+}
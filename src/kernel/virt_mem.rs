@@ -13,6 +13,16 @@ struct Block<T> {
     tag: Option<T>,
 }
 
+impl<T> Block<T> {
+    fn unused() -> Self {
+        Block {
+            ptr: phys_mem::PAGE_SIZE as *mut u8,
+            len: usize::MAX,
+            tag: None,
+        }
+    }
+}
+
 struct VirtualState<T> {
     blocks: Vec<Block<T>>,
 }
@@ -47,17 +57,28 @@ impl<T> VirtualState<T> {
         Ok(orig_ptr)
     }
 
-    fn reserve(&mut self, ptr: *mut u8, len: usize, tag: T) -> bool {
-        let (ptr, len) = Align::range(ptr, len, phys_mem::PAGE_SIZE);
+    fn reserve(&mut self, ptr: *mut u8, len: usize, tag: T) -> Result<()> {
+        if len == 0 {
+            return Err(ErrNum::InvalidArgument);
+        }
+
+        let start = Align::down(ptr as usize, phys_mem::PAGE_SIZE);
+        let end = (ptr as usize)
+            .checked_add(len)
+            .and_then(|end| end.checked_add(phys_mem::PAGE_SIZE - 1))
+            .map(|end| Align::down(end, phys_mem::PAGE_SIZE))
+            .ok_or(ErrNum::InvalidArgument)?;
 
-        let pos = match self
+        let pos = self
             .blocks
             .iter()
-            .position(|block| block.tag.is_none() && block.ptr <= ptr && block.len >= len)
-        {
-            Some(pos) => pos,
-            None => return false,
-        };
+            .position(|block| {
+                let block_start = block.ptr as usize;
+                block.tag.is_none() && block_start <= start && end - block_start <= block.len
+            })
+            .ok_or(ErrNum::InvalidArgument)?;
+
+        let (ptr, len) = (start as *mut u8, end - start);
 
         let (orig_len, len0) = {
             let block0 = &mut self.blocks[pos];
@@ -80,7 +101,15 @@ impl<T> VirtualState<T> {
 
         self.blocks.insert(pos + 1, block1);
         self.blocks.insert(pos + 2, block2);
-        true
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Vec<(*mut u8, usize, T)> {
+        let blocks = mem::replace(&mut self.blocks, vec![Block::unused()]);
+        blocks
+            .into_iter()
+            .filter_map(|block| block.tag.map(|tag| (block.ptr, block.len, tag)))
+            .collect()
     }
 
     fn find_block_position(&self, ptr: *mut u8) -> Option<usize> {
@@ -108,6 +137,50 @@ impl<T> VirtualState<T> {
         Some(info)
     }
 
+    fn tag_mut(&mut self, ptr: *mut u8) -> Option<(*mut u8, usize, &mut T)> {
+        let pos = self.find_block_position(ptr)?;
+        let block = &mut self.blocks[pos];
+        let (ptr, len) = (block.ptr, block.len);
+        block.tag.as_mut().map(|tag| (ptr, len, tag))
+    }
+
+    fn split<F: FnOnce(&mut T, usize)>(&mut self, ptr: *mut u8, f: F) -> bool
+    where
+        T: Clone,
+    {
+        let pos = match self.find_block_position(ptr) {
+            Some(pos) => pos,
+            None => return false,
+        };
+
+        let block2 = {
+            let block1 = &mut self.blocks[pos];
+            if block1.ptr == ptr {
+                return block1.tag.is_some();
+            }
+
+            let len1 = ptr::bytes_between(block1.ptr, ptr);
+            let mut tag = match block1.tag {
+                Some(ref tag) => tag.clone(),
+                None => return false,
+            };
+
+            f(&mut tag, len1);
+
+            let block2 = Block {
+                ptr,
+                len: block1.len - len1,
+                tag: Some(tag),
+            };
+
+            block1.len = len1;
+            block2
+        };
+
+        self.blocks.insert(pos + 1, block2);
+        true
+    }
+
     fn tag_at(&self, ptr: *mut u8) -> Option<(*mut u8, usize, &T)> {
         match self.find_block_position(ptr) {
             Some(pos) => {
@@ -128,11 +201,7 @@ impl<T> VirtualTree<T> {
     pub fn new() -> Self {
         VirtualTree {
             state: Mutex::new(VirtualState {
-                blocks: vec![Block {
-                    ptr: phys_mem::PAGE_SIZE as *mut u8,
-                    len: usize::MAX,
-                    tag: None,
-                }],
+                blocks: vec![Block::unused()],
             }),
         }
     }
@@ -150,13 +219,32 @@ impl<T> VirtualTree<T> {
         Ok(slice)
     }
 
-    pub fn reserve(&self, slice: &mut [u8], tag: T) -> bool {
+    /// Marks the pages that `slice` touches as in use. Fails with `ErrNum::InvalidArgument` unless they all lie in one
+    /// free block.
+    pub fn reserve(&self, slice: &mut [u8], tag: T) -> Result<()> {
         lock!(self.state).reserve(slice.as_mut_ptr(), slice.len(), tag)
     }
 
     pub fn free(&self, p: *mut u8) -> Option<(usize, Option<T>)> {
         lock!(self.state).free(p)
     }
+
+    /// Frees every block at once, and returns the ones that were in use.
+    pub fn drain(&self) -> Vec<(&mut [u8], T)> {
+        let blocks = lock!(self.state).drain();
+        blocks
+            .into_iter()
+            .map(|(ptr, len, tag)| (unsafe { slice::from_raw_parts_mut(ptr, len) }, tag))
+            .collect()
+    }
+
+    /// Changes the tag of the block containing `p`, and returns the whole block.
+    pub fn update_tag<F: FnOnce(&mut T)>(&self, p: *mut u8, f: F) -> Option<&mut [u8]> {
+        let mut state = lock!(self.state);
+        let (ptr, len, tag) = state.tag_mut(p)?;
+        f(tag);
+        Some(unsafe { slice::from_raw_parts_mut(ptr, len) })
+    }
 }
 
 impl<T: Clone> VirtualTree<T> {
@@ -167,6 +255,25 @@ impl<T: Clone> VirtualTree<T> {
             (slice, tag)
         })
     }
+
+    /// Splits the block containing `p` in two, so that a block starts at `p`. `f` is given the tag for the second
+    /// half, and how far into the original block it starts. Returns false if `p` isn't in a block that's in use.
+    pub fn split<F: FnOnce(&mut T, usize)>(&self, p: *mut u8, f: F) -> bool {
+        assert!(Align::is_aligned(p, phys_mem::PAGE_SIZE));
+        lock!(self.state).split(p, f)
+    }
+
+    /// Returns every block that's in use, leaving them in place.
+    pub fn blocks(&self) -> Vec<(&mut [u8], T)> {
+        lock!(self.state)
+            .blocks
+            .iter()
+            .filter_map(|block| {
+                let tag = block.tag.as_ref()?.clone();
+                Some((unsafe { slice::from_raw_parts_mut(block.ptr, block.len) }, tag))
+            })
+            .collect()
+    }
 }
 
 #[cfg(feature = "test")]
@@ -193,7 +300,7 @@ pub mod test {
            let tree = VirtualTree::new();
            assert_eq!(1, tree.block_count());
 
-           assert!(tree.reserve(unsafe { slice::from_raw_parts_mut(4096 as *mut u8, 4096) }, ()));
+           tree.reserve(unsafe { slice::from_raw_parts_mut(4096 as *mut u8, 4096) }, ()).unwrap();
            assert_eq!(3, tree.block_count());
 
            //tree.tag_at(0 as *mut u8).unwrap();
@@ -205,5 +312,52 @@ pub mod test {
            assert_eq!(Some((4096, Some(()))), tree.free(slice.as_mut_ptr()));
            assert_eq!(3, tree.block_count());
        }
+
+       fn can_drain() {
+           let tree = VirtualTree::new();
+           tree.reserve(unsafe { slice::from_raw_parts_mut(4096 as *mut u8, 4096) }, 1).unwrap();
+           tree.alloc(8192, 2).unwrap();
+
+           let blocks: Vec<_> = tree.drain().into_iter().map(|(slice, tag)| (slice.as_ptr(), slice.len(), tag)).collect();
+           assert_eq!(vec![(4096 as *const u8, 4096, 1), (8192 as *const u8, 8192, 2)], blocks);
+           assert_eq!(1, tree.block_count());
+       }
+
+       fn can_list_blocks() {
+           let tree = VirtualTree::new();
+           tree.reserve(unsafe { slice::from_raw_parts_mut(4096 as *mut u8, 4096) }, 1).unwrap();
+           tree.alloc(8192, 2).unwrap();
+
+           let blocks: Vec<_> = tree.blocks().into_iter().map(|(slice, tag)| (slice.as_ptr(), slice.len(), tag)).collect();
+           assert_eq!(vec![(4096 as *const u8, 4096, 1), (8192 as *const u8, 8192, 2)], blocks);
+           assert_eq!(4, tree.block_count());
+       }
+
+       fn reserve_has_to_fit_in_one_free_block() {
+           let tree = VirtualTree::new();
+           let ptr = tree.alloc(0x3000, 0).unwrap().as_mut_ptr();
+           let after = unsafe { ptr.offset(0x3000) };
+           let reserve = |ptr: *mut u8, len| tree.reserve(unsafe { slice::from_raw_parts_mut(ptr, len) }, 1);
+           assert_eq!(Err(ErrNum::InvalidArgument), reserve(unsafe { ptr.offset(0x1000) }, 0x1000));
+           assert_eq!(Err(ErrNum::InvalidArgument), reserve(unsafe { ptr.offset(0x2fff) }, 2));
+           assert_eq!(Err(ErrNum::InvalidArgument), reserve(after, usize::MAX));
+           assert_eq!(Err(ErrNum::InvalidArgument), reserve(after, 0));
+
+           reserve(unsafe { after.offset(0x10) }, 0x1000).unwrap();
+           let (slice, tag) = tree.tag_at(unsafe { after.offset(0x1000) }).unwrap();
+           assert_eq!((after as *const u8, 0x2000, 1), (slice.as_ptr(), slice.len(), tag));
+       }
+
+       fn can_split() {
+           let tree = VirtualTree::new();
+           let ptr = tree.alloc(0x3000, 0).unwrap().as_mut_ptr();
+           let middle = unsafe { ptr.offset(0x1000) };
+           assert!(tree.split(middle, |tag, offset| *tag = offset));
+           assert!(tree.split(middle, |_, _| panic!("already split")));
+           assert!(!tree.split(unsafe { ptr.offset(0x3000) }, |_, _| ()));
+
+           let blocks: Vec<_> = tree.blocks().into_iter().map(|(slice, tag)| (slice.as_ptr(), slice.len(), tag)).collect();
+           assert_eq!(vec![(ptr as *const u8, 0x1000, 0), (middle as *const u8, 0x2000, 0x1000)], blocks);
+       }
     }
 }
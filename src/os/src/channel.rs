@@ -0,0 +1,52 @@
+use crate::{OSHandle, Result};
+use alloc::vec::Vec;
+use syscall::{self, Handle};
+
+/// The most handles that `Channel::receive` accepts with one message.
+pub const MAX_MESSAGE_HANDLES: usize = 8;
+
+/// One end of a connected pair. Messages keep their boundaries, and can carry handles from one process to another.
+pub struct Channel(OSHandle);
+
+impl Channel {
+    pub fn from_raw(handle: OSHandle) -> Self {
+        Self(handle)
+    }
+
+    pub fn create_pair() -> Result<(Self, Self)> {
+        let mut ends = [0; 2];
+        syscall::create_channel(&mut ends)?;
+        Ok((Self(OSHandle::from_raw(ends[0])), Self(OSHandle::from_raw(ends[1]))))
+    }
+
+    pub fn handle(&self) -> &OSHandle {
+        &self.0
+    }
+
+    pub fn duplicate(&self) -> Result<Self> {
+        Ok(Self(self.0.duplicate()?))
+    }
+
+    /// Sends `data` as one message. `handles` go with it, and are closed in this process, even if sending fails.
+    pub fn send(&self, data: &[u8], handles: Vec<OSHandle>) -> Result<()> {
+        let raw = handles.iter().map(OSHandle::get).collect::<Vec<Handle>>();
+        syscall::send_message(self.0.get(), data, &raw)?;
+
+        // The other process owns them now
+        for handle in handles {
+            handle.into_inner();
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until a message arrives, copies its data into `buf` and returns its length, and appends the handles
+    /// that came with it to `handles`. Returns 0 once the other end has been closed.
+    pub fn receive(&self, buf: &mut [u8], handles: &mut Vec<OSHandle>) -> Result<usize> {
+        let mut raw = [0; MAX_MESSAGE_HANDLES];
+        let mut count = 0;
+        let len = syscall::receive_message(self.0.get(), buf, &mut raw, &mut count)?;
+        handles.extend(raw[..count].iter().map(|&handle| OSHandle::from_raw(handle)));
+        Ok(len)
+    }
+}
@@ -1,6 +1,9 @@
 use crate::OSHandle;
 use core::sync::atomic::{AtomicUsize, Ordering};
+use syscall::{ErrNum, Result};
 
+/// `counter` is the number of threads that hold the lock or are waiting for it. The kernel mutex is only used once
+/// there's more than one: each `unlock` that sees a waiter releases it once, and each waiter takes it once.
 pub struct UntypedMutex {
     counter: AtomicUsize,
     handle: OSHandle,
@@ -11,9 +14,14 @@ unsafe impl Sync for UntypedMutex {}
 
 impl UntypedMutex {
     pub fn new() -> Self {
+        let handle = OSHandle::from_raw(syscall::create_mutex());
+
+        // The kernel mutex starts out held, so that a waiter blocks until `unlock` releases it
+        syscall::lock_mutex(handle.get()).unwrap();
+
         Self {
             counter: AtomicUsize::new(0),
-            handle: OSHandle::from_raw(syscall::create_mutex()),
+            handle,
         }
     }
 }
@@ -25,6 +33,35 @@ impl UntypedMutex {
         }
     }
 
+    /// Like `lock`, but gives up with `ErrNum::TimedOut` if the lock isn't released within `timeout_ns`.
+    pub fn lock_timeout(&self, timeout_ns: u64) -> Result<()> {
+        if self.counter.fetch_add(1, Ordering::Acquire) == 0 {
+            return Ok(());
+        }
+
+        match syscall::lock_mutex_timeout(self.handle.get(), timeout_ns) {
+            Err(ErrNum::TimedOut) => (),
+            result => return result,
+        }
+
+        let mut count = self.counter.load(Ordering::Acquire);
+        loop {
+            // The holder unlocked after the timeout, and released the kernel mutex for this thread
+            if count == 1 {
+                syscall::lock_mutex(self.handle.get()).unwrap();
+                return Ok(());
+            }
+
+            match self
+                .counter
+                .compare_exchange(count, count - 1, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return Err(ErrNum::TimedOut),
+                Err(actual) => count = actual,
+            }
+        }
+    }
+
     pub fn unlock(&self) {
         if self.counter.fetch_sub(1, Ordering::Release) > 1 {
             syscall::unlock_mutex(self.handle.get()).unwrap();
@@ -0,0 +1,71 @@
+//! Access to the arguments and environment that the process was started with.
+
+use core::slice;
+use core::str;
+
+static mut ARGC: usize = 0;
+static mut ARGV: *const *const u8 = 0 as *const *const u8;
+
+/// Records the argument vector passed to the process entry point. The environment block follows `argv` directly,
+/// after its terminating null pointer.
+pub unsafe fn init(argc: isize, argv: *const *const u8) {
+    ARGC = argc as usize;
+    ARGV = argv;
+}
+
+unsafe fn from_c_str(s: *const u8) -> &'static str {
+    // The kernel checks that arguments and environment variables are UTF-8 before starting the process
+    str::from_utf8_unchecked(slice::from_raw_parts(s, libc::strlen(s as *const _) as usize))
+}
+
+#[derive(Debug)]
+pub struct Strings {
+    ptr: *const *const u8,
+}
+
+impl Iterator for Strings {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        if self.ptr.is_null() {
+            return None;
+        }
+
+        unsafe {
+            let s = *self.ptr;
+            if s.is_null() {
+                return None;
+            }
+
+            self.ptr = self.ptr.offset(1);
+            Some(from_c_str(s))
+        }
+    }
+}
+
+/// Returns the arguments that the process was started with, starting with the program name.
+pub fn args() -> Strings {
+    Strings { ptr: unsafe { ARGV } }
+}
+
+/// Returns the process's environment variables as `(name, value)` pairs.
+pub fn vars() -> impl Iterator<Item = (&'static str, &'static str)> {
+    let ptr = unsafe {
+        if ARGV.is_null() {
+            ARGV
+        } else {
+            ARGV.offset(ARGC as isize + 1)
+        }
+    };
+
+    Strings { ptr }.map(|s| {
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().unwrap();
+        (name, parts.next().unwrap_or(""))
+    })
+}
+
+/// Returns the value of the environment variable `name`, if it's set.
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find(|&(n, _)| n == name).map(|(_, value)| value)
+}
@@ -1,5 +1,9 @@
-use crate::{OSHandle, Result};
-use syscall;
+use crate::time::duration_to_ns;
+use crate::{OSHandle, OSMem, Result};
+use alloc::string::String;
+use alloc::vec;
+use core::time::Duration;
+use syscall::{self, ErrNum, FileStat, SeekFrom};
 
 pub struct File(OSHandle);
 
@@ -12,15 +16,75 @@ impl File {
         Ok(Self(OSHandle::from_raw(syscall::open(filename)?)))
     }
 
+    /// Creates an empty file, or truncates an existing one, and opens it for reading and writing.
+    pub fn create(filename: &str) -> Result<Self> {
+        Ok(Self(OSHandle::from_raw(syscall::create(filename)?)))
+    }
+
     pub fn create_pipe() -> Self {
         Self(OSHandle::from_raw(syscall::create_pipe()))
     }
 
+    /// Creates a pipe that holds up to `capacity` bytes, and returns its read and write ends. Reading returns 0 once
+    /// every handle to the write end has been closed.
+    pub fn create_pipe_ends(capacity: usize) -> Result<(Self, Self)> {
+        let mut ends = [0; 2];
+        syscall::create_pipe_ends(capacity, &mut ends)?;
+        Ok((Self(OSHandle::from_raw(ends[0])), Self(OSHandle::from_raw(ends[1]))))
+    }
+
     pub fn handle(&self) -> &OSHandle {
         &self.0
     }
 
+    /// Reads from the file, giving up with `ErrNum::TimedOut` if no data arrives within `timeout`.
+    pub fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        syscall::read_timeout(self.0.get(), buf, duration_to_ns(timeout))
+    }
+
+    pub fn seek(&self, pos: SeekFrom) -> Result<u64> {
+        syscall::seek(self.0.get(), pos)
+    }
+
+    pub fn stat(&self) -> Result<FileStat> {
+        let mut stat = FileStat::default();
+        syscall::stat(self.0.get(), &mut stat)?;
+        Ok(stat)
+    }
+
+    /// Returns the name of the next entry in a directory, or `None` once there are no more entries.
+    pub fn read_dir(&self) -> Result<Option<String>> {
+        let mut buf = vec![0; 256];
+        let len = syscall::read_dir(self.0.get(), &mut buf)?;
+        if len == 0 {
+            return Ok(None);
+        }
+
+        buf.truncate(len);
+        String::from_utf8(buf).map(Some).map_err(|_| ErrNum::Utf8Error)
+    }
+
+    pub fn truncate(&self, len: u64) -> Result<()> {
+        syscall::truncate(self.0.get(), len)
+    }
+
+    /// Maps `len` bytes of the file into memory, starting at `offset`, which has to be a multiple of the page size.
+    /// Writing to a writable mapping doesn't change the file.
+    pub fn map(&self, offset: u64, len: usize, writable: bool) -> Result<OSMem<u8>> {
+        let ptr = syscall::map_file(self.0.get(), offset, len, writable)?;
+        Ok(unsafe { OSMem::from_raw(ptr, len) })
+    }
+
     pub fn duplicate(&self) -> Result<Self> {
         Ok(Self(self.0.duplicate()?))
     }
 }
+
+/// Removes a file or an empty directory.
+pub fn unlink(filename: &str) -> Result<()> {
+    syscall::unlink(filename)
+}
+
+pub fn mkdir(filename: &str) -> Result<()> {
+    syscall::mkdir(filename)
+}
@@ -5,25 +5,35 @@
 extern crate alloc;
 extern crate syscall;
 
+pub mod env;
 pub mod libc_helpers;
 
+mod channel;
 mod detail;
 mod file;
 mod mutex;
 mod oshandle;
 mod osmem;
+mod port;
 mod process;
 mod semaphore;
 mod sharedmem;
 mod thread;
+mod time;
 
+pub use self::channel::*;
 pub use self::file::*;
 pub use self::mutex::*;
 pub use self::oshandle::*;
 pub use self::osmem::*;
+pub use self::port::*;
 pub use self::process::*;
 pub use self::semaphore::*;
 pub use self::sharedmem::*;
 pub use self::thread::*;
+pub use self::time::*;
+pub use syscall::{
+    FileKind, FileStat, SeekFrom, PIPE_CAPACITY, POLL_HUP, POLL_READ, POLL_WRITE, PROT_EXEC, PROT_READ, PROT_WRITE,
+};
 
 pub type Result<T> = syscall::Result<T>;
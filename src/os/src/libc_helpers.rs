@@ -1,9 +1,11 @@
 use crate::detail::UntypedRecursiveMutex;
-use crate::Thread;
+use crate::Process;
 use core::fmt;
+use core::mem;
 use core::slice;
-use libc::{c_char, c_int, c_void, mode_t, off_t, size_t, ssize_t};
-use syscall::{Handle, Result};
+use core::str;
+use libc::{c_char, c_int, c_long, c_void, mode_t, off_t, size_t, ssize_t};
+use syscall::{ErrNum, FileKind, FileStat, Handle, Result, SeekFrom};
 
 #[allow(non_upper_case_globals)]
 #[thread_local]
@@ -42,6 +44,46 @@ pub unsafe extern "C" fn __errno() -> *mut c_int {
     &mut errno
 }
 
+fn result_or<T>(result: Result<T>, failed: T) -> T {
+    result.unwrap_or_else(|num| {
+        unsafe {
+            errno = num as i32;
+        }
+        failed
+    })
+}
+
+const S_IFDIR: mode_t = 0o040000;
+const S_IFREG: mode_t = 0o100000;
+
+const O_CREAT: c_int = 0x200;
+const O_TRUNC: c_int = 0x400;
+
+/// Newlib's `struct stat`, which isn't the same shape as Linux's.
+#[repr(C)]
+struct NewlibStat {
+    st_dev: i16,
+    st_ino: u16,
+    st_mode: mode_t,
+    st_nlink: u16,
+    st_uid: u16,
+    st_gid: u16,
+    st_rdev: i16,
+    st_size: off_t,
+    st_atim: [c_long; 2],
+    st_mtim: [c_long; 2],
+    st_ctim: [c_long; 2],
+    st_blksize: c_long,
+    st_blocks: c_long,
+    st_spare4: [c_long; 2],
+}
+
+// Newlib's x86_64 layout: 16 bytes of small fields up to `st_rdev`, then `st_size` at 16, the three timespecs at 24,
+// 40 and 56, `st_blksize` at 72, `st_blocks` at 80 and the spare words at 88
+const _: [(); 4] = [(); mem::size_of::<mode_t>()];
+const _: [(); 8] = [(); mem::size_of::<off_t>()];
+const _: [(); 104] = [(); mem::size_of::<NewlibStat>()];
+
 #[no_mangle]
 pub extern "C" fn sbrk(len: usize) -> *mut u8 {
     match syscall::alloc_pages(len) {
@@ -71,12 +113,12 @@ pub extern "C" fn __malloc_unlock(_reent: *mut c_void) {
 
 #[no_mangle]
 pub unsafe extern "C" fn __assert_fail(
-    _assertion: *const c_char,
+    assertion: *const c_char,
     _file: *const c_char,
     _line: c_int,
     _function: *const c_char,
 ) -> ! {
-    Thread::exit(-1)
+    Process::exit_with_message(-1, c_str(assertion).unwrap_or("assertion failed"))
 }
 
 #[no_mangle]
@@ -90,13 +132,18 @@ pub extern "C" fn _fputwc_r(_ptr: *mut c_void, _wc: char, _fp: *mut c_void) -> c
 }
 
 #[no_mangle]
-pub extern "C" fn _exit(_n: c_int) -> ! {
-    panic!("_exit was called")
+pub extern "C" fn _exit(n: c_int) -> ! {
+    Process::exit(n)
 }
 
+/// `pid` is a handle to the process. It's killed with exit code `128 + sig`, the way shells report signals.
 #[no_mangle]
-pub extern "C" fn kill(_pid: c_int, _sig: c_int) -> c_int {
-    panic!("kill was called")
+pub extern "C" fn kill(pid: c_int, sig: c_int) -> c_int {
+    if sig <= 0 {
+        return result_or(Err(ErrNum::InvalidArgument), -1);
+    }
+
+    result_or(syscall::kill_process(pid as Handle, 128 + sig).map(|()| 0), -1)
 }
 
 #[no_mangle]
@@ -104,49 +151,115 @@ pub extern "C" fn getpid() -> c_int {
     panic!("getpid was called")
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t {
-    if fd != 2 {
-        panic!("write({}, {:?}, {})", fd, buf, count);
+/// File descriptors are handles, except that stderr goes to the same place as stdout.
+fn fd_handle(fd: c_int) -> Handle {
+    if fd == 2 {
+        stdout
+    } else {
+        fd as Handle
     }
+}
 
+#[no_mangle]
+pub unsafe extern "C" fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t {
     let buf = slice::from_raw_parts(buf as *const u8, count as usize);
-    syscall::write(stdout, buf).map(|n| n as ssize_t).unwrap_or(-1)
+    result_or(syscall::write(fd_handle(fd), buf).map(|n| n as ssize_t), -1)
 }
 
 #[no_mangle]
-pub extern "C" fn close(_fd: c_int) -> c_int {
-    panic!("close was called")
+pub extern "C" fn close(fd: c_int) -> c_int {
+    // Closing stderr leaves stdout open
+    if fd == 2 {
+        return 0;
+    }
+
+    result_or(syscall::close(fd_handle(fd)).map(|()| 0), -1)
 }
 
 #[no_mangle]
-pub extern "C" fn fstat(_fd: c_int, _buf: *mut c_void) -> c_int {
-    panic!("fstat was called")
+pub unsafe extern "C" fn fstat(fd: c_int, buf: *mut c_void) -> c_int {
+    let mut stat = FileStat::default();
+    if let Err(num) = syscall::stat(fd_handle(fd), &mut stat) {
+        errno = num as i32;
+        return -1;
+    }
+
+    let kind = match stat.kind {
+        FileKind::File => S_IFREG,
+        FileKind::Directory => S_IFDIR,
+    };
+
+    let buf = &mut *(buf as *mut NewlibStat);
+    *buf = mem::zeroed();
+    buf.st_mode = kind | stat.mode;
+    buf.st_nlink = 1;
+    buf.st_size = stat.size as off_t;
+    buf.st_blksize = 4096;
+    buf.st_blocks = ((stat.size + 511) / 512) as c_long;
+    0
 }
 
+/// There are no terminal devices as such: the terminal talks to programs through pipes. So anything that isn't a file
+/// or a directory, which are the only things that `stat` works on, counts as a terminal.
 #[no_mangle]
-pub extern "C" fn isatty(_fd: c_int) -> c_int {
-    panic!("isatty was called")
+pub extern "C" fn isatty(fd: c_int) -> c_int {
+    let mut stat = FileStat::default();
+    match syscall::stat(fd_handle(fd), &mut stat) {
+        Err(ErrNum::NotSupported) => 1,
+        Ok(()) => result_or(Err(ErrNum::NotSupported), 0),
+        Err(num) => result_or(Err(num), 0),
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn lseek(_fd: c_int, _offset: off_t, _whence: c_int) -> off_t {
-    panic!("lseek was called")
+pub extern "C" fn lseek(fd: c_int, offset: off_t, whence: c_int) -> off_t {
+    let pos = match whence {
+        0 if offset >= 0 => SeekFrom::Start(offset as u64),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return result_or(Err(ErrNum::InvalidArgument), -1),
+    };
+
+    result_or(syscall::seek(fd_handle(fd), pos).map(|pos| pos as off_t), -1)
 }
 
 #[no_mangle]
-pub extern "C" fn read(_fd: c_int, _buf: *mut c_void, _count: size_t) -> ssize_t {
-    panic!("read was called")
+pub unsafe extern "C" fn read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t {
+    let buf = slice::from_raw_parts_mut(buf as *mut u8, count as usize);
+    result_or(syscall::read(fd_handle(fd), buf).map(|n| n as ssize_t), -1)
 }
 
 #[no_mangle]
-pub extern "C" fn open(_path: *const c_char, _oflag: c_int, _mode: mode_t) -> c_int {
-    panic!("open was called")
+pub unsafe extern "C" fn open(path: *const c_char, oflag: c_int, _mode: mode_t) -> c_int {
+    let handle = c_str(path).and_then(|path| {
+        if oflag & O_CREAT != 0 && oflag & O_TRUNC != 0 {
+            return syscall::create(path);
+        }
+
+        match syscall::open(path) {
+            Err(ErrNum::FileNotFound) if oflag & O_CREAT != 0 => syscall::create(path),
+            Ok(handle) if oflag & O_TRUNC != 0 => match syscall::truncate(handle, 0) {
+                Ok(()) => Ok(handle),
+                Err(num) => {
+                    let _ = syscall::close(handle);
+                    Err(num)
+                }
+            },
+            result => result,
+        }
+    });
+
+    result_or(handle.map(|handle| handle as c_int), -1)
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn unlink(_c: *const c_char) -> c_int {
-    panic!("unlink was called")
+pub unsafe extern "C" fn unlink(path: *const c_char) -> c_int {
+    result_or(c_str(path).and_then(|path| syscall::unlink(path)).map(|()| 0), -1)
+}
+
+unsafe fn c_str<'a>(s: *const c_char) -> Result<&'a str> {
+    let s = slice::from_raw_parts(s as *const u8, libc::strlen(s) as usize);
+    Ok(str::from_utf8(s)?)
 }
 
 pub unsafe fn init() -> Result<()> {
@@ -168,5 +281,5 @@ pub unsafe fn init() -> Result<()> {
 
 pub unsafe fn shutdown(code: i32) -> ! {
     MALLOC_LOCK.as_ref().take();
-    Thread::exit(code)
+    Process::exit(code)
 }
@@ -1,7 +1,10 @@
 use crate::detail::UntypedMutex;
+use crate::time::duration_to_ns;
+use crate::Result;
 use core::cell::UnsafeCell;
 use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
+use core::time::Duration;
 
 pub struct Mutex<T: ?Sized> {
     mutex: UntypedMutex,
@@ -28,6 +31,15 @@ impl<T: ?Sized> Mutex<T> {
             _pd: PhantomData,
         }
     }
+
+    /// Like `lock`, but gives up with `ErrNum::TimedOut` if the mutex isn't free within `timeout`.
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<MutexGuard<T>> {
+        self.mutex.lock_timeout(duration_to_ns(timeout))?;
+        Ok(MutexGuard {
+            mutex: self,
+            _pd: PhantomData,
+        })
+    }
 }
 
 #[must_use]
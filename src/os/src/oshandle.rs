@@ -1,4 +1,6 @@
+use crate::time::duration_to_ns;
 use core::mem::ManuallyDrop;
+use core::time::Duration;
 use syscall::{self, Handle, Result};
 
 #[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -21,6 +23,24 @@ impl OSHandle {
     pub fn duplicate(&self) -> Result<Self> {
         Ok(Self(syscall::duplicate_handle(self.0)?))
     }
+
+    /// Makes reads and writes through this handle fail with `ErrNum::WouldBlock` instead of waiting.
+    pub fn set_non_blocking(&self, non_blocking: bool) -> Result<()> {
+        syscall::set_non_blocking(self.0, non_blocking)
+    }
+}
+
+/// Blocks until one of `handles` is ready, and returns its index. Waits forever if `timeout` is `None`.
+pub fn wait_for_any(handles: &[Handle], timeout: Option<Duration>) -> Result<usize> {
+    let timeout_ns = timeout.map(duration_to_ns).unwrap_or(u64::max_value());
+    syscall::wait_for_any(handles, timeout_ns)
+}
+
+/// Blocks until one of `handles` has one of the `POLL_` flags asked for in `events`, or has hung up. Sets `events` to
+/// the flags that were found, and returns how many handles had any, which is 0 if `timeout` passed first.
+pub fn poll(handles: &[Handle], events: &mut [u32], timeout: Option<Duration>) -> Result<usize> {
+    let timeout_ns = timeout.map(duration_to_ns).unwrap_or(u64::max_value());
+    syscall::poll(handles, events, timeout_ns)
 }
 
 impl Drop for OSHandle {
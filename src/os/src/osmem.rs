@@ -1,7 +1,9 @@
+use crate::Result;
+use core::mem;
 use core::ops::{Deref, DerefMut};
-use core::ptr::NonNull;
+use core::ptr::{self, NonNull};
 use core::slice;
-use syscall;
+use syscall::{self, ErrNum};
 
 pub struct OSMem<T>(NonNull<T>, usize);
 
@@ -17,6 +19,29 @@ where
     }
 }
 
+impl OSMem<u8> {
+    /// Reserves `len` bytes of address space without committing any memory to it. The memory can't be touched until
+    /// `protect` makes it accessible.
+    pub fn reserve(len: usize) -> Result<Self> {
+        let ptr = syscall::reserve_pages(ptr::null_mut(), len)?;
+        Ok(unsafe { Self::from_raw(ptr, len) })
+    }
+}
+
+impl<T> OSMem<T> {
+    /// Changes whether the `len` bytes starting `offset` bytes in can be read, written or executed, using the
+    /// `PROT_` flags. `offset` has to be a multiple of the page size.
+    pub fn protect(&self, offset: usize, len: usize, flags: u32) -> Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.1 * mem::size_of::<T>() => (),
+            _ => return Err(ErrNum::InvalidArgument),
+        }
+
+        let ptr = unsafe { (self.0.as_ptr() as *mut u8).add(offset) };
+        syscall::protect_pages(ptr, len, flags)
+    }
+}
+
 impl<T> Deref for OSMem<T> {
     type Target = [T];
 
@@ -0,0 +1,27 @@
+use crate::{Channel, OSHandle, Result};
+use syscall;
+
+/// A name that other processes can connect to.
+pub struct Port(OSHandle);
+
+impl Port {
+    /// Claims `name`, failing with `ErrNum::AlreadyExists` if another process has it.
+    pub fn register(name: &str) -> Result<Self> {
+        Ok(Self(OSHandle::from_raw(syscall::register_port(name)?)))
+    }
+
+    /// Connects to the port registered as `name`, and returns a channel to the process that registered it.
+    pub fn connect(name: &str) -> Result<Channel> {
+        Ok(Channel::from_raw(OSHandle::from_raw(syscall::connect_port(name)?)))
+    }
+
+    pub fn handle(&self) -> &OSHandle {
+        &self.0
+    }
+
+    /// Blocks until a client connects, and returns a channel to it.
+    pub fn accept(&self) -> Result<Channel> {
+        let handle = syscall::accept_port(self.0.get())?;
+        Ok(Channel::from_raw(OSHandle::from_raw(handle)))
+    }
+}
@@ -1,24 +1,136 @@
-use crate::{OSHandle, Result};
-use syscall::Handle;
+use crate::time::duration_to_ns;
+use crate::{env, OSHandle, Result};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+use core::time::Duration;
+use syscall::{ErrNum, Handle};
+
+/// How a process finished: its exit code, plus a message if it panicked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExitStatus {
+    code: i32,
+    message: Option<String>,
+}
+
+impl ExitStatus {
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(String::as_str)
+    }
+
+    pub fn success(&self) -> bool {
+        self.code == 0
+    }
+}
 
 pub struct Process(OSHandle);
 
+extern "C" fn fork_entry(context: usize) {
+    let b: Box<Box<dyn FnOnce() -> i32>> = unsafe { Box::from_raw(context as *mut _) };
+    Process::exit(b())
+}
+
 impl Process {
     pub fn from_raw(handle: OSHandle) -> Self {
         Process(handle)
     }
 
     pub fn spawn(filename: &str, inherit: &[Handle]) -> Result<Self> {
-        Ok(Process(OSHandle::from_raw(syscall::spawn_process(filename, inherit)?)))
+        Self::spawn_with_args(filename, &[filename], inherit)
+    }
+
+    /// Starts a process with the given arguments and a copy of the current process's environment.
+    pub fn spawn_with_args(filename: &str, args: &[&str], inherit: &[Handle]) -> Result<Self> {
+        Self::spawn_with_env(filename, args, env::vars(), inherit)
+    }
+
+    pub fn spawn_with_env<'a, I>(filename: &str, args: &[&str], vars: I, inherit: &[Handle]) -> Result<Self>
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        let mut block = Vec::new();
+        for arg in args {
+            if arg.is_empty() || arg.contains('\0') {
+                return Err(ErrNum::InvalidArgument);
+            }
+
+            block.extend_from_slice(arg.as_bytes());
+            block.push(0);
+        }
+
+        block.push(0);
+
+        for (name, value) in vars {
+            if name.is_empty() || name.contains('=') || name.contains('\0') || value.contains('\0') {
+                return Err(ErrNum::InvalidArgument);
+            }
+
+            block.extend_from_slice(name.as_bytes());
+            block.push(b'=');
+            block.extend_from_slice(value.as_bytes());
+            block.push(0);
+        }
+
+        let handle = syscall::spawn_process(filename, &block, inherit)?;
+        Ok(Process(OSHandle::from_raw(handle)))
+    }
+
+    /// Starts a process with a copy of this process's memory and handles, which calls `entry` and exits with the code
+    /// it returns. The new process has one thread, and its thread-local variables start off fresh.
+    pub fn fork<'a, F: FnOnce() -> i32 + 'a>(entry: F) -> Result<Self> {
+        let b: Box<Box<dyn FnOnce() -> i32 + 'a>> = Box::new(Box::new(entry));
+        let context_ptr = Box::into_raw(b);
+        let result = syscall::fork_process(fork_entry, context_ptr as usize);
+
+        // The new process runs its own copy of the closure
+        mem::drop(unsafe { Box::from_raw(context_ptr) });
+        Ok(Process(OSHandle::from_raw(result?)))
     }
 
     pub fn handle(&self) -> &OSHandle {
         &self.0
     }
 
-    pub fn wait_for_exit(&self) -> Result<()> {
-        syscall::wait_for_exit(self.0.get())?;
-        Ok(())
+    /// Exits every thread in the current process.
+    pub fn exit(code: i32) -> ! {
+        syscall::exit_process(code, "")
+    }
+
+    /// Exits every thread in the current process, leaving a message for whoever is waiting for it.
+    pub fn exit_with_message(code: i32, message: &str) -> ! {
+        syscall::exit_process(code, message)
+    }
+
+    fn exit_status(&self, code: i32) -> Result<ExitStatus> {
+        let mut buf = [0; 1024];
+        let len = syscall::exit_message(self.0.get(), &mut buf)?;
+        let message = if len > 0 {
+            Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+        } else {
+            None
+        };
+
+        Ok(ExitStatus { code, message })
+    }
+
+    pub fn wait_for_exit(&self) -> Result<ExitStatus> {
+        let code = syscall::wait_for_exit(self.0.get())?;
+        self.exit_status(code)
+    }
+
+    pub fn wait_for_exit_timeout(&self, timeout: Duration) -> Result<ExitStatus> {
+        let code = syscall::wait_for_exit_timeout(self.0.get(), duration_to_ns(timeout))?;
+        self.exit_status(code)
+    }
+
+    /// Terminates the process, which then looks to anything waiting for it as if it had exited with `code`.
+    pub fn kill(&self, code: i32) -> Result<()> {
+        syscall::kill_process(self.0.get(), code)
     }
 
     pub fn open_handle(&self, from_handle: usize) -> Result<OSHandle> {
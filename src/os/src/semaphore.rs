@@ -1,4 +1,6 @@
-use crate::OSHandle;
+use crate::time::duration_to_ns;
+use crate::{OSHandle, Result};
+use core::time::Duration;
 
 pub struct Semaphore(OSHandle);
 
@@ -16,6 +18,10 @@ impl Semaphore {
         syscall::wait_semaphore(self.0.get()).unwrap();
     }
 
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<()> {
+        syscall::wait_semaphore_timeout(self.0.get(), duration_to_ns(timeout))
+    }
+
     pub fn post(&self) {
         syscall::post_semaphore(self.0.get()).unwrap()
     }
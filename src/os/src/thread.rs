@@ -1,5 +1,10 @@
-use crate::{OSHandle, Result};
+use crate::time::duration_to_ns;
+use crate::{Mutex, OSHandle, Process, Result};
 use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 use syscall;
 use syscall::ErrNum;
 
@@ -38,16 +43,28 @@ impl Termination for Result<i32> {
     }
 }
 
-extern "C" fn thread_entry<T>(context: usize)
-where
-    T: Termination,
-{
-    let b: Box<Box<dyn FnOnce() -> T>> = unsafe { Box::from_raw(context as *mut _) };
-    let code = b().report();
+/// Where a spawned thread leaves the value returned by its closure, for `join` to pick up.
+type Packet<T> = Arc<Mutex<Option<T>>>;
+
+/// Set by the panic handler in a thread started by `Thread::spawn`. `None` in threads that weren't.
+#[thread_local]
+static mut PANICKED: Option<Arc<AtomicBool>> = None;
+
+extern "C" fn thread_entry(context: usize) {
+    let b: Box<Box<dyn FnOnce() -> i32>> = unsafe { Box::from_raw(context as *mut _) };
+    let code = b();
+    unsafe {
+        PANICKED = None;
+    }
+
     Thread::exit(code)
 }
 
-pub struct Thread(OSHandle);
+pub struct Thread<T = ()> {
+    handle: OSHandle,
+    packet: Packet<T>,
+    panicked: Arc<AtomicBool>,
+}
 
 impl Thread {
     pub fn current_thread_id() -> usize {
@@ -63,37 +80,106 @@ impl Thread {
         }
     }
 
-    pub fn from_raw(handle: OSHandle) -> Self {
-        Thread(handle)
+    pub fn exit(code: i32) -> ! {
+        syscall::exit_thread(code)
+    }
+
+    /// Called by the panic handler. A panic in a thread started by `Thread::spawn` ends just that thread, and makes
+    /// its `join` return an error; a panic anywhere else ends the whole process.
+    pub fn exit_after_panic(code: i32, message: &str) -> ! {
+        if let Some(panicked) = unsafe { PANICKED.as_ref() } {
+            panicked.store(true, Ordering::Release);
+            Thread::exit(code)
+        } else {
+            Process::exit_with_message(code, message)
+        }
+    }
+
+    pub fn sleep(duration: Duration) {
+        syscall::sleep(duration_to_ns(duration))
     }
 
-    fn spawn_inner<T>(b: Box<Box<dyn FnOnce() -> T>>) -> Self
+    /// Changes the priority of the current thread, from 0 up to `syscall::MAX_USER_PRIORITY`. Runnable threads with
+    /// a higher priority run first, although threads that have been waiting for a while get a turn anyway.
+    pub fn set_priority(priority: u8) -> Result<()> {
+        syscall::set_thread_priority(priority)
+    }
+}
+
+/// The stack size for threads started with `Thread::spawn`.
+pub const DEFAULT_STACK_SIZE: usize = 40 * 1024;
+
+impl<T> Thread<T> {
+    pub fn spawn<F>(entry: F) -> Self
     where
-        T: Termination,
+        F: FnOnce() -> T + Send + 'static,
+        T: Termination + Clone + Send + 'static,
     {
-        let context_ptr = Box::into_raw(b);
-        let handle = syscall::spawn_thread(thread_entry::<T>, context_ptr as usize);
-        Self(OSHandle::from_raw(handle))
+        Self::spawn_with_stack_size(DEFAULT_STACK_SIZE, entry).expect("failed to spawn thread")
     }
 
-    pub fn spawn<F, T>(entry: F) -> Self
+    pub fn spawn_with_stack_size<F>(stack_size: usize, entry: F) -> Result<Self>
     where
         F: FnOnce() -> T + Send + 'static,
-        T: Termination,
+        T: Termination + Clone + Send + 'static,
     {
-        Self::spawn_inner(Box::new(Box::new(entry)))
-    }
+        let packet: Packet<T> = Arc::new(Mutex::new(None));
+        let panicked = Arc::new(AtomicBool::new(false));
+        let b: Box<Box<dyn FnOnce() -> i32>> = Box::new(Box::new({
+            let packet = packet.clone();
+            let panicked = panicked.clone();
+            move || {
+                unsafe {
+                    PANICKED = Some(panicked);
+                }
+
+                let value = entry();
+                let code = value.clone().report();
+                *packet.lock() = Some(value);
+                code
+            }
+        }));
 
-    pub fn exit(code: i32) -> ! {
-        syscall::exit_thread(code)
+        let context_ptr = Box::into_raw(b);
+        let handle = match syscall::spawn_thread(thread_entry, context_ptr as usize, stack_size) {
+            Ok(handle) => handle,
+            Err(num) => {
+                mem::drop(unsafe { Box::from_raw(context_ptr) });
+                return Err(num);
+            }
+        };
+
+        Ok(Thread {
+            handle: OSHandle::from_raw(handle),
+            packet,
+            panicked,
+        })
     }
 
     pub fn handle(&self) -> &OSHandle {
-        &self.0
+        &self.handle
+    }
+
+    /// Waits for the thread to finish, and returns the value that its closure returned. Returns
+    /// `ErrNum::Panicked` if the thread panicked instead, or `ErrNum::Interrupted` if it called `Thread::exit`.
+    pub fn join(self) -> Result<T> {
+        syscall::wait_for_exit(self.handle.get())?;
+        if let Some(value) = self.packet.lock().take() {
+            Ok(value)
+        } else if self.panicked.load(Ordering::Acquire) {
+            Err(ErrNum::Panicked)
+        } else {
+            Err(ErrNum::Interrupted)
+        }
     }
 
     pub fn wait_for_exit(&self) -> Result<()> {
-        syscall::wait_for_exit(self.0.get())?;
+        syscall::wait_for_exit(self.handle.get())?;
+        Ok(())
+    }
+
+    pub fn wait_for_exit_timeout(&self, timeout: Duration) -> Result<()> {
+        syscall::wait_for_exit_timeout(self.handle.get(), duration_to_ns(timeout))?;
         Ok(())
     }
 }
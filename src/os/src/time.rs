@@ -0,0 +1,41 @@
+use core::cmp;
+use core::ops::{Add, Sub};
+use core::time::Duration;
+
+/// A measurement of the kernel's monotonic clock.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(syscall::now())
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, other: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration_to_ns(other)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, other: Instant) -> Duration {
+        self.duration_since(other)
+    }
+}
+
+pub(crate) fn duration_to_ns(duration: Duration) -> u64 {
+    cmp::min(duration.as_nanos(), u64::max_value() as u128) as u64
+}
@@ -1,10 +1,11 @@
-use os::{libc_helpers, Termination};
+use os::{env, libc_helpers, Termination};
 
 #[lang = "start"]
-unsafe fn lang_start<T>(main: fn() -> T, _argc: isize, _argv: *const *const u8) -> isize
+unsafe fn lang_start<T>(main: fn() -> T, argc: isize, argv: *const *const u8) -> isize
 where
     T: Termination + 'static,
 {
+    env::init(argc, argv);
     let code = libc_helpers::init().map(|()| main().report()).report();
     libc_helpers::shutdown(code as i32);
 }
@@ -1,16 +1,45 @@
-use core::fmt::Write;
+use core::cmp;
+use core::fmt::{self, Write};
 use core::panic::PanicInfo;
+use core::str;
 use os::libc_helpers::StdoutWriter;
 use os::Thread;
 
+/// Formats the panic message without allocating, cutting it short if it doesn't fit.
+struct MessageBuf {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl MessageBuf {
+    fn as_str(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+}
+
+impl Write for MessageBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = cmp::min(s.len(), self.buf.len() - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     let (file, line) = info.location().map(|l| (l.file(), l.line())).unwrap_or_default();
-    let _ = if let Some(message) = info.message() {
-        writeln!(&mut StdoutWriter, "Panic at {}({}): {}", file, line, message)
+    let mut message = MessageBuf { buf: [0; 256], len: 0 };
+    let _ = if let Some(args) = info.message() {
+        write!(&mut message, "Panic at {}({}): {}", file, line, args)
     } else {
-        writeln!(&mut StdoutWriter, "Panic at {}({})", file, line)
+        write!(&mut message, "Panic at {}({})", file, line)
     };
 
-    Thread::exit(-(line as i32))
+    let _ = writeln!(&mut StdoutWriter, "{}", message.as_str());
+    Thread::exit_after_panic(-(line as i32), message.as_str())
 }
@@ -0,0 +1,60 @@
+//! Inspection of the process's arguments and environment.
+
+#![stable(feature = "rust-os", since = "1.0.0")]
+
+use crate::error::Error;
+use crate::fmt;
+use crate::string::String;
+
+/// An iterator over the arguments of a process, yielding a `String` for each argument.
+#[stable(feature = "rust-os", since = "1.0.0")]
+#[derive(Debug)]
+pub struct Args(os::env::Strings);
+
+#[stable(feature = "rust-os", since = "1.0.0")]
+impl Iterator for Args {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.0.next().map(String::from)
+    }
+}
+
+/// Returns the arguments that this program was started with, starting with the program name.
+#[stable(feature = "rust-os", since = "1.0.0")]
+pub fn args() -> Args {
+    Args(os::env::args())
+}
+
+/// Returns a snapshot of the process's environment variables as `(name, value)` pairs.
+#[stable(feature = "rust-os", since = "1.0.0")]
+pub fn vars() -> impl Iterator<Item = (String, String)> {
+    os::env::vars().map(|(name, value)| (String::from(name), String::from(value)))
+}
+
+/// Fetches the environment variable `key` from the current process.
+#[stable(feature = "rust-os", since = "1.0.0")]
+pub fn var<K: AsRef<str>>(key: K) -> Result<String, VarError> {
+    os::env::var(key.as_ref()).map(String::from).ok_or(VarError::NotPresent)
+}
+
+/// The error type for operations interacting with environment variables.
+#[stable(feature = "rust-os", since = "1.0.0")]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum VarError {
+    /// The specified environment variable was not present in the current process's environment.
+    #[stable(feature = "rust-os", since = "1.0.0")]
+    NotPresent,
+}
+
+#[stable(feature = "rust-os", since = "1.0.0")]
+impl fmt::Display for VarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            VarError::NotPresent => write!(f, "environment variable not found"),
+        }
+    }
+}
+
+#[stable(feature = "rust-os", since = "1.0.0")]
+impl Error for VarError {}
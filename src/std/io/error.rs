@@ -466,6 +466,12 @@ impl From<ErrNum> for ErrorKind {
         match num {
             ErrNum::InvalidArgument => ErrorKind::InvalidInput,
             ErrNum::FileNotFound => ErrorKind::NotFound,
+            ErrNum::TimedOut => ErrorKind::TimedOut,
+            ErrNum::AlreadyExists => ErrorKind::AlreadyExists,
+            ErrNum::BadExecutable => ErrorKind::InvalidData,
+            ErrNum::BrokenPipe => ErrorKind::BrokenPipe,
+            ErrNum::WouldBlock => ErrorKind::WouldBlock,
+            ErrNum::Interrupted => ErrorKind::Interrupted,
             _ => ErrorKind::Other
         }
     }
@@ -477,6 +483,11 @@ impl From<ErrorKind> for ErrNum {
         match kind {
             ErrorKind::InvalidInput => ErrNum::InvalidArgument,
             ErrorKind::NotFound => ErrNum::FileNotFound,
+            ErrorKind::TimedOut => ErrNum::TimedOut,
+            ErrorKind::AlreadyExists => ErrNum::AlreadyExists,
+            ErrorKind::BrokenPipe => ErrNum::BrokenPipe,
+            ErrorKind::WouldBlock => ErrNum::WouldBlock,
+            ErrorKind::Interrupted => ErrNum::Interrupted,
             _ => ErrNum::NotSupported
         }
     }
@@ -2,7 +2,7 @@
 #![stable(feature = "rust-os", since = "1.0.0")]
 
 use crate::fmt;
-use crate::io::{self, Read, Write};
+use crate::io::{self, Read, Seek, SeekFrom, Write};
 use os::File;
 use os::libc_helpers::{StdoutWriter, StderrWriter};
 use syscall;
@@ -25,6 +25,19 @@ impl Write for File {
     }
 }
 
+#[stable(feature = "rust-os", since = "1.0.0")]
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => syscall::SeekFrom::Start(offset),
+            SeekFrom::End(offset) => syscall::SeekFrom::End(offset),
+            SeekFrom::Current(offset) => syscall::SeekFrom::Current(offset),
+        };
+
+        File::seek(self, pos).map_err(From::from)
+    }
+}
+
 #[stable(feature = "rust-os", since = "1.0.0")]
 pub fn print(args: fmt::Arguments) {
      fmt::Write::write_fmt(&mut StdoutWriter, args).unwrap()
@@ -434,6 +434,7 @@ pub use core::usize;
 pub mod f32;
 pub mod f64;
 
+pub mod env;
 pub mod error;
 pub mod io;
 pub mod num;
@@ -2,7 +2,7 @@ use core::convert::TryFrom;
 use core::result;
 use core::str::Utf8Error;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ErrNum {
     Utf8Error,
     OutOfMemory,
@@ -10,6 +10,15 @@ pub enum ErrNum {
     NotSupported,
     FileNotFound,
     InvalidArgument,
+    TimedOut,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    BadExecutable,
+    Panicked,
+    BrokenPipe,
+    WouldBlock,
+    NoSpace,
+    Interrupted,
 }
 
 impl TryFrom<usize> for ErrNum {
@@ -23,6 +32,15 @@ impl TryFrom<usize> for ErrNum {
             4 => Ok(Self::NotSupported),
             5 => Ok(Self::FileNotFound),
             6 => Ok(Self::InvalidArgument),
+            7 => Ok(Self::TimedOut),
+            8 => Ok(Self::AlreadyExists),
+            9 => Ok(Self::DirectoryNotEmpty),
+            10 => Ok(Self::BadExecutable),
+            11 => Ok(Self::Panicked),
+            12 => Ok(Self::BrokenPipe),
+            13 => Ok(Self::WouldBlock),
+            14 => Ok(Self::NoSpace),
+            15 => Ok(Self::Interrupted),
             _ => Err(()),
         }
     }
@@ -37,6 +55,15 @@ impl Into<usize> for ErrNum {
             Self::NotSupported => 4,
             Self::FileNotFound => 5,
             Self::InvalidArgument => 6,
+            Self::TimedOut => 7,
+            Self::AlreadyExists => 8,
+            Self::DirectoryNotEmpty => 9,
+            Self::BadExecutable => 10,
+            Self::Panicked => 11,
+            Self::BrokenPipe => 12,
+            Self::WouldBlock => 13,
+            Self::NoSpace => 14,
+            Self::Interrupted => 15,
         }
     }
 }
@@ -0,0 +1,54 @@
+use crate::{ErrNum, Result};
+
+/// Enumeration of possible methods to seek within a file.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+impl SeekFrom {
+    /// Works out the new position for a file of length `len` whose cursor is currently at `current`.
+    pub fn resolve(self, current: u64, len: u64) -> Result<u64> {
+        let (base, offset) = match self {
+            SeekFrom::Start(offset) => return Ok(offset),
+            SeekFrom::End(offset) => (len, offset),
+            SeekFrom::Current(offset) => (current, offset),
+        };
+
+        let pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+
+        pos.ok_or(ErrNum::InvalidArgument)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FileKind {
+    File,
+    Directory,
+}
+
+/// Information about a file or directory, as returned by `stat`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FileStat {
+    pub size: u64,
+    pub kind: FileKind,
+    /// Permission bits, as in Unix.
+    pub mode: u32,
+}
+
+impl Default for FileStat {
+    fn default() -> Self {
+        FileStat {
+            size: 0,
+            kind: FileKind::File,
+            mode: 0,
+        }
+    }
+}
@@ -4,10 +4,37 @@
 
 pub type Handle = usize;
 
+/// Thread priorities run from 0 up to `MAX_PRIORITY`. Threads with a higher priority run first, although threads that
+/// have been waiting for a while get a turn anyway.
+pub const MAX_PRIORITY: u8 = 3;
+
+/// The priority of the first thread in the system.
+pub const DEFAULT_PRIORITY: u8 = 1;
+
+/// The highest priority that `set_thread_priority` gives out. Priorities above it are kept for the kernel.
+pub const MAX_USER_PRIORITY: u8 = DEFAULT_PRIORITY + 1;
+
+/// Flags for `protect_pages`. Pages with none of these set can't be touched at all; pages that can be written or
+/// executed can also be read.
+pub const PROT_READ: u32 = 1;
+pub const PROT_WRITE: u32 = 2;
+pub const PROT_EXEC: u32 = 4;
+
+/// The number of bytes a pipe holds before writers have to wait for a reader, and the most that `create_pipe_ends`
+/// allows.
+pub const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Flags for `poll`. A handle is readable or writable if reading or writing it wouldn't block, and has hung up once
+/// the other end has gone or, for processes and threads, once it has exited.
+pub const POLL_READ: u32 = 1;
+pub const POLL_WRITE: u32 = 2;
+pub const POLL_HUP: u32 = 4;
+
 #[macro_use]
 mod macros;
 
 mod error;
+mod file;
 mod marshal;
 mod table;
 
@@ -15,4 +42,5 @@ mod table;
 pub use marshal::PackedArgs;
 
 pub use error::{ErrNum, Result};
+pub use file::{FileKind, FileStat, SeekFrom};
 pub use table::*;
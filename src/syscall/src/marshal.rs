@@ -1,4 +1,4 @@
-use crate::ErrNum;
+use crate::{ErrNum, FileStat, SeekFrom};
 use core::convert::TryFrom;
 use core::mem;
 use core::slice;
@@ -267,6 +267,18 @@ impl SyscallArgs for usize {
     }
 }
 
+impl SyscallArgs for u64 {
+    type Parsed = Self;
+
+    fn as_args(self, args: &mut PackedArgs) {
+        args.push_back(self as usize);
+    }
+
+    fn from_args(args: &mut PackedArgs) -> Self {
+        args.pop_front() as Self
+    }
+}
+
 impl<T> SyscallArgs for *const T {
     type Parsed = Self;
 
@@ -317,6 +329,56 @@ impl<'a, T> SyscallArgs for &'a mut [T] {
     }
 }
 
+impl<'a> SyscallArgs for &'a mut FileStat {
+    type Parsed = Self;
+
+    fn as_args(self, args: &mut PackedArgs) {
+        (self as *mut FileStat).as_args(args)
+    }
+
+    fn from_args(args: &mut PackedArgs) -> Self {
+        let ptr = <*mut FileStat as SyscallArgs>::from_args(args);
+        unsafe { &mut *ptr }
+    }
+}
+
+impl<'a> SyscallArgs for &'a mut usize {
+    type Parsed = Self;
+
+    fn as_args(self, args: &mut PackedArgs) {
+        (self as *mut usize).as_args(args)
+    }
+
+    fn from_args(args: &mut PackedArgs) -> Self {
+        let ptr = <*mut usize as SyscallArgs>::from_args(args);
+        unsafe { &mut *ptr }
+    }
+}
+
+impl SyscallArgs for SeekFrom {
+    type Parsed = Result<Self, ErrNum>;
+
+    fn as_args(self, args: &mut PackedArgs) {
+        let (whence, offset): (usize, usize) = match self {
+            SeekFrom::Start(offset) => (0, offset as usize),
+            SeekFrom::Current(offset) => (1, offset as usize),
+            SeekFrom::End(offset) => (2, offset as usize),
+        };
+
+        (whence, offset).as_args(args)
+    }
+
+    fn from_args(args: &mut PackedArgs) -> Result<Self, ErrNum> {
+        let (whence, offset) = <(usize, usize) as SyscallArgs>::from_args(args);
+        match whence {
+            0 => Ok(SeekFrom::Start(offset as u64)),
+            1 => Ok(SeekFrom::Current(offset as i64)),
+            2 => Ok(SeekFrom::End(offset as i64)),
+            _ => Err(ErrNum::InvalidArgument),
+        }
+    }
+}
+
 impl<'a> SyscallArgs for &'a str {
     type Parsed = Result<Self, Utf8Error>;
 
@@ -387,6 +449,25 @@ impl<T1: SyscallArgs, T2: SyscallArgs, T3: SyscallArgs> SyscallArgs for (T1, T2,
     }
 }
 
+impl<T1: SyscallArgs, T2: SyscallArgs, T3: SyscallArgs, T4: SyscallArgs> SyscallArgs for (T1, T2, T3, T4) {
+    type Parsed = (T1::Parsed, T2::Parsed, T3::Parsed, T4::Parsed);
+
+    fn as_args(self, args: &mut PackedArgs) {
+        self.0.as_args(args);
+        self.1.as_args(args);
+        self.2.as_args(args);
+        self.3.as_args(args);
+    }
+
+    fn from_args(args: &mut PackedArgs) -> Self::Parsed {
+        let a = T1::from_args(args);
+        let b = T2::from_args(args);
+        let c = T3::from_args(args);
+        let d = T4::from_args(args);
+        (a, b, c, d)
+    }
+}
+
 impl<T: SyscallResult> SyscallResult for Result<T, ErrNum> {
     fn as_result(self) -> isize {
         match self {
@@ -469,6 +550,16 @@ impl SyscallResult for usize {
     }
 }
 
+impl SyscallResult for u64 {
+    fn as_result(self) -> isize {
+        self as isize
+    }
+
+    fn from_result(value: isize) -> Self {
+        value as Self
+    }
+}
+
 impl<'a, T> SyscallResult for *const T {
     fn as_result(self) -> isize {
         self as isize
@@ -1,4 +1,4 @@
-use crate::{Handle, Result};
+use crate::{FileStat, Handle, Result, SeekFrom};
 
 syscalls! {
     /// Exits the current thread.
@@ -23,7 +23,11 @@ syscalls! {
     fn read(file: Handle, buf: &'a mut [u8]) -> Result<usize> => 6,
 
     fn init_video_mode(width: u16, height: u16, bpp: u8) -> Result<*mut u8> => 7,
-    fn spawn_process(executable: &str, inherit: &'a [Handle]) -> Result<Handle> => 8,
+
+    /// Starts a process running `executable`. `args` holds the process's arguments, then an empty string, then its
+    /// environment as `NAME=value` strings, with each string ending in a NUL.
+    fn spawn_process(executable: &str, args: &'a [u8], inherit: &'a [Handle]) -> Result<Handle> => 8,
+
     fn wait_for_exit(process: Handle) -> Result<i32> => 9,
     fn create_shared_mem() -> Handle => 10,
     fn map_shared_mem(block: Handle, len: usize, writable: bool) -> Result<*mut u8> => 11,
@@ -32,11 +36,121 @@ syscalls! {
     fn create_mutex() -> Handle => 14,
     fn lock_mutex(mutex: Handle) -> Result<()> => 15,
     fn unlock_mutex(mutex: Handle) -> Result<()> => 16,
-    fn spawn_thread(entry: extern fn(usize), context: usize) -> Handle => 17,
+
+    /// Starts a thread in the current process with a user-mode stack of `stack_size` bytes. The page below the stack
+    /// is left unmapped, so that overflowing the stack causes a page fault.
+    fn spawn_thread(entry: extern fn(usize), context: usize, stack_size: usize) -> Result<Handle> => 17,
+
     fn schedule() -> () => 18,
     fn current_thread_id() -> usize => 19,
     fn duplicate_handle(handle: Handle) -> Result<Handle> => 20,
     fn create_semaphore(value: usize) -> Handle => 21,
     fn wait_semaphore(semaphore: Handle) -> Result<()> => 22,
-    fn post_semaphore(semaphore: Handle) -> Result<()> => 23
+    fn post_semaphore(semaphore: Handle) -> Result<()> => 23,
+
+    /// Blocks the current thread for at least `ns` nanoseconds.
+    fn sleep(ns: u64) -> () => 24,
+
+    /// Returns the number of nanoseconds since the kernel started. Never goes backwards.
+    fn now() -> u64 => 25,
+
+    fn wait_semaphore_timeout(semaphore: Handle, timeout_ns: u64) -> Result<()> => 26,
+    fn lock_mutex_timeout(mutex: Handle, timeout_ns: u64) -> Result<()> => 27,
+    fn wait_for_exit_timeout(process: Handle, timeout_ns: u64) -> Result<i32> => 28,
+    fn read_timeout(file: Handle, buf: &'a mut [u8], timeout_ns: u64) -> Result<usize> => 29,
+
+    /// Blocks until one of the handles is ready, and returns its index. Pipes and devices are ready when they have
+    /// data to read, semaphores when they can be waited on without blocking, and processes and threads when they
+    /// have exited.
+    fn wait_for_any(handles: &'a [Handle], timeout_ns: u64) -> Result<usize> => 30,
+
+    /// Moves the position of a file, and returns the new position from the start of the file.
+    fn seek(file: Handle, pos: SeekFrom) -> Result<u64> => 31,
+
+    /// Returns the size, kind and mode of a file or directory.
+    fn stat(file: Handle, stat: &'a mut FileStat) -> Result<()> => 32,
+
+    /// Reads the name of the next entry in a directory into `buf` and returns its length, or 0 once there are no
+    /// more entries.
+    fn read_dir(dir: Handle, buf: &'a mut [u8]) -> Result<usize> => 33,
+
+    /// Creates an empty file, or truncates an existing one, and opens it for reading and writing.
+    fn create(filename: &'a str) -> Result<Handle> => 34,
+
+    /// Cuts a file off at `len` bytes, or extends it with zeros up to `len` bytes.
+    fn truncate(file: Handle, len: u64) -> Result<()> => 35,
+
+    /// Removes a file or an empty directory.
+    fn unlink(filename: &'a str) -> Result<()> => 36,
+    fn mkdir(filename: &'a str) -> Result<()> => 37,
+
+    /// Terminates a process. Anything waiting for the process sees `code` as its exit code, and its threads stop the
+    /// next time they would return to user mode.
+    fn kill_process(process: Handle, code: i32) -> Result<()> => 38,
+
+    /// Exits every thread in the current process. Anything waiting for the process sees `code` as its exit code,
+    /// and can fetch `message` with `exit_message`.
+    fn exit_process(code: i32, message: &'a str) -> ! => 39,
+
+    /// Reads the message that a process gave when it exited into `buf`, and returns its length.
+    fn exit_message(process: Handle, buf: &'a mut [u8]) -> Result<usize> => 40,
+
+    /// Changes the priority of the current thread, from 0 up to `MAX_USER_PRIORITY`. Threads start at the priority of
+    /// the thread that spawned them.
+    fn set_thread_priority(priority: u8) -> Result<()> => 41,
+
+    /// Starts a process with a copy of the current process's memory and handles. Pages are shared until one of the
+    /// processes writes to them. The new process has one thread, which calls `entry(context)` on a new stack.
+    fn fork_process(entry: extern fn(usize), context: usize) -> Result<Handle> => 42,
+
+    /// Maps `len` bytes of a file into memory, starting at `offset`, which has to be a multiple of the page size.
+    /// Pages are read from the file as they're touched. Writing to a writable mapping doesn't change the file.
+    fn map_file(file: Handle, offset: u64, len: usize, writable: bool) -> Result<*mut u8> => 43,
+
+    /// Reserves `len` bytes of address space, at `ptr` unless it's null, without committing any memory to it. The
+    /// pages fault when they're touched until `protect_pages` makes them accessible.
+    fn reserve_pages(ptr: *mut u8, len: usize) -> Result<*mut u8> => 44,
+
+    /// Changes whether the pages from `ptr` to `ptr + len` can be read, written or executed, using the `PROT_`
+    /// flags. The pages have to be allocated or reserved already, and can't be both writable and executable.
+    /// `free_pages` on the original pointer still frees the whole allocation.
+    fn protect_pages(ptr: *mut u8, len: usize, flags: u32) -> Result<()> => 45,
+
+    /// Claims `name` so that other processes can connect to it, and returns a port handle. The port is ready when a
+    /// client is waiting for `accept_port`. The name is free again once the port handle is closed.
+    fn register_port(name: &'a str) -> Result<Handle> => 46,
+
+    /// Connects to the port registered as `name`, and returns one end of a new channel. Bytes written to one end of
+    /// a channel are read from the other.
+    fn connect_port(name: &'a str) -> Result<Handle> => 47,
+
+    /// Blocks until a client connects to a port, and returns the server's end of the new channel.
+    fn accept_port(port: Handle) -> Result<Handle> => 48,
+
+    /// Creates a connected pair of channels, and puts their handles in `ends`, which has to have room for two.
+    fn create_channel(ends: &'a mut [Handle]) -> Result<()> => 49,
+
+    /// Sends `data` as one message to the other end of a channel. `handles` go with the message, and are closed in
+    /// the current process. Fails with `ErrNum::WouldBlock` if too many messages are waiting at the other end.
+    fn send_message(channel: Handle, data: &'a [u8], handles: &'a [Handle]) -> Result<()> => 50,
+
+    /// Blocks until a message arrives on a channel. Copies its data into `buf`, opens the handles that came with it
+    /// into `handles`, and returns the length of the data, with the number of handles in `handle_count`. Returns 0
+    /// with no handles once the other end has been closed.
+    fn receive_message(channel: Handle, buf: &'a mut [u8], handles: &'a mut [Handle], handle_count: &'a mut usize)
+        -> Result<usize> => 51,
+
+    /// Creates a pipe that holds up to `capacity` bytes, which can't be more than `PIPE_CAPACITY`, and puts the
+    /// handles of its read and write ends in `ends`, which has to have room for two. Reads return 0 once the write end
+    /// has been closed and the pipe is empty. Writes fail with `ErrNum::BrokenPipe` once the read end has been closed.
+    fn create_pipe_ends(capacity: usize, ends: &'a mut [Handle]) -> Result<()> => 52,
+
+    /// Makes `read` and `write` on `handle` fail with `ErrNum::WouldBlock` instead of waiting, or makes them wait
+    /// again. Other handles to the same object aren't affected.
+    fn set_non_blocking(handle: Handle, non_blocking: bool) -> Result<()> => 53,
+
+    /// Blocks until any of `handles` has one of the `POLL_` flags asked for in the matching entry of `events`, or
+    /// until `timeout_ns` has passed. `POLL_HUP` is reported whether or not it was asked for. Sets each entry of
+    /// `events` to the flags that were found, and returns how many handles had any, which is 0 after a timeout.
+    fn poll(handles: &'a [Handle], events: &'a mut [u32], timeout_ns: u64) -> Result<usize> => 54
 }
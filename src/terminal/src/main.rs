@@ -6,26 +6,29 @@ extern crate rt;
 
 use crate::state::TerminalState;
 use core::cell::RefCell;
+use core::mem;
 use core::str;
 use freetype::FreeType;
 use graphics::components::{NeedsPaint, OnInput, OnPaint, Position, Text};
 use graphics::widgets::ClientPortal;
-use graphics::{App, Event, EventInput, Result};
+use graphics::{share, App, Event, EventInput, Result};
 use os::{File, Process, Thread};
 use std::io::{Read, Write};
 
 mod state;
 
-static FONT_BYTES: &[u8] = include_bytes!("VeraMono.ttf");
-
 fn main() -> Result<()> {
-    let mut app = App::new();
-    let stdin = File::create_pipe();
-    let mut stdout = File::create_pipe();
-    Process::spawn("input", &[stdin.handle().get(), stdout.handle().get()])?;
+    let mut app = App::new()?;
+    let (input_stdin, stdin) = File::create_pipe_ends(syscall::PIPE_CAPACITY)?;
+    let (mut stdout, input_stdout) = File::create_pipe_ends(syscall::PIPE_CAPACITY)?;
+    Process::spawn("input", &[input_stdin.handle().get(), input_stdout.handle().get()])?;
+
+    // Only the shell and its children hold the write end of stdout, so reading it returns 0 once they've all exited
+    mem::drop(input_stdin);
+    mem::drop(input_stdout);
 
     let mut ft = FreeType::new();
-    let mut ft_face = freetype::Face::from_slice(&mut ft, FONT_BYTES, 0);
+    let mut ft_face = freetype::Face::from_slice(&mut ft, share::load("fonts/VeraMono.ttf")?, 0);
     ft_face.set_char_size(0.0, 16.0, 72, 72);
 
     let cr_face = cairo::FontFace::from_freetype(&ft_face);
@@ -73,6 +76,10 @@ fn main() -> Result<()> {
             let mut buf = [0; 4096];
             loop {
                 let len = stdout.read(&mut buf).unwrap();
+                if len == 0 {
+                    Process::exit(0);
+                }
+
                 if let Ok(s) = str::from_utf8(&buf[..len]) {
                     let s = s.to_owned();
                     sync.call(move |world| {